@@ -4,7 +4,10 @@ use actix::Addr;
 use actix_web::{get, post, web, App, HttpResponse, HttpServer, Responder};
 use async_nats::{self, Connection};
 use cloudevents::{EventBuilder, EventBuilderV10};
-use nats_actor2::{
+use nats_actor::{
+    config_loader::{config_path, load_config},
+    health::{health_nats, NatsHealth},
+    metrics::metrics,
     model::event::{
         event::Event,
         nats::{
@@ -13,8 +16,9 @@ use nats_actor2::{
         },
     },
     publisher::{NatsPublisher, NatsPublisherConfig},
+    shutdown::GracefulShutdown,
     subscriber::{subscribe, NatsSubscriberConfig},
-    EventMessage, NatsClientSettings,
+    EventMessage,
 };
 use serde::{Deserialize, Serialize};
 use serde_json::json;
@@ -23,7 +27,7 @@ use uuid::Uuid;
 // This struct represents state
 #[derive(Clone)]
 struct AppState {
-    nats_publisher: Arc<Addr<NatsPublisher>>,
+    nats_publisher: Arc<Addr<NatsPublisher<cloudevents::Event>>>,
 }
 
 #[get("/ping")]
@@ -47,6 +51,7 @@ async fn ping(data: web::Data<AppState>) -> impl Responder {
     let publisher = Arc::clone(&data.nats_publisher);
     publisher.do_send(EventMessage {
         event: ping_event.try_into().unwrap(),
+        subject: None,
     });
 
     HttpResponse::Ok().body("Ping!")
@@ -73,6 +78,7 @@ async fn pong(data: web::Data<AppState>) -> impl Responder {
     let publisher = Arc::clone(&data.nats_publisher);
     publisher.do_send(EventMessage {
         event: pong_event.try_into().unwrap(),
+        subject: None,
     });
 
     HttpResponse::Ok().body("Pong!")
@@ -80,34 +86,32 @@ async fn pong(data: web::Data<AppState>) -> impl Responder {
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
-    let nats_address = format!("127.0.0.1:{}", 4222);
-    let subject = "test_subject";
-
-    let publisher = NatsPublisher::start_new(NatsPublisherConfig {
-        client_settings: NatsClientSettings {
-            addresses: vec![nats_address.to_owned()],
-            max_reconnects: Some(5),
-            retry_timeout: Some(Duration::from_secs(30)),
-        },
-        subject: subject.to_owned(),
-        mailbox_size: 100,
-    })
-    .await
-    .unwrap();
+    let config: NatsPublisherConfig =
+        load_config(&[&config_path("config/publisher.toml")]).unwrap();
 
+    let publisher = NatsPublisher::start_new(config).await.unwrap();
+
+    let health = web::Data::new(NatsHealth::new().with("publisher", publisher.clone().recipient()));
+    let shutdown = GracefulShutdown::new(Duration::from_secs(10))
+        .with("publisher", publisher.clone().recipient());
     let data = web::Data::new(AppState {
         nats_publisher: Arc::new(publisher),
     });
 
-    HttpServer::new(move || {
+    let server = HttpServer::new(move || {
         App::new()
             .app_data(data.clone())
+            .app_data(health.clone())
             .service(ping)
             .service(pong)
+            .service(health_nats)
+            .service(metrics)
     })
     .bind("127.0.0.1:8000")?
-    .run()
-    .await
+    .run();
+
+    shutdown.run(server).await
 }
 
 // http://127.0.0.1:8000/ping
+// http://127.0.0.1:8000/health/nats
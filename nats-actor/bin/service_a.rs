@@ -1,12 +1,16 @@
 use std::{sync::Arc, time::Duration};
 
 use actix::Addr;
-use actix_web::{get, post, web, App, HttpResponse, HttpServer, Responder};
+use actix_web::{get, post, web, App, HttpResponse, HttpServer};
 use async_nats::{self, Connection};
 use nats_actor::{
+    config_loader::{config_path, load_config},
+    health::{health_nats, NatsHealth},
+    metrics::metrics,
     publisher::{NatsPublisher, NatsPublisherConfig},
+    shutdown::GracefulShutdown,
     subscriber::{subscribe, NatsSubscriberConfig},
-    Event, EventMessage, NatsClientSettings,
+    Event, EventMessage, InternalError,
 };
 use serde::{Deserialize, Serialize};
 
@@ -20,46 +24,55 @@ struct Person {
 // This struct represents state
 #[derive(Clone)]
 struct AppState {
-    nats_publisher: Arc<Addr<NatsPublisher>>,
+    nats_publisher: Arc<Addr<NatsPublisher<Event>>>,
 }
 
 #[get("/hello")]
-async fn hello(data: web::Data<AppState>) -> impl Responder {
+async fn hello(data: web::Data<AppState>) -> Result<HttpResponse, InternalError> {
     let event = Event::new(format!("event_type_{}", 12345));
 
     let publisher = Arc::clone(&data.nats_publisher);
-    publisher.do_send(EventMessage {
-        event: event.clone(),
-    });
+    // With `ack_mode` set to flush, answers 503 when the event could not be published
+    publisher
+        .send(EventMessage {
+            event: event.clone(),
+            subject: None,
+        })
+        .await
+        .map_err(|err| InternalError::GenericError {
+            cause: err.to_string(),
+        })??;
 
-    HttpResponse::Ok().body("Hello!")
+    Ok(HttpResponse::Ok().body("Hello!"))
 }
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
-    let nats_address = format!("127.0.0.1:{}", 4222);
-    let subject = "test_subject";
+    let config: NatsPublisherConfig =
+        load_config(&[&config_path("config/publisher.toml")]).unwrap();
 
-    let publisher = NatsPublisher::start_new(NatsPublisherConfig {
-        client_settings: NatsClientSettings {
-            addresses: vec![nats_address.to_owned()],
-            max_reconnects: Some(5),
-            retry_timeout: Some(Duration::from_secs(30)),
-        },
-        subject: subject.to_owned(),
-        mailbox_size: 100,
-    })
-    .await
-    .unwrap();
+    let publisher = NatsPublisher::start_new(config).await.unwrap();
 
+    let health = web::Data::new(NatsHealth::new().with("publisher", publisher.clone().recipient()));
+    let shutdown = GracefulShutdown::new(Duration::from_secs(10))
+        .with("publisher", publisher.clone().recipient());
     let data = web::Data::new(AppState {
         nats_publisher: Arc::new(publisher),
     });
 
-    HttpServer::new(move || App::new().app_data(data.clone()).service(hello))
-        .bind("127.0.0.1:8080")?
-        .run()
-        .await
+    let server = HttpServer::new(move || {
+        App::new()
+            .app_data(data.clone())
+            .app_data(health.clone())
+            .service(hello)
+            .service(health_nats)
+            .service(metrics)
+    })
+    .bind("127.0.0.1:8080")?
+    .run();
+
+    shutdown.run(server).await
 }
 
 // http://127.0.0.1:8080/hello
+// http://127.0.0.1:8080/health/nats
@@ -1,4 +1,8 @@
-use nats_actor::Event;
+use nats_actor::{
+    config_loader::{config_path, load_config},
+    subscriber::NatsSubscriberConfig,
+    Event,
+};
 use serde::{Deserialize, Serialize};
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -10,10 +14,10 @@ struct Person {
 
 #[tokio::main]
 async fn main() -> std::io::Result<()> {
-    let nats_address = format!("127.0.0.1:{}", 4222);
-    let subject = "test_subject";
+    let config: NatsSubscriberConfig =
+        load_config(&[&config_path("config/subscriber.toml")]).unwrap();
 
-    let nc = async_nats::connect(&nats_address).await?;
+    let nc = async_nats::connect(&config.client_settings.addresses.join(",")).await?;
 
     let p = Person {
         first_name: "derek".to_owned(),
@@ -21,7 +25,7 @@ async fn main() -> std::io::Result<()> {
         age: 22,
     };
 
-    let sub = nc.subscribe(&subject).await?;
+    let sub = nc.subscribe(&config.subject).await?;
 
     loop {
         let e = sub.next().await.map(move |msg| {
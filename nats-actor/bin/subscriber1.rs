@@ -0,0 +1,24 @@
+use nats_actor::config_loader::{config_path, load_config};
+use nats_actor::model::event::event::Event;
+use nats_actor::subscriber::NatsSubscriberConfig;
+
+#[tokio::main]
+async fn main() -> std::io::Result<()> {
+    let config: NatsSubscriberConfig =
+        load_config(&[&config_path("config/subscriber.yaml")]).unwrap();
+
+    let nc = async_nats::connect(&config.client_settings.addresses.join(",")).await?;
+
+    let sub = nc.subscribe(&config.subject).await?;
+
+    loop {
+        let e = sub.next().await.map(move |msg| {
+            let e: cloudevents::Event = serde_json::from_slice(&msg.data).unwrap();
+            let e: Event = e.try_into().unwrap();
+            e
+        });
+        println!("received {:?}", e);
+    }
+
+    Ok(())
+}
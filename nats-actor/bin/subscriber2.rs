@@ -1,16 +1,17 @@
-use std::{sync::Arc, time::Duration};
+use std::sync::Arc;
 
 use actix::Addr;
 use actix_web::{get, post, web, App, HttpResponse, HttpServer, Responder};
 use async_nats::{self, Connection};
 use cloudevents::{EventBuilder, EventBuilderV10};
-use nats_actor2::{
+use nats_actor::{
+    config_loader::{config_path, load_config},
     model::event::{
         event::Event,
         nats::ping::{PingMessage, EVENT_TYPE_PING},
     },
     subscriber::{subscribe, NatsSubscriberConfig},
-    EventMessage, NatsClientSettings,
+    EventMessage,
 };
 use serde::{Deserialize, Serialize};
 use serde_json::json;
@@ -27,20 +28,9 @@ async fn hello(data: web::Data<AppState>) -> impl Responder {
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
-    let nats_address = format!("127.0.0.1:{}", 4222);
-    let subject = "test_subject";
-
     actix::spawn(async move {
         subscribe(
-            NatsSubscriberConfig {
-                client_settings: NatsClientSettings {
-                    addresses: vec![nats_address.to_owned()],
-                    max_reconnects: Some(5),
-                    retry_timeout: Some(Duration::from_secs(30)),
-                },
-                subject: subject.to_owned(),
-                mailbox_size: 100,
-            },
+            load_config::<NatsSubscriberConfig>(&[&config_path("config/subscriber.yaml")]).unwrap(),
             move |event| {
                 println!("Received event {:?}", event);
                 Ok(())
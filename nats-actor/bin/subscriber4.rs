@@ -1,20 +1,21 @@
-use std::{sync::Arc, time::Duration};
+use std::sync::Arc;
 
 use actix::{Actor, Addr, Context, Handler, Message};
 use actix_web::{get, post, web, App, HttpResponse, HttpServer, Responder};
 use async_nats::{self, Connection};
 use chrono::Utc;
 use cloudevents::{Data, EventBuilder, EventBuilderV10};
-use nats_actor2::model::event::event::{Event, EventError};
-use nats_actor2::model::event::nats::pong::EVENT_TYPE_PONG;
-use nats_actor2::subscriber::NatsStreamMessage;
-use nats_actor2::{
+use nats_actor::model::event::event::{Event, EventError};
+use nats_actor::model::event::nats::pong::EVENT_TYPE_PONG;
+use nats_actor::subscriber::NatsMessage;
+use nats_actor::{
+    config_loader::{config_path, load_config},
     model::event::nats::{
         ping::{PingMessage, EVENT_TYPE_PING},
         pong::PongMessage,
     },
     subscriber::{subscribe, NatsSubscriberConfig},
-    EventMessage, NatsClientSettings,
+    EventMessage,
 };
 use serde::{Deserialize, Serialize};
 use serde_json::json;
@@ -71,9 +72,6 @@ async fn hello(data: web::Data<AppState>) -> impl Responder {
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
-    let nats_address = format!("127.0.0.1:{}", 4222);
-    let subject = "test_subject";
-
     let state = web::Data::new(AppState {});
 
     let nats_stream_handler = EventStreamHandler {
@@ -83,18 +81,10 @@ async fn main() -> std::io::Result<()> {
 
     actix::spawn(async move {
         subscribe(
-            NatsSubscriberConfig {
-                client_settings: NatsClientSettings {
-                    addresses: vec![nats_address.to_owned()],
-                    max_reconnects: Some(5),
-                    retry_timeout: Some(Duration::from_secs(30)),
-                },
-                subject: subject.to_owned(),
-                mailbox_size: 100,
-            },
-            move |msg: NatsStreamMessage| {
+            load_config::<NatsSubscriberConfig>(&[&config_path("config/subscriber.yaml")]).unwrap(),
+            move |msg: NatsMessage| {
                 println!("Received event {:?}", msg);
-                let event: cloudevents::Event = serde_json::from_slice(&msg.msg.data).unwrap();
+                let event: cloudevents::Event = msg.event().unwrap();
                 let event: Event = event.try_into().unwrap();
                 println!("Extracted event {:?}", event);
                 nats_stream_handler.try_send(event);
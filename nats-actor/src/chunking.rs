@@ -0,0 +1,508 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::time::{Duration, Instant};
+
+use async_nats::{Connection, Headers};
+use log::*;
+use serde::{Deserialize, Serialize};
+
+use crate::InternalError;
+
+/// The header carrying the id shared by the chunks of a message.
+pub const CHUNK_ID_HEADER: &str = "Nats-Chunk-Id";
+/// The header carrying the index of a chunk, from `0`.
+pub const CHUNK_INDEX_HEADER: &str = "Nats-Chunk-Index";
+/// The header carrying the number of chunks of a message.
+pub const CHUNK_COUNT_HEADER: &str = "Nats-Chunk-Count";
+
+/// The size reserved for the chunk headers in each chunk.
+const CHUNK_HEADERS_SIZE: usize = 256;
+
+/// A chunk of a message, with its headers.
+type Chunk<'a> = (Headers, &'a [u8]);
+
+///
+/// Splits the messages larger than `max_payload`, headers included, into chunks sent one
+/// after the other. The first chunk carries the headers of the message, and all of them the
+/// chunk headers. The subscribers reassemble the chunks before their callback.
+///
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(default)]
+pub struct ChunkingConfig {
+    /// The `max_payload` of the NATS server.
+    pub max_payload: usize,
+}
+
+impl Default for ChunkingConfig {
+    fn default() -> Self {
+        ChunkingConfig {
+            max_payload: 1024 * 1024,
+        }
+    }
+}
+
+impl ChunkingConfig {
+    pub(crate) fn validate(&self) -> Result<(), InternalError> {
+        if self.max_payload < 4 * CHUNK_HEADERS_SIZE {
+            return Err(InternalError::ConfigurationError {
+                cause: format! {"chunking.max_payload must be at least {} bytes", 4 * CHUNK_HEADERS_SIZE},
+            });
+        }
+        Ok(())
+    }
+
+    /// Fails when the message is too large and its headers leave no room for the chunks.
+    pub(crate) fn check(
+        &self,
+        headers: Option<&Headers>,
+        payload: &[u8],
+    ) -> Result<(), InternalError> {
+        self.chunk_size(headers, payload).map(|_| ())
+    }
+
+    /// The size of the chunks of the message, `None` when it is small enough to be sent at once.
+    fn chunk_size(
+        &self,
+        headers: Option<&Headers>,
+        payload: &[u8],
+    ) -> Result<Option<usize>, InternalError> {
+        let headers_size = headers_size(headers);
+        if headers_size + payload.len() <= self.max_payload {
+            return Ok(None);
+        }
+        if headers_size + CHUNK_HEADERS_SIZE >= self.max_payload {
+            return Err(InternalError::PublishError {
+                attempts: 0,
+                cause: format! {
+                    "Headers of {} bytes exceed max_payload of {} bytes once chunked",
+                    headers_size, self.max_payload
+                },
+            });
+        }
+        Ok(Some(self.max_payload - headers_size - CHUNK_HEADERS_SIZE))
+    }
+
+    /// The chunks of the message, or `None` when it is small enough to be sent at once.
+    pub(crate) fn split<'a>(
+        &self,
+        headers: Option<&Headers>,
+        payload: &'a [u8],
+    ) -> Result<Option<Vec<Chunk<'a>>>, InternalError> {
+        let chunk_size = match self.chunk_size(headers, payload)? {
+            Some(chunk_size) => chunk_size,
+            None => return Ok(None),
+        };
+        let count = payload.len().div_ceil(chunk_size);
+        let id = uuid::Uuid::new_v4().to_string();
+        let chunks = payload
+            .chunks(chunk_size)
+            .enumerate()
+            .map(|(index, chunk)| {
+                let mut chunk_headers = match (index, headers) {
+                    (0, Some(headers)) => headers.clone(),
+                    _ => Headers::default(),
+                };
+                for (name, value) in [
+                    (CHUNK_ID_HEADER, id.clone()),
+                    (CHUNK_INDEX_HEADER, index.to_string()),
+                    (CHUNK_COUNT_HEADER, count.to_string()),
+                ] {
+                    chunk_headers
+                        .inner
+                        .insert(name.to_owned(), [value].into_iter().collect());
+                }
+                (chunk_headers, chunk)
+            })
+            .collect();
+        Ok(Some(chunks))
+    }
+}
+
+///
+/// Publishes a message to `subject`, in chunks when `chunking` is set and the message is too
+/// large.
+///
+pub(crate) async fn publish_chunked(
+    connection: &Connection,
+    subject: &str,
+    headers: Option<&Headers>,
+    payload: &[u8],
+    chunking: Option<&ChunkingConfig>,
+) -> io::Result<()> {
+    let chunks = match chunking {
+        Some(chunking) => chunking
+            .split(headers, payload)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err.to_string()))?,
+        None => None,
+    };
+    match chunks {
+        None => {
+            connection
+                .publish_with_reply_or_headers(subject, None, headers, payload)
+                .await
+        }
+        Some(chunks) => {
+            debug!(
+                "Publishing message of {} bytes to [{}] in {} chunks",
+                payload.len(),
+                subject,
+                chunks.len()
+            );
+            for (chunk_headers, chunk) in chunks {
+                connection
+                    .publish_with_reply_or_headers(subject, None, Some(&chunk_headers), chunk)
+                    .await?;
+            }
+            Ok(())
+        }
+    }
+}
+
+/// The size of the headers of a NATS message.
+fn headers_size(headers: Option<&Headers>) -> usize {
+    headers.map_or(0, |headers| {
+        // `NATS/1.0\r\n`, then `name: value\r\n` per value, then `\r\n`
+        let lines: usize = headers
+            .iter()
+            .map(|(name, values)| {
+                values
+                    .iter()
+                    .map(|value| name.len() + value.len() + 4)
+                    .sum::<usize>()
+            })
+            .sum();
+        10 + lines + 2
+    })
+}
+
+///
+/// How a `NatsSubscriber` reassembles the chunked messages. The incomplete messages are
+/// dropped after `timeout`, the oldest ones first when they take more than `max_pending_bytes`.
+///
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(default)]
+pub struct ReassemblyConfig {
+    pub timeout: Duration,
+    /// The maximum size of the chunks of all the incomplete messages.
+    pub max_pending_bytes: usize,
+    /// The maximum size of a reassembled message.
+    pub max_message_size: usize,
+}
+
+impl Default for ReassemblyConfig {
+    fn default() -> Self {
+        ReassemblyConfig {
+            timeout: Duration::from_secs(30),
+            max_pending_bytes: 64 * 1024 * 1024,
+            max_message_size: 16 * 1024 * 1024,
+        }
+    }
+}
+
+impl ReassemblyConfig {
+    pub(crate) fn validate(&self) -> Result<(), InternalError> {
+        if self.timeout.is_zero() {
+            return Err(InternalError::ConfigurationError {
+                cause: "reassembly.timeout must be positive".to_owned(),
+            });
+        }
+        Ok(())
+    }
+}
+
+/// The chunks received of a message.
+struct Partial {
+    headers: Option<Headers>,
+    count: usize,
+    chunks: BTreeMap<usize, Vec<u8>>,
+    size: usize,
+    started: Instant,
+}
+
+/// Collects the chunks of the messages of a subscriber.
+pub(crate) struct Reassembler {
+    config: ReassemblyConfig,
+    /// The incomplete messages, by subject and chunk id
+    partials: HashMap<(String, String), Partial>,
+    pending_bytes: usize,
+}
+
+impl Reassembler {
+    pub(crate) fn new(config: ReassemblyConfig) -> Reassembler {
+        Reassembler {
+            config,
+            partials: HashMap::new(),
+            pending_bytes: 0,
+        }
+    }
+
+    ///
+    /// Returns the payload of a message received on `subject`: as is when not chunked, once
+    /// reassembled otherwise, the `headers` being replaced with the ones of the message.
+    /// Returns `None` while chunks are missing.
+    ///
+    pub(crate) fn push(
+        &mut self,
+        subject: &str,
+        headers: &mut Option<Headers>,
+        data: Vec<u8>,
+        now: Instant,
+    ) -> Result<Option<Vec<u8>>, InternalError> {
+        let (id, index, count) = match chunk_headers(headers.as_ref())? {
+            Some(chunk) => chunk,
+            None => return Ok(Some(data)),
+        };
+        self.expire(now);
+        let key = (subject.to_owned(), id);
+        let partial = self.partials.entry(key.clone()).or_insert_with(|| Partial {
+            headers: None,
+            count,
+            chunks: BTreeMap::new(),
+            size: 0,
+            started: now,
+        });
+        if partial.count != count {
+            self.remove(&key);
+            return Err(chunk_error(format! {"Chunk count of [{}] changed", key.1}));
+        }
+        if partial.size + data.len() > self.config.max_message_size {
+            self.remove(&key);
+            return Err(chunk_error(format! {
+                "Message [{}] larger than {} bytes", key.1, self.config.max_message_size
+            }));
+        }
+        if partial.chunks.contains_key(&index) {
+            debug!("Ignoring duplicate chunk {} of [{}]", index, key.1);
+            return Ok(None);
+        }
+        if index == 0 {
+            partial.headers = headers.take().and_then(without_chunk_headers);
+        }
+        partial.size += data.len();
+        self.pending_bytes += data.len();
+        partial.chunks.insert(index, data);
+        if partial.chunks.len() < count {
+            self.enforce_memory_cap(&key);
+            return Ok(None);
+        }
+
+        let partial = self.remove(&key).expect("complete message");
+        *headers = partial.headers;
+        let mut payload = Vec::with_capacity(partial.size);
+        for chunk in partial.chunks.into_values() {
+            payload.extend_from_slice(&chunk);
+        }
+        Ok(Some(payload))
+    }
+
+    /// Drops the incomplete messages older than the timeout.
+    pub(crate) fn expire(&mut self, now: Instant) {
+        let timeout = self.config.timeout;
+        let expired: Vec<(String, String)> = self
+            .partials
+            .iter()
+            .filter(|(_, partial)| now.saturating_duration_since(partial.started) >= timeout)
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in expired {
+            warn!(
+                "Dropping incomplete message [{}] from [{}] after {:?}",
+                key.1, key.0, timeout
+            );
+            self.remove(&key);
+        }
+    }
+
+    /// Drops the oldest incomplete messages but `current` until under `max_pending_bytes`.
+    fn enforce_memory_cap(&mut self, current: &(String, String)) {
+        while self.pending_bytes > self.config.max_pending_bytes {
+            let oldest = self
+                .partials
+                .iter()
+                .filter(|(key, _)| *key != current)
+                .min_by_key(|(_, partial)| partial.started)
+                .map(|(key, _)| key.clone());
+            let key = oldest.unwrap_or_else(|| current.clone());
+            warn!(
+                "Dropping incomplete message [{}] from [{}]: more than {} bytes pending",
+                key.1, key.0, self.config.max_pending_bytes
+            );
+            self.remove(&key);
+        }
+    }
+
+    /// Whether chunks of the message `id` received on `subject` are waiting for the others.
+    pub(crate) fn is_pending(&self, subject: &str, id: &str) -> bool {
+        self.partials
+            .contains_key(&(subject.to_owned(), id.to_owned()))
+    }
+
+    fn remove(&mut self, key: &(String, String)) -> Option<Partial> {
+        let partial = self.partials.remove(key)?;
+        self.pending_bytes -= partial.size;
+        Some(partial)
+    }
+}
+
+/// The id shared by the chunks of a message, `None` when the message is not chunked.
+pub(crate) fn chunk_id(headers: Option<&Headers>) -> Option<String> {
+    headers?
+        .iter()
+        .find(|(key, _)| key.eq_ignore_ascii_case(CHUNK_ID_HEADER))
+        .and_then(|(_, values)| values.iter().next().cloned())
+}
+
+/// The id, index and count of a chunk, `None` when the message is not chunked.
+fn chunk_headers(
+    headers: Option<&Headers>,
+) -> Result<Option<(String, usize, usize)>, InternalError> {
+    let header = |name: &str| {
+        headers?
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .and_then(|(_, values)| values.iter().next())
+    };
+    let id = match chunk_id(headers) {
+        Some(id) => id,
+        None => return Ok(None),
+    };
+    let number = |name: &str| {
+        header(name)
+            .and_then(|value| value.parse::<usize>().ok())
+            .ok_or_else(|| chunk_error(format! {"Invalid {} header of [{}]", name, id}))
+    };
+    let (index, count) = (number(CHUNK_INDEX_HEADER)?, number(CHUNK_COUNT_HEADER)?);
+    if index >= count {
+        return Err(chunk_error(
+            format! {"Chunk {} of [{}] out of {}", index, id, count},
+        ));
+    }
+    Ok(Some((id, index, count)))
+}
+
+fn without_chunk_headers(mut headers: Headers) -> Option<Headers> {
+    headers.inner.retain(|name, _| {
+        ![CHUNK_ID_HEADER, CHUNK_INDEX_HEADER, CHUNK_COUNT_HEADER]
+            .iter()
+            .any(|chunk_header| name.eq_ignore_ascii_case(chunk_header))
+    });
+    (!headers.is_empty()).then_some(headers)
+}
+
+fn chunk_error(cause: String) -> InternalError {
+    InternalError::ReassemblyError { cause }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use async_nats::Headers;
+
+    use super::{ChunkingConfig, Reassembler, ReassemblyConfig};
+    use crate::InternalError;
+
+    fn split(payload: &[u8], headers: Option<&Headers>) -> Vec<(Option<Headers>, Vec<u8>)> {
+        let config = ChunkingConfig { max_payload: 1024 };
+        config
+            .split(headers, payload)
+            .unwrap()
+            .unwrap()
+            .into_iter()
+            .map(|(headers, chunk)| (Some(headers), chunk.to_vec()))
+            .collect()
+    }
+
+    #[test]
+    fn should_reassemble_chunks_in_any_order() {
+        let payload: Vec<u8> = (0..5000).map(|i| (i % 251) as u8).collect();
+        let headers: Headers = [("ce-type", "large")].iter().collect();
+        let mut chunks = split(&payload, Some(&headers));
+        assert_eq!(7, chunks.len());
+        assert!(chunks.iter().all(|(_, chunk)| chunk.len() <= 1024));
+        chunks.swap(0, 3);
+        let mut reassembler = Reassembler::new(ReassemblyConfig::default());
+        let now = Instant::now();
+
+        let mut reassembled = None;
+        for (mut chunk_headers, chunk) in chunks {
+            if let Some(data) = reassembler
+                .push("large", &mut chunk_headers, chunk, now)
+                .unwrap()
+            {
+                reassembled = Some((chunk_headers, data));
+            }
+        }
+
+        let (reassembled_headers, data) = reassembled.unwrap();
+        assert_eq!(payload, data);
+        assert_eq!(Some(headers), reassembled_headers);
+        assert_eq!(0, reassembler.pending_bytes);
+        assert!(ChunkingConfig::default()
+            .split(None, &payload)
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn should_reject_headers_leaving_no_room_for_chunks() {
+        let config = ChunkingConfig { max_payload: 1024 };
+        let headers: Headers = [("ce-type", "x".repeat(800).as_str())].iter().collect();
+
+        assert!(config.check(Some(&headers), &[0; 100]).is_ok());
+        assert!(matches!(
+            config.check(Some(&headers), &[0; 1000]),
+            Err(InternalError::PublishError { attempts: 0, .. })
+        ));
+        assert!(config.split(Some(&headers), &[0; 1000]).is_err());
+    }
+
+    #[test]
+    fn should_drop_incomplete_messages_after_timeout_or_over_memory_cap() {
+        let payload = vec![1u8; 3000];
+        let mut reassembler = Reassembler::new(ReassemblyConfig {
+            timeout: Duration::from_secs(1),
+            max_pending_bytes: 2000,
+            max_message_size: 2000,
+        });
+        let now = Instant::now();
+
+        let (mut first_headers, first) = split(&payload, None).remove(0);
+        let (mut second_headers, second) = split(&payload, None).remove(0);
+        let (mut third_headers, third) = split(&payload, None).remove(0);
+        reassembler
+            .push("a", &mut first_headers, first, now)
+            .unwrap();
+        reassembler
+            .push(
+                "a",
+                &mut second_headers,
+                second,
+                now + Duration::from_millis(1),
+            )
+            .unwrap();
+        assert_eq!(2, reassembler.partials.len());
+        reassembler
+            .push(
+                "a",
+                &mut third_headers,
+                third,
+                now + Duration::from_millis(2),
+            )
+            .unwrap();
+        assert_eq!(2, reassembler.partials.len());
+        assert!(reassembler.pending_bytes <= 2000);
+
+        reassembler.expire(now + Duration::from_secs(2));
+        assert!(reassembler.partials.is_empty());
+        assert_eq!(0, reassembler.pending_bytes);
+
+        let mut too_large = split(&payload, None);
+        too_large.truncate(3);
+        let results: Vec<_> = too_large
+            .into_iter()
+            .map(|(mut headers, chunk)| reassembler.push("a", &mut headers, chunk, now))
+            .collect();
+        assert!(results[2].is_err());
+    }
+}
@@ -0,0 +1,285 @@
+use std::io::{Read, Write};
+
+use async_nats::Headers;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use serde::{Deserialize, Serialize};
+
+use crate::InternalError;
+
+/// The header naming the codec of a compressed payload.
+pub const CONTENT_ENCODING_HEADER: &str = "Content-Encoding";
+
+/// The maximum size of a decompressed payload, against compression bombs.
+const MAX_DECOMPRESSED_SIZE: u64 = 64 * 1024 * 1024;
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Codec {
+    Gzip,
+    Zstd,
+}
+
+impl Codec {
+    /// The value of the `Content-Encoding` header.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Codec::Gzip => "gzip",
+            Codec::Zstd => "zstd",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Codec> {
+        [Codec::Gzip, Codec::Zstd]
+            .into_iter()
+            .find(|codec| codec.name().eq_ignore_ascii_case(name.trim()))
+    }
+}
+
+///
+/// Compresses the payloads of at least `threshold` bytes with `codec`, in both content modes.
+/// A compressed payload is marked by a `Content-Encoding` header, and decompressed by the
+/// subscribers before their callback.
+/// The payload is sent as is when the compression does not make it smaller.
+///
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+pub struct CompressionConfig {
+    pub codec: Codec,
+    #[serde(default = "default_threshold")]
+    pub threshold: usize,
+    /// The level of the codec, its default one when not set.
+    #[serde(default)]
+    pub level: Option<i32>,
+}
+
+fn default_threshold() -> usize {
+    1024
+}
+
+impl CompressionConfig {
+    pub(crate) fn validate(&self) -> Result<(), InternalError> {
+        let valid = match (self.codec, self.level) {
+            (_, None) => true,
+            (Codec::Gzip, Some(level)) => (0..=9).contains(&level),
+            (Codec::Zstd, Some(level)) => zstd::compression_level_range().contains(&level),
+        };
+        if valid {
+            Ok(())
+        } else {
+            Err(InternalError::ConfigurationError {
+                cause: format! {"Invalid {} compression level {:?}", self.codec.name(), self.level},
+            })
+        }
+    }
+
+    /// Compresses `payload` if it is large enough, adding the `Content-Encoding` header.
+    pub(crate) fn compress(
+        &self,
+        headers: Option<Headers>,
+        payload: Vec<u8>,
+    ) -> Result<(Option<Headers>, Vec<u8>), InternalError> {
+        if payload.len() < self.threshold {
+            return Ok((headers, payload));
+        }
+        let compressed = match self.codec {
+            Codec::Gzip => {
+                let level = self
+                    .level
+                    .map_or_else(flate2::Compression::default, |level| {
+                        flate2::Compression::new(level as u32)
+                    });
+                let mut encoder = GzEncoder::new(Vec::new(), level);
+                encoder
+                    .write_all(&payload)
+                    .and_then(|_| encoder.finish())
+                    .map_err(|err| compression_error(self.codec, err))?
+            }
+            Codec::Zstd => zstd::encode_all(payload.as_slice(), self.level.unwrap_or(0))
+                .map_err(|err| compression_error(self.codec, err))?,
+        };
+        if compressed.len() >= payload.len() {
+            return Ok((headers, payload));
+        }
+        let mut headers = headers.unwrap_or_default();
+        headers
+            .inner
+            .entry(CONTENT_ENCODING_HEADER.to_owned())
+            .or_default()
+            .insert(self.codec.name().to_owned());
+        Ok((Some(headers), compressed))
+    }
+}
+
+/// Compresses the payload with `compression`, if set.
+pub(crate) fn compress(
+    compression: Option<&CompressionConfig>,
+    headers: Option<Headers>,
+    payload: Vec<u8>,
+) -> Result<(Option<Headers>, Vec<u8>), InternalError> {
+    match compression {
+        Some(compression) => compression.compress(headers, payload),
+        None => Ok((headers, payload)),
+    }
+}
+
+///
+/// Decompresses the payload of a message according to its `Content-Encoding` header, and
+/// removes the header. The other payloads are returned as they are.
+///
+pub(crate) fn decompress(
+    message_headers: &mut Option<Headers>,
+    payload: Vec<u8>,
+) -> Result<Vec<u8>, InternalError> {
+    let headers = match message_headers.as_mut() {
+        Some(headers) => headers,
+        None => return Ok(payload),
+    };
+    let name = match headers
+        .keys()
+        .find(|name| name.eq_ignore_ascii_case(CONTENT_ENCODING_HEADER))
+    {
+        Some(name) => name.clone(),
+        None => return Ok(payload),
+    };
+    let encodings = headers.inner.remove(&name).unwrap_or_default();
+    let codec = match encodings.iter().next() {
+        Some(encoding) if encodings.len() == 1 => {
+            Codec::from_name(encoding).ok_or_else(|| InternalError::SerdeError {
+                cause: format! {"Unsupported content encoding [{}]", encoding},
+            })?
+        }
+        _ => {
+            return Err(InternalError::SerdeError {
+                cause: format! {"Unsupported content encodings {:?}", encodings},
+            })
+        }
+    };
+    if headers.is_empty() {
+        *message_headers = None;
+    }
+
+    let mut data = Vec::with_capacity(payload.len() * 4);
+    let read = match codec {
+        Codec::Gzip => GzDecoder::new(payload.as_slice())
+            .take(MAX_DECOMPRESSED_SIZE + 1)
+            .read_to_end(&mut data),
+        Codec::Zstd => zstd::Decoder::new(payload.as_slice()).and_then(|decoder| {
+            decoder
+                .take(MAX_DECOMPRESSED_SIZE + 1)
+                .read_to_end(&mut data)
+        }),
+    };
+    let size = read.map_err(|err| InternalError::SerdeError {
+        cause: format! {"Cannot decompress {} payload. Err: {}", codec.name(), err},
+    })?;
+    if size as u64 > MAX_DECOMPRESSED_SIZE {
+        return Err(InternalError::SerdeError {
+            cause: format! {"Decompressed payload larger than {} bytes", MAX_DECOMPRESSED_SIZE},
+        });
+    }
+    Ok(data)
+}
+
+fn compression_error(codec: Codec, err: std::io::Error) -> InternalError {
+    InternalError::SerdeError {
+        cause: format! {"Cannot compress payload with {}. Err: {}", codec.name(), err},
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use log::debug;
+    use std::time::Instant;
+
+    use super::{decompress, Codec, CompressionConfig, CONTENT_ENCODING_HEADER};
+
+    /// A JSON document shaped as the `data` of the events: records with repeated keys.
+    fn large_document(records: usize) -> Vec<u8> {
+        let records: Vec<serde_json::Value> = (0..records)
+            .map(|i| {
+                let service = ["http", "ssh", "dns", "smtp"][i % 4];
+                serde_json::json!({
+                    "id": i,
+                    "host": format!("host-{:04}.example.com", i % 250),
+                    "service": service,
+                    "state": if i % 7 == 0 { "CRITICAL" } else { "OK" },
+                    "output": format!("check returned {} in {}ms", i % 3, (i * 37) % 1000),
+                    "tags": ["production", "datacenter-1", format!("rack-{}", i % 40)],
+                })
+            })
+            .collect();
+        serde_json::to_vec(&serde_json::json!({ "records": records })).unwrap()
+    }
+
+    #[test]
+    fn should_compress_large_documents() {
+        let document = large_document(5000);
+
+        // The minimum size reduction of each codec with its default level
+        for (codec, min_ratio) in [(Codec::Gzip, 10.0), (Codec::Zstd, 15.0)] {
+            let config = CompressionConfig {
+                codec,
+                threshold: 1024,
+                level: None,
+            };
+            let started = Instant::now();
+            let (mut headers, compressed) = config.compress(None, document.clone()).unwrap();
+            let compress_time = started.elapsed();
+            let started = Instant::now();
+            let decompressed = decompress(&mut headers, compressed.clone()).unwrap();
+            let decompress_time = started.elapsed();
+
+            let ratio = document.len() as f64 / compressed.len() as f64;
+            debug!(
+                "{}: {} -> {} bytes, ratio {:.1}, compressed in {:?}, decompressed in {:?}",
+                codec.name(),
+                document.len(),
+                compressed.len(),
+                ratio,
+                compress_time,
+                decompress_time
+            );
+            assert!(
+                ratio >= min_ratio,
+                "{} ratio {:.1} below {}",
+                codec.name(),
+                ratio,
+                min_ratio
+            );
+            assert_eq!(document, decompressed);
+            assert!(headers.is_none());
+        }
+    }
+
+    #[test]
+    fn should_not_compress_small_payloads() {
+        let config = CompressionConfig {
+            codec: Codec::Zstd,
+            threshold: 1024,
+            level: Some(3),
+        };
+        let headers = [("ce-specversion", "1.0")].iter().collect();
+
+        let (headers, payload) = config
+            .compress(Some(headers), b"{\"small\":true}".to_vec())
+            .unwrap();
+
+        assert_eq!(b"{\"small\":true}".to_vec(), payload);
+        let headers = headers.unwrap();
+        assert!(!headers.contains_key(CONTENT_ENCODING_HEADER));
+        assert!(headers.contains_key("ce-specversion"));
+    }
+
+    #[test]
+    fn should_reject_unknown_encodings_and_levels() {
+        let headers = [(CONTENT_ENCODING_HEADER, "br")].iter().collect();
+
+        assert!(decompress(&mut Some(headers), vec![1, 2, 3]).is_err());
+        let config = CompressionConfig {
+            codec: Codec::Gzip,
+            threshold: 0,
+            level: Some(12),
+        };
+        assert!(config.validate().is_err());
+    }
+}
@@ -0,0 +1,252 @@
+use config::{Config, Environment, File};
+use serde::de::DeserializeOwned;
+
+use crate::consumer::JetStreamConsumerConfig;
+use crate::publisher::NatsPublisherConfig;
+use crate::signing::{KeyRing, Signer};
+use crate::subject::validate_subject;
+use crate::subscriber::NatsSubscriberConfig;
+use crate::{InternalError, NatsClientSettings, RetryPolicy};
+
+/// Prefix of the environment variables overriding the configuration files.
+pub const ENV_PREFIX: &str = "NATS";
+
+/// Environment variable with the path of the configuration file, see `config_path`.
+pub const CONFIG_PATH_ENV: &str = "NATS_CONFIG";
+
+///
+/// The path of the configuration file of a binary: its first command line argument, else the
+/// `NATS_CONFIG` environment variable, else `default` (relative to the working directory).
+///
+pub fn config_path(default: &str) -> String {
+    std::env::args()
+        .nth(1)
+        .or_else(|| std::env::var(CONFIG_PATH_ENV).ok())
+        .unwrap_or_else(|| default.to_owned())
+}
+
+///
+/// Loads a configuration from the given TOML or YAML files (the format is picked from the
+/// file extension), each one overriding the previous ones, then from the `NATS_*`
+/// environment variables, and validates it.
+///
+/// Nested fields are separated by a double underscore and lists by a comma, e.g.
+/// `NATS_CLIENT_SETTINGS__ADDRESSES=127.0.0.1:4222,127.0.0.1:4223`.
+///
+pub fn load_config<T: DeserializeOwned + Validate>(files: &[&str]) -> Result<T, InternalError> {
+    let config: T = files
+        .iter()
+        .fold(Config::builder(), |builder, file| {
+            builder.add_source(File::with_name(file))
+        })
+        .add_source(
+            Environment::with_prefix(ENV_PREFIX)
+                .prefix_separator("_")
+                .separator("__")
+                .list_separator(",")
+                .with_list_parse_key("client_settings.addresses")
+                .try_parsing(true),
+        )
+        .build()
+        .and_then(Config::try_deserialize)
+        .map_err(|err| InternalError::ConfigurationError {
+            cause: format! {"Cannot load configuration from {:?}. Err: {}", files, err},
+        })?;
+
+    config.validate()?;
+    Ok(config)
+}
+
+/// Checks the values that cannot be enforced by deserialization.
+pub trait Validate {
+    fn validate(&self) -> Result<(), InternalError>;
+}
+
+impl Validate for NatsClientSettings {
+    fn validate(&self) -> Result<(), InternalError> {
+        if self.addresses.is_empty() {
+            return invalid("client_settings.addresses must not be empty");
+        }
+        if self
+            .addresses
+            .iter()
+            .any(|address| address.trim().is_empty())
+        {
+            return invalid(format!(
+                "client_settings.addresses must not contain blank addresses: {:?}",
+                self.addresses
+            ));
+        }
+        self.retry_policy.validate()
+    }
+}
+
+impl Validate for RetryPolicy {
+    fn validate(&self) -> Result<(), InternalError> {
+        if self.multiplier < 1.0 {
+            return invalid(format!(
+                "retry_policy.multiplier must be at least 1, got {}",
+                self.multiplier
+            ));
+        }
+        if !(0.0..=1.0).contains(&self.jitter) {
+            return invalid(format!(
+                "retry_policy.jitter must be between 0 and 1, got {}",
+                self.jitter
+            ));
+        }
+        if self.max_attempts == Some(0) {
+            return invalid("retry_policy.max_attempts must not be 0");
+        }
+        Ok(())
+    }
+}
+
+impl Validate for NatsPublisherConfig {
+    fn validate(&self) -> Result<(), InternalError> {
+        self.client_settings.validate()?;
+        validate_subject(&self.subject, false)?;
+        validate_mailbox_size(self.mailbox_size)?;
+        self.rate_limit.validate()?;
+        if let Some(compression) = &self.compression {
+            compression.validate()?;
+        }
+        if let Some(signing) = &self.signing {
+            Signer::new(signing)?;
+        }
+        if let Some(chunking) = &self.chunking {
+            chunking.validate()?;
+        }
+        if let Some(jetstream) = &self.jetstream {
+            jetstream.validate()?;
+            if self.chunking.is_some() {
+                return invalid("chunking is not supported with jetstream");
+            }
+        }
+        self.retry_policy.validate()
+    }
+}
+
+impl Validate for JetStreamConsumerConfig {
+    fn validate(&self) -> Result<(), InternalError> {
+        self.client_settings.validate()?;
+        if let Some(filter_subject) = &self.filter_subject {
+            validate_subject(filter_subject, true)?;
+        }
+        if let Some(verification) = &self.verification {
+            KeyRing::new(&verification.keys)?;
+        }
+        JetStreamConsumerConfig::validate(self)
+    }
+}
+
+impl Validate for NatsSubscriberConfig {
+    fn validate(&self) -> Result<(), InternalError> {
+        self.client_settings.validate()?;
+        validate_subject(&self.subject, true)?;
+        if let Some(verification) = &self.verification {
+            KeyRing::new(&verification.keys)?;
+        }
+        self.reassembly.validate()?;
+        validate_mailbox_size(self.mailbox_size)
+    }
+}
+
+fn validate_mailbox_size(mailbox_size: usize) -> Result<(), InternalError> {
+    if mailbox_size == 0 {
+        return invalid("mailbox_size must be greater than 0");
+    }
+    Ok(())
+}
+
+fn invalid<S: Into<String>>(cause: S) -> Result<(), InternalError> {
+    Err(InternalError::ConfigurationError {
+        cause: cause.into(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use serial_test::serial;
+    use std::io::Write;
+
+    use super::load_config;
+    use crate::publisher::{NatsPublisherConfig, DEFAULT_PUBLISH_MAX_ATTEMPTS};
+    use crate::subscriber::NatsSubscriberConfig;
+    use crate::InternalError;
+
+    fn config_file(extension: &str, content: &str) -> tempfile::NamedTempFile {
+        let mut file = tempfile::Builder::new()
+            .suffix(&format!(".{}", extension))
+            .tempfile()
+            .unwrap();
+        file.write_all(content.as_bytes()).unwrap();
+        file
+    }
+
+    #[test]
+    #[serial]
+    fn should_load_config_from_file_and_environment() {
+        let file = config_file(
+            "toml",
+            r#"
+            subject = "test_subject"
+            mailbox_size = 100
+
+            [client_settings]
+            addresses = ["127.0.0.1:4222"]
+            "#,
+        );
+
+        std::env::set_var("NATS_MAILBOX_SIZE", "10");
+        std::env::set_var(
+            "NATS_CLIENT_SETTINGS__ADDRESSES",
+            "127.0.0.1:4222,127.0.0.1:4223",
+        );
+        let config: Result<NatsPublisherConfig, _> = load_config(&[file.path().to_str().unwrap()]);
+        std::env::remove_var("NATS_MAILBOX_SIZE");
+        std::env::remove_var("NATS_CLIENT_SETTINGS__ADDRESSES");
+
+        let config = config.unwrap();
+        assert_eq!("test_subject", config.subject);
+        assert_eq!(10, config.mailbox_size);
+        assert_eq!(
+            Some(DEFAULT_PUBLISH_MAX_ATTEMPTS),
+            config.retry_policy.max_attempts
+        );
+        assert_eq!(
+            vec!["127.0.0.1:4222".to_owned(), "127.0.0.1:4223".to_owned()],
+            config.client_settings.addresses
+        );
+    }
+
+    #[test]
+    #[serial]
+    fn should_reject_invalid_config() {
+        let load = |content: &str| {
+            let file = config_file("yaml", content);
+            load_config::<NatsSubscriberConfig>(&[file.path().to_str().unwrap()])
+        };
+
+        assert!(load(
+            "subject: events.>\nmailbox_size: 100\nclient_settings:\n  addresses: [\"127.0.0.1:4222\"]"
+        )
+        .is_ok());
+        assert!(matches!(
+            load("subject: events\nmailbox_size: 100\nclient_settings:\n  addresses: []"),
+            Err(InternalError::ConfigurationError { .. })
+        ));
+        assert!(matches!(
+            load("subject: events..ping\nmailbox_size: 100\nclient_settings:\n  addresses: [\"127.0.0.1:4222\"]"),
+            Err(InternalError::ConfigurationError { .. })
+        ));
+        assert!(matches!(
+            load("subject: events.>.ping\nmailbox_size: 100\nclient_settings:\n  addresses: [\"127.0.0.1:4222\"]"),
+            Err(InternalError::ConfigurationError { .. })
+        ));
+        assert!(matches!(
+            load("subject: events\nmailbox_size: 0\nclient_settings:\n  addresses: [\"127.0.0.1:4222\"]"),
+            Err(InternalError::ConfigurationError { .. })
+        ));
+    }
+}
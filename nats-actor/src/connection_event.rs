@@ -0,0 +1,126 @@
+use std::sync::{Arc, Mutex};
+
+use actix::prelude::*;
+use log::*;
+
+/// Lifecycle notification of a NATS connection.
+/// Note: async-nats 0.10 does not report the lame duck mode of the server, there is no event
+/// for it.
+#[derive(Message, Debug, Clone, PartialEq, Eq)]
+#[rtype(result = "()")]
+pub enum ConnectionEvent {
+    /// The connection to the server was lost, the client is trying to reconnect.
+    Disconnected,
+    /// The client reconnected after a `Disconnected`.
+    Reconnected,
+    /// The connection is closed for good, e.g. after `max_reconnects` attempts.
+    Closed,
+    /// A subscriber is not keeping up with the messages published on `subject`.
+    SlowConsumer { subject: String },
+}
+
+#[derive(Clone)]
+enum Listener {
+    Recipient(Recipient<ConnectionEvent>),
+    Callback(Arc<dyn Fn(&ConnectionEvent) + Send + Sync>),
+}
+
+/// The recipients notified of the `ConnectionEvent`s of a connection.
+/// Cloning it gives a handle to the same set of recipients.
+#[derive(Clone, Default)]
+pub struct ConnectionEventListeners {
+    listeners: Arc<Mutex<Vec<Listener>>>,
+}
+
+impl ConnectionEventListeners {
+    pub fn register(&self, recipient: Recipient<ConnectionEvent>) {
+        self.listeners
+            .lock()
+            .unwrap()
+            .push(Listener::Recipient(recipient));
+    }
+
+    /// Registers a callback, invoked from the NATS client thread.
+    pub fn register_callback<F: 'static + Fn(&ConnectionEvent) + Send + Sync>(&self, callback: F) {
+        self.listeners
+            .lock()
+            .unwrap()
+            .push(Listener::Callback(Arc::new(callback)));
+    }
+
+    ///
+    /// Notifies `event` to the listeners. They are called without holding the lock, so that
+    /// they can register other listeners or notify events themselves.
+    ///
+    pub fn notify(&self, event: ConnectionEvent) {
+        let listeners = {
+            let mut listeners = self.listeners.lock().unwrap();
+            listeners.retain(|listener| match listener {
+                Listener::Recipient(recipient) => recipient.connected(),
+                Listener::Callback(_) => true,
+            });
+            listeners.clone()
+        };
+        debug!("Notifying {:?} to {} listener(s)", event, listeners.len());
+        for listener in listeners {
+            match listener {
+                Listener::Recipient(recipient) => recipient.do_send(event.clone()),
+                Listener::Callback(callback) => callback(&event),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix::prelude::*;
+
+    use super::{ConnectionEvent, ConnectionEventListeners};
+
+    struct Collector {
+        events: tokio::sync::mpsc::UnboundedSender<ConnectionEvent>,
+    }
+
+    impl Actor for Collector {
+        type Context = Context<Self>;
+    }
+
+    impl Handler<ConnectionEvent> for Collector {
+        type Result = ();
+
+        fn handle(&mut self, msg: ConnectionEvent, _: &mut Context<Self>) {
+            self.events.send(msg).unwrap();
+        }
+    }
+
+    #[actix_rt::test]
+    async fn should_notify_registered_listeners() {
+        let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+        let listeners = ConnectionEventListeners::default();
+        listeners.register(Collector { events: sender }.start().recipient());
+
+        listeners.notify(ConnectionEvent::Disconnected);
+        listeners.notify(ConnectionEvent::Reconnected);
+
+        assert_eq!(Some(ConnectionEvent::Disconnected), receiver.recv().await);
+        assert_eq!(Some(ConnectionEvent::Reconnected), receiver.recv().await);
+    }
+
+    #[test]
+    fn should_allow_callbacks_to_use_the_listeners() {
+        let listeners = ConnectionEventListeners::default();
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let handle = listeners.clone();
+        listeners.register_callback(move |event| {
+            if *event == ConnectionEvent::Disconnected {
+                handle.notify(ConnectionEvent::Closed);
+            }
+        });
+        listeners.register_callback(move |event| sender.send(event.clone()).unwrap());
+
+        listeners.notify(ConnectionEvent::Disconnected);
+
+        assert_eq!(ConnectionEvent::Closed, receiver.recv().unwrap());
+        assert_eq!(ConnectionEvent::Disconnected, receiver.recv().unwrap());
+    }
+}
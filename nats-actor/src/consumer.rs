@@ -0,0 +1,796 @@
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use actix::prelude::*;
+use async_nats::{Connection, Subscription};
+use backoff::backoff::Backoff;
+use log::*;
+use serde::{Deserialize, Serialize};
+use tokio::sync::oneshot;
+use tokio::time;
+
+use crate::chunking::{chunk_id, Reassembler, ReassemblyConfig};
+use crate::connection_event::ConnectionEvent;
+use crate::dead_letter::DeadLetterSink;
+use crate::health::{ConnectionState, GetStatus, NatsStatus, StatusTracker};
+use crate::jetstream::{create_consumer, validate_name, API_PREFIX};
+use crate::metrics::{NatsMetrics, SubscriberMetrics};
+use crate::registry::{NatsConnectionRegistry, SharedConnection};
+use crate::shutdown::Shutdown;
+use crate::signing::{KeyRing, KeyRingUpdate, VerificationConfig};
+use crate::subscriber::{reject, unseal, NatsMessage, Undelivered};
+use crate::{flush, InternalError, NatsClientSettings, RetryBackoff};
+
+/// The time a pull waits for the server to end it once expired.
+const PULL_GRACE: Duration = Duration::from_secs(1);
+/// Maximum time waited for the creation of the consumer.
+const CREATE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How the callback of a `JetStreamConsumer` disposes of a message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AckKind {
+    /// Processed, the message is not delivered again.
+    Ack,
+    /// Not processed, the message is delivered again after `delay`, or at once when not set.
+    Nak { delay: Option<Duration> },
+    /// Never to be processed, the message is not delivered again.
+    Term,
+    /// Still being processed, the `ack_wait` of the message starts over. The message must be
+    /// acknowledged later with `NatsMessage::ack`.
+    InProgress,
+}
+
+impl AckKind {
+    /// The payload of the acknowledgement, as expected by the server.
+    pub(crate) fn body(&self) -> Vec<u8> {
+        match self {
+            AckKind::Ack => b"+ACK".to_vec(),
+            AckKind::Nak { delay: None } => b"-NAK".to_vec(),
+            AckKind::Nak { delay: Some(delay) } => {
+                format! {"-NAK {{\"delay\":{}}}", delay.as_nanos()}.into_bytes()
+            }
+            AckKind::Term => b"+TERM".to_vec(),
+            AckKind::InProgress => b"+WPI".to_vec(),
+        }
+    }
+}
+
+///
+/// A durable pull consumer of a JetStream stream: the messages stored while the service is
+/// down are delivered once it is back. The consumer is created when missing.
+///
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct JetStreamConsumerConfig {
+    pub client_settings: NatsClientSettings,
+    pub stream: String,
+    pub durable_name: String,
+    /// Only consumes the messages of this subject, possibly with wildcards.
+    #[serde(default)]
+    pub filter_subject: Option<String>,
+    /// The maximum number of messages per pull. The next pull is sent once they are processed.
+    #[serde(default = "default_batch_size")]
+    pub batch_size: usize,
+    /// The time after which a message not acknowledged is delivered again.
+    #[serde(default = "default_ack_wait")]
+    pub ack_wait: Duration,
+    /// The maximum number of deliveries of a message, unlimited when not set.
+    #[serde(default)]
+    pub max_deliver: Option<usize>,
+    /// The time a pull waits for messages when the stream has none.
+    #[serde(default = "default_pull_expires")]
+    pub pull_expires: Duration,
+    /// The delay before a message the callback failed on is delivered again.
+    #[serde(default = "default_nak_delay")]
+    pub nak_delay: Duration,
+    /// Delivers only the messages signed by a known key, the others are terminated. See
+    /// `KeyRingUpdate`.
+    #[serde(default)]
+    pub verification: Option<VerificationConfig>,
+    /// The chunks of a message are acknowledged together, once reassembled.
+    #[serde(default)]
+    pub reassembly: ReassemblyConfig,
+}
+
+fn default_batch_size() -> usize {
+    10
+}
+
+fn default_ack_wait() -> Duration {
+    Duration::from_secs(30)
+}
+
+fn default_pull_expires() -> Duration {
+    Duration::from_secs(5)
+}
+
+fn default_nak_delay() -> Duration {
+    Duration::from_secs(5)
+}
+
+impl JetStreamConsumerConfig {
+    pub(crate) fn validate(&self) -> Result<(), InternalError> {
+        let invalid = |cause: &str| {
+            Err(InternalError::ConfigurationError {
+                cause: cause.to_owned(),
+            })
+        };
+        validate_name("stream", &self.stream)?;
+        validate_name("consumer", &self.durable_name)?;
+        if self.batch_size == 0 {
+            return invalid("batch_size must be greater than 0");
+        }
+        if self.ack_wait.is_zero() || self.pull_expires.is_zero() {
+            return invalid("ack_wait and pull_expires must be greater than 0");
+        }
+        if self.max_deliver == Some(0) {
+            return invalid("max_deliver must not be 0");
+        }
+        self.reassembly.validate()
+    }
+
+    /// The configuration of the consumer in the format of the JetStream API.
+    fn to_api(&self) -> serde_json::Value {
+        let max_deliver = self.max_deliver.map_or(-1, |max| max as i64);
+        let mut config = serde_json::json!({
+            "durable_name": self.durable_name,
+            "deliver_policy": "all",
+            "ack_policy": "explicit",
+            "ack_wait": self.ack_wait.as_nanos() as u64,
+            "max_deliver": max_deliver,
+            "replay_policy": "instant",
+        });
+        if let Some(filter_subject) = &self.filter_subject {
+            config["filter_subject"] = filter_subject.clone().into();
+        }
+        config
+    }
+}
+
+pub async fn consume<
+    F: 'static + FnMut(NatsMessage) -> Result<AckKind, InternalError> + Sized + Unpin,
+>(
+    config: JetStreamConsumerConfig,
+    callback: F,
+) -> Result<Addr<JetStreamConsumer<F>>, InternalError> {
+    consume_with_registry(config, NatsConnectionRegistry::global(), callback).await
+}
+
+///
+/// Same as `consume`, borrowing the connection from `registry`.
+/// A failure of the callback is answered with `AckKind::Nak`, for the message to be delivered
+/// again after `nak_delay`.
+///
+pub async fn consume_with_registry<
+    F: 'static + FnMut(NatsMessage) -> Result<AckKind, InternalError> + Sized + Unpin,
+>(
+    config: JetStreamConsumerConfig,
+    registry: &NatsConnectionRegistry,
+    callback: F,
+) -> Result<Addr<JetStreamConsumer<F>>, InternalError> {
+    config.validate()?;
+    let (key_ring, rejected) = match &config.verification {
+        Some(verification) => (
+            Some(Rc::new(RefCell::new(KeyRing::new(&verification.keys)?))),
+            verification.rejected.clone(),
+        ),
+        None => (None, None),
+    };
+    let listeners = registry.listeners(&config.client_settings)?;
+    let client = registry.acquire(&config.client_settings).await?;
+    create_consumer(&client, &config.stream, config.to_api(), CREATE_TIMEOUT).await?;
+
+    info!(
+        "Consuming stream [{}] as [{}]",
+        config.stream, config.durable_name
+    );
+    let stopping = Rc::new(Cell::new(false));
+    let pulling = Rc::new(RefCell::new(None));
+    let (pump_done_sender, pump_done) = oneshot::channel();
+    let subject = config
+        .filter_subject
+        .clone()
+        .unwrap_or_else(|| config.stream.clone());
+    let metrics = NatsMetrics::global().subscriber(&subject);
+    let connection = Connection::clone(&client);
+
+    let address = JetStreamConsumer::create(|ctx| {
+        ctx.set_mailbox_capacity(config.batch_size);
+        listeners.register(ctx.address().recipient());
+        JetStreamConsumer {
+            callback,
+            client,
+            status: StatusTracker::new(ConnectionState::Connected),
+            stopping: stopping.clone(),
+            pulling: pulling.clone(),
+            pump_done: Some(pump_done),
+            key_ring: key_ring.clone(),
+            nak_delay: config.nak_delay,
+            metrics: metrics.clone(),
+        }
+    });
+
+    let pump = Pump {
+        consumer: address.clone(),
+        connection,
+        next_subject: format!(
+            "{}.CONSUMER.MSG.NEXT.{}.{}",
+            API_PREFIX, config.stream, config.durable_name
+        ),
+        request: serde_json::json!({
+            "batch": config.batch_size,
+            "expires": config.pull_expires.as_nanos() as u64,
+        })
+        .to_string(),
+        batch_size: config.batch_size,
+        pull_expires: config.pull_expires,
+        backoff: config.client_settings.retry_policy.backoff(None),
+        stopping,
+        pulling,
+        reassembler: Reassembler::new(config.reassembly),
+        chunk_replies: HashMap::new(),
+        key_ring,
+        rejected,
+        metrics,
+    };
+    actix::spawn(async move {
+        pump.run().await;
+        debug!("Consumer of stream [{}] stopped pulling", config.stream);
+        let _ = pump_done_sender.send(());
+    });
+
+    Ok(address)
+}
+
+/// Pulls the messages, one batch after the other, and delivers them to the consumer.
+struct Pump<F>
+where
+    F: 'static + FnMut(NatsMessage) -> Result<AckKind, InternalError> + Sized + Unpin,
+{
+    consumer: Addr<JetStreamConsumer<F>>,
+    connection: Connection,
+    next_subject: String,
+    request: String,
+    batch_size: usize,
+    pull_expires: Duration,
+    backoff: RetryBackoff,
+    stopping: Rc<Cell<bool>>,
+    pulling: Rc<RefCell<Option<Rc<Subscription>>>>,
+    reassembler: Reassembler,
+    /// The reply subjects of the chunks waiting for the others, by subject and chunk id
+    chunk_replies: HashMap<(String, String), Vec<String>>,
+    /// Shared with the consumer, which updates it
+    key_ring: Option<Rc<RefCell<KeyRing>>>,
+    rejected: Option<DeadLetterSink>,
+    metrics: SubscriberMetrics,
+}
+
+impl<F> Pump<F>
+where
+    F: 'static + FnMut(NatsMessage) -> Result<AckKind, InternalError> + Sized + Unpin,
+{
+    /// Pulls until the consumer stops. The failed pulls are retried forever, per the
+    /// `retry_policy` of the client settings then every `pull_expires`.
+    async fn run(mut self) {
+        while !self.stopping.get() {
+            match self.pull().await {
+                Ok(true) => self.backoff.reset(),
+                // The consumer is stopped
+                Ok(false) => return,
+                Err(err) => {
+                    let delay = self.backoff.next_backoff().unwrap_or(self.pull_expires);
+                    warn!(
+                        "Pull from [{}] failed, retrying in {:?}. Err: {}",
+                        self.next_subject, delay, err
+                    );
+                    time::sleep(delay).await;
+                }
+            }
+        }
+    }
+
+    ///
+    /// Requests a batch of messages to a new inbox, and delivers them as they arrive. The pull
+    /// ends with the batch, or with the status message the server sends when it expires.
+    /// Returns whether the consumer is still running.
+    ///
+    async fn pull(&mut self) -> Result<bool, InternalError> {
+        let operation_error = |err: std::io::Error| InternalError::NatsOperationError {
+            cause: format! {"{}", err},
+        };
+        let inbox = self.connection.new_inbox();
+        let subscription = Rc::new(
+            self.connection
+                .subscribe(&inbox)
+                .await
+                .map_err(operation_error)?,
+        );
+        if let Err(err) = self
+            .connection
+            .publish_request(&self.next_subject, &inbox, &self.request)
+            .await
+        {
+            let _ = subscription.unsubscribe().await;
+            return Err(operation_error(err));
+        }
+        *self.pulling.borrow_mut() = Some(subscription.clone());
+        // Ends the pull if the server does not, the messages already received are delivered
+        let expired = subscription.clone();
+        let expires = self.pull_expires + PULL_GRACE;
+        let expiry = actix::spawn(async move {
+            time::sleep(expires).await;
+            let _ = expired.drain().await;
+        });
+
+        let mut running = true;
+        let mut received = 0;
+        while received < self.batch_size {
+            let mut msg = match subscription.next().await {
+                Some(msg) => msg,
+                None => break,
+            };
+            // The status messages of the server, such as `408 Request Timeout`, have no reply
+            if msg.reply.is_none() {
+                trace!("Pull from [{}] ended by the server", self.next_subject);
+                break;
+            }
+            received += 1;
+            self.metrics.received.inc();
+            let reply = msg.reply.clone().unwrap_or_default();
+            let chunk = chunk_id(msg.headers.as_ref()).map(|id| (msg.subject.clone(), id));
+            let unsealed = unseal(
+                &mut msg,
+                &mut self.reassembler,
+                self.key_ring
+                    .as_ref()
+                    .map(|key_ring| key_ring.borrow())
+                    .as_deref(),
+            );
+            let chunk_replies = self.chunk_replies(chunk, reply, &unsealed);
+            let failed = match unsealed {
+                Ok(()) => None,
+                // Acknowledged with the last chunk
+                Err(Undelivered::Pending) => continue,
+                Err(Undelivered::Rejected(err)) => {
+                    warn!("Terminating message from [{}]. Err: {}", msg.subject, err);
+                    self.metrics.rejected.inc();
+                    reject(&msg, err.clone(), self.rejected.as_ref(), &self.connection);
+                    Some(err)
+                }
+                Err(Undelivered::Failed(err)) => {
+                    error!("Terminating message from [{}]. Err: {}", msg.subject, err);
+                    self.metrics.errors.inc();
+                    Some(err)
+                }
+            };
+            let msg =
+                NatsMessage::received(msg, &self.connection).with_chunk_replies(chunk_replies);
+            if failed.is_some() {
+                let _ = msg.ack(AckKind::Term);
+                continue;
+            }
+            self.metrics.mailbox_depth.inc();
+            if self.consumer.send(msg).await.is_err() {
+                self.metrics.mailbox_depth.dec();
+                running = false;
+                break;
+            }
+        }
+
+        expiry.abort();
+        self.pulling.borrow_mut().take();
+        if let Err(err) = subscription.unsubscribe().await {
+            warn!("Cannot unsubscribe from pull inbox. Err: {}", err);
+        }
+        debug!(
+            "Pulled {} message(s) from [{}]",
+            received, self.next_subject
+        );
+        Ok(running)
+    }
+
+    ///
+    /// Keeps the `reply` subject of a chunk while the other chunks are missing, and returns
+    /// the ones kept for the message once `unsealed`. The ones of the messages given up on by
+    /// the reassembler are forgotten.
+    ///
+    fn chunk_replies(
+        &mut self,
+        chunk: Option<(String, String)>,
+        reply: String,
+        unsealed: &Result<(), Undelivered>,
+    ) -> Vec<String> {
+        let key = match chunk {
+            Some(key) => key,
+            None => return vec![],
+        };
+        let replies = if let Err(Undelivered::Pending) = unsealed {
+            self.chunk_replies.entry(key).or_default().push(reply);
+            vec![]
+        } else {
+            self.chunk_replies.remove(&key).unwrap_or_default()
+        };
+        let reassembler = &self.reassembler;
+        self.chunk_replies
+            .retain(|(subject, id), _| reassembler.is_pending(subject, id));
+        replies
+    }
+}
+
+/// Delivers the messages of a durable JetStream consumer to the callback, and acknowledges them.
+pub struct JetStreamConsumer<F>
+where
+    F: 'static + FnMut(NatsMessage) -> Result<AckKind, InternalError> + Sized + Unpin,
+{
+    callback: F,
+    // The client must live as long as the actor, otherwise the connection is dropped when the client is deallocated
+    client: SharedConnection,
+    status: StatusTracker,
+    /// Checked by the pump before each pull
+    stopping: Rc<Cell<bool>>,
+    /// The inbox of the pull in progress, drained on shutdown
+    pulling: Rc<RefCell<Option<Rc<Subscription>>>>,
+    /// Completed once the pump delivered its last message
+    pump_done: Option<oneshot::Receiver<()>>,
+    /// Shared with the pump, which verifies the messages
+    key_ring: Option<Rc<RefCell<KeyRing>>>,
+    nak_delay: Duration,
+    metrics: SubscriberMetrics,
+}
+
+impl<F> Actor for JetStreamConsumer<F>
+where
+    F: 'static + FnMut(NatsMessage) -> Result<AckKind, InternalError> + Sized + Unpin,
+{
+    type Context = Context<Self>;
+
+    fn stopped(&mut self, _: &mut Context<Self>) {
+        self.stopping.set(true);
+    }
+}
+
+impl<F> Handler<NatsMessage> for JetStreamConsumer<F>
+where
+    F: 'static + FnMut(NatsMessage) -> Result<AckKind, InternalError> + Sized + Unpin,
+{
+    type Result = Result<(), InternalError>;
+
+    fn handle(&mut self, msg: NatsMessage, _: &mut Context<Self>) -> Self::Result {
+        trace!("Message received");
+        self.metrics.mailbox_depth.dec();
+        let replier = msg.replier();
+        let started = Instant::now();
+        let result = (self.callback)(msg);
+        self.metrics
+            .duration
+            .observe(started.elapsed().as_secs_f64());
+        let kind = result.unwrap_or_else(|err| {
+            error!("Received message processing failed: {:?}", err);
+            self.metrics.errors.inc();
+            AckKind::Nak {
+                delay: Some(self.nak_delay),
+            }
+        });
+        replier.ack(kind)
+    }
+}
+
+impl<F> Handler<KeyRingUpdate> for JetStreamConsumer<F>
+where
+    F: 'static + FnMut(NatsMessage) -> Result<AckKind, InternalError> + Sized + Unpin,
+{
+    type Result = Result<(), InternalError>;
+
+    fn handle(&mut self, msg: KeyRingUpdate, _: &mut Context<Self>) -> Self::Result {
+        match &self.key_ring {
+            Some(key_ring) => {
+                info!("JetStreamConsumer updating its key ring: {:?}", msg);
+                key_ring.borrow_mut().update(msg)
+            }
+            None => Err(InternalError::ConfigurationError {
+                cause: "The consumer does not verify its messages".to_owned(),
+            }),
+        }
+    }
+}
+
+impl<F> Handler<ConnectionEvent> for JetStreamConsumer<F>
+where
+    F: 'static + FnMut(NatsMessage) -> Result<AckKind, InternalError> + Sized + Unpin,
+{
+    type Result = ();
+
+    fn handle(&mut self, msg: ConnectionEvent, _: &mut Context<Self>) {
+        self.status.on_connection_event(&msg);
+    }
+}
+
+impl<F> Handler<GetStatus> for JetStreamConsumer<F>
+where
+    F: 'static + FnMut(NatsMessage) -> Result<AckKind, InternalError> + Sized + Unpin,
+{
+    type Result = ResponseFuture<NatsStatus>;
+
+    fn handle(&mut self, _: GetStatus, _: &mut Context<Self>) -> Self::Result {
+        Box::pin(self.status.status(Some(Connection::clone(&self.client))))
+    }
+}
+
+impl<F> Handler<Shutdown> for JetStreamConsumer<F>
+where
+    F: 'static + FnMut(NatsMessage) -> Result<AckKind, InternalError> + Sized + Unpin,
+{
+    type Result = ResponseActFuture<Self, Result<(), InternalError>>;
+
+    fn handle(&mut self, msg: Shutdown, _: &mut Context<Self>) -> Self::Result {
+        info!("JetStreamConsumer shutting down, draining the pull in progress");
+        self.stopping.set(true);
+        let pulling = self.pulling.borrow().clone();
+        let pump_done = self.pump_done.take();
+        let timeout = msg.timeout;
+        let drain = async move {
+            // The messages already received are still delivered by the pump
+            if let Some(pulling) = pulling {
+                pulling
+                    .drain()
+                    .await
+                    .map_err(|err| InternalError::NatsOperationError {
+                        cause: format! {"Cannot drain pull inbox. Err: {}", err},
+                    })?;
+            }
+            if let Some(pump_done) = pump_done {
+                let _ = pump_done.await;
+            }
+            Ok(())
+        };
+        Box::pin(
+            async move {
+                time::timeout(timeout, drain).await.unwrap_or_else(|_| {
+                    Err(InternalError::NatsOperationError {
+                        cause: format! {"Pull not drained after {:?}", timeout},
+                    })
+                })
+            }
+            .into_actor(self)
+            .then(move |result, act, _| {
+                let connection = Connection::clone(&act.client);
+                act.status.set_state(ConnectionState::Closed);
+                async move {
+                    // Sends the last acknowledgements
+                    flush(&connection, timeout).await?;
+                    info!("JetStreamConsumer shut down");
+                    result
+                }
+                .into_actor(act)
+            })
+            .map(|result, _, ctx| {
+                // Stopping the actor releases the connection
+                ctx.stop();
+                result
+            }),
+        )
+    }
+}
+
+#[cfg(all(test, feature = "legacy"))]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use super::{consume, AckKind, JetStreamConsumerConfig};
+    use crate::shutdown::Shutdown;
+    use crate::test_support::FakeNatsServer;
+    use crate::{Envelope, Event, InternalError};
+
+    #[actix_rt::test]
+    async fn should_acknowledge_messages_as_answered_by_callback() {
+        let server = FakeNatsServer::start();
+        let connection = async_nats::connect(&server.address()).await.unwrap();
+        let api = connection.subscribe("$JS.API.CONSUMER.>").await.unwrap();
+        let acks = connection.subscribe("$JS.ACK.>").await.unwrap();
+        connection.flush().await.unwrap();
+        // Creates the consumer, then delivers the events to the first pull only
+        let stream = connection.clone();
+        let created = actix_rt::spawn(async move {
+            let create = api.next().await.unwrap();
+            create.respond("{}").await.unwrap();
+            let pull = api.next().await.unwrap();
+            let inbox = pull.reply.clone().unwrap();
+            for i in 1..=5 {
+                let event = Event::new(format!("event_{}", i)).encode().unwrap();
+                let ack = format!("$JS.ACK.ORDERS.worker.1.{}.{}.0.0", i, i);
+                stream.publish_request(&inbox, &ack, event).await.unwrap();
+            }
+            // The status message ending the pull
+            stream.publish(&inbox, "").await.unwrap();
+            while api.next().await.is_some() {}
+            (create, pull)
+        });
+        let in_progress = Arc::new(Mutex::new(vec![]));
+        let kept = in_progress.clone();
+        let config = JetStreamConsumerConfig {
+            client_settings: server.client_settings(),
+            stream: "ORDERS".to_owned(),
+            durable_name: "worker".to_owned(),
+            filter_subject: Some("orders.>".to_owned()),
+            batch_size: 10,
+            ack_wait: Duration::from_secs(30),
+            max_deliver: Some(3),
+            pull_expires: Duration::from_millis(100),
+            nak_delay: Duration::from_millis(500),
+            verification: None,
+            reassembly: Default::default(),
+        };
+
+        let consumer = consume(config, move |msg| {
+            let event: Event = msg.event()?;
+            match event.event_type.as_str() {
+                "event_1" => Ok(AckKind::Ack),
+                "event_2" => Ok(AckKind::Nak {
+                    delay: Some(Duration::from_secs(1)),
+                }),
+                "event_3" => Ok(AckKind::Term),
+                "event_4" => {
+                    kept.lock().unwrap().push(msg);
+                    Ok(AckKind::InProgress)
+                }
+                _ => Err(InternalError::GenericError {
+                    cause: "failed".to_owned(),
+                }),
+            }
+        })
+        .await
+        .unwrap();
+        let mut received = HashMap::new();
+        for _ in 0..5 {
+            let ack = acks.next().await.unwrap();
+            received.insert(ack.subject, String::from_utf8(ack.data).unwrap());
+        }
+        let kept = in_progress.lock().unwrap().pop().unwrap();
+        kept.ack(AckKind::Ack).unwrap();
+        let completed = acks.next().await.unwrap();
+        consumer
+            .send(Shutdown {
+                timeout: Duration::from_secs(1),
+            })
+            .await
+            .unwrap()
+            .unwrap();
+
+        let ack = |i: usize| received[&format!("$JS.ACK.ORDERS.worker.1.{}.{}.0.0", i, i)].as_str();
+        assert_eq!("+ACK", ack(1));
+        assert_eq!("-NAK {\"delay\":1000000000}", ack(2));
+        assert_eq!("+TERM", ack(3));
+        assert_eq!("+WPI", ack(4));
+        assert_eq!("-NAK {\"delay\":500000000}", ack(5));
+        assert_eq!("$JS.ACK.ORDERS.worker.1.4.4.0.0", completed.subject);
+        assert_eq!(b"+ACK".to_vec(), completed.data);
+        connection.close().await.unwrap();
+        let (create, pull) = created.await.unwrap();
+        assert_eq!(
+            "$JS.API.CONSUMER.DURABLE.CREATE.ORDERS.worker",
+            create.subject
+        );
+        let request: serde_json::Value = serde_json::from_slice(&create.data).unwrap();
+        assert_eq!("worker", request["config"]["durable_name"]);
+        assert_eq!("explicit", request["config"]["ack_policy"]);
+        assert_eq!(3, request["config"]["max_deliver"]);
+        assert_eq!("orders.>", request["config"]["filter_subject"]);
+        assert_eq!("$JS.API.CONSUMER.MSG.NEXT.ORDERS.worker", pull.subject);
+        let pulled: serde_json::Value = serde_json::from_slice(&pull.data).unwrap();
+        assert_eq!(10, pulled["batch"]);
+    }
+
+    #[actix_rt::test]
+    async fn should_acknowledge_chunks_together_and_terminate_unsigned_messages() {
+        use crate::chunking::ChunkingConfig;
+        use crate::dead_letter::{DeadLetterCallback, DeadLetterSink};
+        use crate::signing::{Algorithm, Signer, SigningKey, VerificationConfig};
+
+        let key = SigningKey {
+            id: "key-1".to_owned(),
+            algorithm: Algorithm::Ed25519,
+            secret: base64::encode([1; 32]),
+        };
+        let signer = Signer::new(&key).unwrap();
+        let server = FakeNatsServer::start();
+        let connection = async_nats::connect(&server.address()).await.unwrap();
+        let api = connection.subscribe("$JS.API.CONSUMER.>").await.unwrap();
+        let acks = connection.subscribe("$JS.ACK.>").await.unwrap();
+        connection.flush().await.unwrap();
+        // Delivers a signed event in chunks, then an unsigned one, to the first pull only
+        let stream = connection.clone();
+        let (chunked, chunk_count) = tokio::sync::oneshot::channel();
+        actix_rt::spawn(async move {
+            let create = api.next().await.unwrap();
+            create.respond("{}").await.unwrap();
+            let pull = api.next().await.unwrap();
+            let inbox = pull.reply.clone().unwrap();
+            let mut payload = serde_json::Map::new();
+            payload.insert("data".to_owned(), "x".repeat(3000).into());
+            let event = Event::new_with_payload("large", payload).encode().unwrap();
+            let headers = signer.sign(&inbox, None, &event);
+            let chunks = ChunkingConfig { max_payload: 1024 }
+                .split(headers.as_ref(), &event)
+                .unwrap()
+                .unwrap();
+            let count = chunks.len();
+            for (i, (headers, chunk)) in chunks.into_iter().enumerate() {
+                let ack = format!("$JS.ACK.ORDERS.worker.1.{}.{}.0.0", i, i);
+                stream
+                    .publish_with_reply_or_headers(&inbox, Some(&ack), Some(&headers), chunk)
+                    .await
+                    .unwrap();
+            }
+            let unsigned = Event::new("unsigned").encode().unwrap();
+            let ack = format!("$JS.ACK.ORDERS.worker.1.{}.{}.0.0", count, count);
+            stream
+                .publish_request(&inbox, &ack, unsigned)
+                .await
+                .unwrap();
+            stream.publish(&inbox, "").await.unwrap();
+            chunked.send(count).unwrap();
+            while api.next().await.is_some() {}
+        });
+        let dead_letters = Arc::new(Mutex::new(vec![]));
+        let rejected = dead_letters.clone();
+        let delivered = Arc::new(Mutex::new(vec![]));
+        let received = delivered.clone();
+        let config = JetStreamConsumerConfig {
+            client_settings: server.client_settings(),
+            stream: "ORDERS".to_owned(),
+            durable_name: "worker".to_owned(),
+            filter_subject: None,
+            batch_size: 10,
+            ack_wait: Duration::from_secs(30),
+            max_deliver: None,
+            pull_expires: Duration::from_millis(100),
+            nak_delay: Duration::from_millis(500),
+            verification: Some(VerificationConfig {
+                keys: vec![key.verifying_key().unwrap()],
+                rejected: Some(DeadLetterSink::Callback(DeadLetterCallback::new(
+                    move |letter| {
+                        rejected.lock().unwrap().push(letter);
+                        Ok(())
+                    },
+                ))),
+            }),
+            reassembly: Default::default(),
+        };
+
+        let consumer = consume(config, move |msg| {
+            let event: Event = msg.event()?;
+            received.lock().unwrap().push(event);
+            Ok(AckKind::Ack)
+        })
+        .await
+        .unwrap();
+        let count = chunk_count.await.unwrap();
+        let mut answered = HashMap::new();
+        for _ in 0..=count {
+            let ack = acks.next().await.unwrap();
+            answered.insert(ack.subject, String::from_utf8(ack.data).unwrap());
+        }
+        consumer
+            .send(Shutdown {
+                timeout: Duration::from_secs(1),
+            })
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert!(count > 1);
+        let ack = |i: usize| answered[&format!("$JS.ACK.ORDERS.worker.1.{}.{}.0.0", i, i)].as_str();
+        for i in 0..count {
+            assert_eq!("+ACK", ack(i));
+        }
+        assert_eq!("+TERM", ack(count));
+        connection.close().await.unwrap();
+        let delivered = delivered.lock().unwrap();
+        assert_eq!(1, delivered.len());
+        assert_eq!("large", delivered[0].event_type);
+        assert_eq!(1, dead_letters.lock().unwrap().len());
+    }
+}
@@ -0,0 +1,204 @@
+use std::fmt::{Debug, Formatter};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use async_nats::Connection;
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncWriteExt;
+
+use crate::{Envelope, InternalError};
+
+///
+/// An event the `NatsPublisher` gave up on, or a message a `NatsSubscriber` rejected, with
+/// the reason of the failure.
+///
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DeadLetter {
+    /// The subject the event was meant to be published to.
+    pub subject: String,
+    /// The event, as serialized by its `Envelope`.
+    pub event: serde_json::Value,
+    /// The number of failed publish attempts, `0` when the event could not be published at all.
+    pub attempts: usize,
+    pub last_error: String,
+    pub first_attempt_ms: u64,
+    pub failed_ms: u64,
+}
+
+impl DeadLetter {
+    pub(crate) fn new<E: Envelope>(
+        subject: String,
+        event: &E,
+        attempts: usize,
+        last_error: &InternalError,
+        first_attempt_ms: u64,
+    ) -> DeadLetter {
+        DeadLetter {
+            subject,
+            event: serde_json::to_value(event).unwrap_or_else(|err| {
+                serde_json::Value::String(format! {"Event not serializable. Err: {}", err})
+            }),
+            attempts,
+            last_error: last_error.to_string(),
+            first_attempt_ms,
+            failed_ms: now_ms(),
+        }
+    }
+
+    /// A message received on `subject` and not delivered.
+    pub(crate) fn rejected(subject: String, payload: &[u8], err: &InternalError) -> DeadLetter {
+        let now = now_ms();
+        DeadLetter {
+            subject,
+            event: serde_json::from_slice(payload).unwrap_or_else(|_| {
+                serde_json::Value::String(String::from_utf8_lossy(payload).into_owned())
+            }),
+            attempts: 0,
+            last_error: err.to_string(),
+            first_attempt_ms: now,
+            failed_ms: now,
+        }
+    }
+}
+
+/// Where the `NatsPublisher` sends the events it gave up on, and a `NatsSubscriber` the
+/// messages it rejected.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum DeadLetterSink {
+    /// Publishes the dead letters as JSON to `subject`, over the connection of the publisher.
+    Subject { subject: String },
+    /// Appends the dead letters as JSON lines to the file at `path`.
+    File { path: PathBuf },
+    /// Hands the dead letters to a function. Only available from code.
+    #[serde(skip)]
+    Callback(DeadLetterCallback),
+}
+
+/// The function of a `DeadLetterSink::Callback`.
+#[derive(Clone)]
+pub struct DeadLetterCallback(Arc<dyn Fn(DeadLetter) -> Result<(), InternalError> + Send + Sync>);
+
+impl DeadLetterCallback {
+    pub fn new<F>(callback: F) -> DeadLetterCallback
+    where
+        F: Fn(DeadLetter) -> Result<(), InternalError> + Send + Sync + 'static,
+    {
+        DeadLetterCallback(Arc::new(callback))
+    }
+}
+
+impl Debug for DeadLetterCallback {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str("DeadLetterCallback")
+    }
+}
+
+impl DeadLetterSink {
+    /// Sends `letter` to the sink. `connection` is the one of the publisher, if any.
+    pub(crate) async fn send(
+        &self,
+        letter: DeadLetter,
+        connection: Option<Connection>,
+    ) -> Result<(), InternalError> {
+        match self {
+            DeadLetterSink::Subject { subject } => {
+                let connection = connection.ok_or_else(|| InternalError::NatsOperationError {
+                    cause: "No NATS connection to publish the dead letter".to_owned(),
+                })?;
+                connection
+                    .publish(subject, to_json(&letter)?)
+                    .await
+                    .map_err(|err| InternalError::NatsOperationError {
+                        cause: format! {"Cannot publish the dead letter. Err: {}", err},
+                    })
+            }
+            DeadLetterSink::File { path } => {
+                let mut line = to_json(&letter)?;
+                line.push(b'\n');
+                let write = async {
+                    let mut file = tokio::fs::OpenOptions::new()
+                        .create(true)
+                        .append(true)
+                        .open(path)
+                        .await?;
+                    file.write_all(&line).await?;
+                    file.flush().await
+                };
+                write.await.map_err(|err| InternalError::GenericError {
+                    cause: format! {"Cannot write the dead letter to {:?}. Err: {}", path, err},
+                })
+            }
+            DeadLetterSink::Callback(callback) => (callback.0)(letter),
+        }
+    }
+}
+
+fn to_json(letter: &DeadLetter) -> Result<Vec<u8>, InternalError> {
+    serde_json::to_vec(letter).map_err(|err| InternalError::SerdeError {
+        cause: format! {"{}", err},
+    })
+}
+
+pub(crate) fn now_ms() -> u64 {
+    Utc::now().timestamp_millis() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DeadLetter, DeadLetterSink};
+    use crate::InternalError;
+
+    fn letter() -> DeadLetter {
+        DeadLetter {
+            subject: "events".to_owned(),
+            event: serde_json::json!({"type": "event_type"}),
+            attempts: 3,
+            last_error: "Nats operation failed: timeout".to_owned(),
+            first_attempt_ms: 1,
+            failed_ms: 2,
+        }
+    }
+
+    #[test]
+    fn should_parse_dead_letter_sink_config() {
+        let sink: DeadLetterSink =
+            serde_json::from_str(r#"{"type": "file", "path": "/tmp/dead_letters.jsonl"}"#).unwrap();
+
+        assert!(matches!(sink, DeadLetterSink::File { .. }));
+        assert!(serde_json::from_str::<DeadLetterSink>(r#"{"type": "callback"}"#).is_err());
+    }
+
+    #[actix_rt::test]
+    async fn should_append_dead_letters_to_file() {
+        let path =
+            std::env::temp_dir().join(format!("dead_letters_{}.jsonl", uuid::Uuid::new_v4()));
+        let sink = DeadLetterSink::File { path: path.clone() };
+
+        sink.send(letter(), None).await.unwrap();
+        sink.send(letter(), None).await.unwrap();
+
+        let content = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let letters: Vec<DeadLetter> = content
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(vec![letter(), letter()], letters);
+    }
+
+    #[actix_rt::test]
+    async fn should_require_a_connection_for_subject_sink() {
+        let sink = DeadLetterSink::Subject {
+            subject: "dead_letters".to_owned(),
+        };
+
+        let result = sink.send(letter(), None).await;
+
+        assert!(matches!(
+            result,
+            Err(InternalError::NatsOperationError { .. })
+        ));
+    }
+}
@@ -0,0 +1,192 @@
+use std::convert::TryFrom;
+
+use async_nats::Headers;
+use cloudevents::event::SpecVersion;
+use cloudevents::message::{BinaryDeserializer, BinarySerializer, MessageAttributeValue};
+use cloudevents::{AttributesReader, Data};
+
+use crate::envelope::Envelope;
+use crate::InternalError;
+
+/// The prefix of the attribute headers in binary mode.
+const ATTRIBUTE_PREFIX: &str = "ce-";
+const SPEC_VERSION_HEADER: &str = "ce-specversion";
+/// Carries `datacontenttype` in binary mode, and the format of the event in structured mode.
+const CONTENT_TYPE_HEADER: &str = "content-type";
+const STRUCTURED_CONTENT_TYPE: &str = "application/cloudevents+json";
+
+/// CloudEvents v1.0 envelope, serialized in the structured JSON mode unless `ContentMode::Binary`
+/// is configured, following the CloudEvents NATS protocol binding.
+impl Envelope for cloudevents::Event {
+    fn trace_id(&self) -> &str {
+        self.id()
+    }
+
+    /// The context attributes, `source_host` (the host of `source`) and the extensions.
+    fn attribute(&self, name: &str) -> Option<String> {
+        match name {
+            "id" => Some(self.id().to_owned()),
+            "type" => Some(self.ty().to_owned()),
+            "source" => Some(self.source().to_string()),
+            "source_host" => source_host(self.source()).map(str::to_owned),
+            "subject" => self.subject().map(str::to_owned),
+            "datacontenttype" => self.datacontenttype().map(str::to_owned),
+            extension => self.extension(extension).map(ToString::to_string),
+        }
+    }
+
+    fn encode_binary(&self) -> Result<(Headers, Vec<u8>), InternalError> {
+        let message = self
+            .clone()
+            .deserialize_binary(BinaryMessage::default())
+            .map_err(binding_error)?;
+        Ok((message.headers.into_iter().collect(), message.payload))
+    }
+
+    /// Reads the binary mode when the message has a `ce-specversion` header, the structured
+    /// mode otherwise.
+    fn decode_message(headers: Option<&Headers>, data: &[u8]) -> Result<Self, InternalError> {
+        let headers: Vec<(String, String)> = headers
+            .map(|headers| {
+                headers
+                    .iter()
+                    .filter_map(|(name, values)| {
+                        let value = values.iter().next()?;
+                        Some((name.to_ascii_lowercase(), value.clone()))
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        let structured = headers.iter().any(|(name, value)| {
+            name == CONTENT_TYPE_HEADER && value.starts_with(STRUCTURED_CONTENT_TYPE)
+        });
+        let binary = headers.iter().any(|(name, _)| name == SPEC_VERSION_HEADER);
+        if structured || !binary {
+            return Self::decode(data);
+        }
+        let message = BinaryMessage {
+            headers,
+            payload: data.to_vec(),
+        };
+        let mut event = message.into_event().map_err(binding_error)?;
+        // Gives back the JSON data the structured mode would have decoded
+        if let (Some(Data::Binary(bytes)), Some(content_type)) =
+            (event.data(), event.datacontenttype())
+        {
+            if is_json(content_type) {
+                if let Ok(json) = serde_json::from_slice::<serde_json::Value>(bytes) {
+                    event.set_data_unchecked(json);
+                }
+            }
+        }
+        Ok(event)
+    }
+}
+
+/// A NATS message in the binary mode of the CloudEvents NATS protocol binding.
+#[derive(Default)]
+struct BinaryMessage {
+    /// With lowercase names
+    headers: Vec<(String, String)>,
+    payload: Vec<u8>,
+}
+
+fn header_name(attribute: &str) -> String {
+    if attribute == "datacontenttype" {
+        CONTENT_TYPE_HEADER.to_owned()
+    } else {
+        format!("{}{}", ATTRIBUTE_PREFIX, attribute)
+    }
+}
+
+impl BinarySerializer<BinaryMessage> for BinaryMessage {
+    fn set_spec_version(mut self, spec_version: SpecVersion) -> cloudevents::message::Result<Self> {
+        self.headers
+            .push((SPEC_VERSION_HEADER.to_owned(), spec_version.to_string()));
+        Ok(self)
+    }
+
+    fn set_attribute(
+        mut self,
+        name: &str,
+        value: MessageAttributeValue,
+    ) -> cloudevents::message::Result<Self> {
+        self.headers.push((header_name(name), value.to_string()));
+        Ok(self)
+    }
+
+    fn set_extension(
+        mut self,
+        name: &str,
+        value: MessageAttributeValue,
+    ) -> cloudevents::message::Result<Self> {
+        self.headers.push((header_name(name), value.to_string()));
+        Ok(self)
+    }
+
+    fn end_with_data(mut self, bytes: Vec<u8>) -> cloudevents::message::Result<BinaryMessage> {
+        self.payload = bytes;
+        Ok(self)
+    }
+
+    fn end(self) -> cloudevents::message::Result<BinaryMessage> {
+        Ok(self)
+    }
+}
+
+impl BinaryDeserializer for BinaryMessage {
+    fn deserialize_binary<R: Sized, V: BinarySerializer<R>>(
+        self,
+        mut visitor: V,
+    ) -> cloudevents::message::Result<R> {
+        let spec_version = self
+            .headers
+            .iter()
+            .find(|(name, _)| name == SPEC_VERSION_HEADER)
+            .map(|(_, value)| SpecVersion::try_from(value.as_str()))
+            .ok_or(cloudevents::message::Error::WrongEncoding {})??;
+        visitor = visitor.set_spec_version(spec_version.clone())?;
+
+        let attributes = spec_version.attribute_names();
+        for (name, value) in self.headers {
+            let value = MessageAttributeValue::String(value);
+            if name == CONTENT_TYPE_HEADER {
+                visitor = visitor.set_attribute("datacontenttype", value)?;
+            } else if let Some(name) = name.strip_prefix(ATTRIBUTE_PREFIX) {
+                if name == "specversion" {
+                    continue;
+                } else if attributes.contains(&name) {
+                    visitor = visitor.set_attribute(name, value)?;
+                } else {
+                    visitor = visitor.set_extension(name, value)?;
+                }
+            }
+        }
+
+        if self.payload.is_empty() {
+            visitor.end()
+        } else {
+            visitor.end_with_data(self.payload)
+        }
+    }
+}
+
+fn is_json(content_type: &str) -> bool {
+    let essence = content_type.split(';').next().unwrap_or_default().trim();
+    essence == "application/json" || essence.ends_with("+json")
+}
+
+fn binding_error(err: cloudevents::message::Error) -> InternalError {
+    InternalError::SerdeError {
+        cause: format! {"CloudEvents NATS binding: {}", err},
+    }
+}
+
+/// The host of a `scheme://[user@]host[:port]/path` source.
+fn source_host(source: &str) -> Option<&str> {
+    let (_, authority) = source.split_once("://")?;
+    let authority = authority.split(['/', '?', '#']).next()?;
+    let host = authority.rsplit('@').next()?;
+    let host = host.split(':').next()?;
+    (!host.is_empty()).then_some(host)
+}
@@ -0,0 +1,65 @@
+use chrono::prelude::Local;
+use serde::{Deserialize, Deserializer, Serialize};
+
+use crate::envelope::Envelope;
+
+/// An Event is published from a service to other services using NATS.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Default)]
+pub struct Event {
+    #[serde(default = "default_trace_id")]
+    #[serde(deserialize_with = "deserialize_null_trace_id")]
+    pub trace_id: String,
+    #[serde(rename = "type")]
+    pub event_type: String,
+    pub created_ms: u64,
+    pub payload: Payload,
+}
+
+impl Event {
+    pub fn new<S: Into<String>>(event_type: S) -> Event {
+        Event::new_with_payload(event_type, Map::new())
+    }
+
+    pub fn new_with_payload<S: Into<String>>(event_type: S, payload: Payload) -> Event {
+        let dt = Local::now(); // e.g. `2014-11-28T21:45:59.324310806+09:00`
+        let created_ms = dt.timestamp_millis() as u64;
+        Event {
+            trace_id: default_trace_id(),
+            event_type: event_type.into(),
+            created_ms,
+            payload,
+        }
+    }
+}
+
+pub type Payload = Map<String, Value>;
+pub type Value = serde_json::Value;
+pub type Map<K, V> = serde_json::Map<K, V>;
+pub type Number = serde_json::Number;
+
+#[inline]
+fn default_trace_id() -> String {
+    uuid::Uuid::new_v4().to_string()
+}
+
+fn deserialize_null_trace_id<'de, D>(deserializer: D) -> Result<String, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let opt = Option::deserialize(deserializer)?;
+    Ok(opt.unwrap_or_else(default_trace_id))
+}
+
+impl Envelope for Event {
+    fn trace_id(&self) -> &str {
+        &self.trace_id
+    }
+
+    fn attribute(&self, name: &str) -> Option<String> {
+        match name {
+            "event_type" | "type" => Some(self.event_type.clone()),
+            "trace_id" => Some(self.trace_id.clone()),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,156 @@
+use std::fmt::Debug;
+
+use async_nats::Headers;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use crate::InternalError;
+
+#[cfg(feature = "cloudevents")]
+pub mod cloudevent;
+#[cfg(feature = "legacy")]
+pub mod legacy;
+
+///
+/// How an event is carried by a NATS message, as defined by the CloudEvents NATS protocol binding.
+///
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ContentMode {
+    /// The whole event is serialized in the payload.
+    #[default]
+    Structured,
+    /// The attributes are `ce-*` headers and the payload is the data of the event.
+    Binary,
+}
+
+impl ContentMode {
+    /// The content mode of a received NATS message: binary when it has a `ce-specversion` header.
+    pub fn of(headers: Option<&Headers>) -> ContentMode {
+        let binary = headers.is_some_and(|headers| {
+            headers
+                .keys()
+                .any(|name| name.eq_ignore_ascii_case("ce-specversion"))
+        });
+        if binary {
+            ContentMode::Binary
+        } else {
+            ContentMode::Structured
+        }
+    }
+}
+
+///
+/// The format of the events carried by an `EventMessage`.
+/// The publisher and the subscriber only rely on this trait, so a service picks its format
+/// through the cargo features (`legacy` and/or `cloudevents`).
+///
+pub trait Envelope: Serialize + DeserializeOwned + Debug + Clone + Send + Unpin + 'static {
+    /// The id correlating the logs of the event across services.
+    fn trace_id(&self) -> &str;
+
+    /// The value of the attribute `name`, referenced as `{name}` in a subject template.
+    fn attribute(&self, _name: &str) -> Option<String> {
+        None
+    }
+
+    /// Serializes the event into the payload of a NATS message.
+    fn encode(&self) -> Result<Vec<u8>, InternalError> {
+        serde_json::to_vec(self).map_err(|err| InternalError::SerdeError {
+            cause: format! {"{}", err},
+        })
+    }
+
+    /// Deserializes the event from the payload of a NATS message.
+    fn decode(data: &[u8]) -> Result<Self, InternalError> {
+        serde_json::from_slice(data).map_err(|err| InternalError::SerdeError {
+            cause: format! {"{}", err},
+        })
+    }
+
+    /// Serializes the event into the headers and the payload of a NATS message in binary mode.
+    fn encode_binary(&self) -> Result<(Headers, Vec<u8>), InternalError> {
+        Err(InternalError::ConfigurationError {
+            cause: "Binary content mode not supported by this envelope".to_owned(),
+        })
+    }
+
+    /// Deserializes the event from a NATS message, whatever its content mode.
+    fn decode_message(_headers: Option<&Headers>, data: &[u8]) -> Result<Self, InternalError> {
+        Self::decode(data)
+    }
+
+    /// Serializes the event into the headers, if any, and the payload of a NATS message.
+    fn encode_as(&self, mode: ContentMode) -> Result<(Option<Headers>, Vec<u8>), InternalError> {
+        match mode {
+            ContentMode::Structured => Ok((None, self.encode()?)),
+            ContentMode::Binary => {
+                let (headers, payload) = self.encode_binary()?;
+                Ok((Some(headers), payload))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Envelope;
+
+    #[cfg(feature = "legacy")]
+    #[test]
+    fn should_round_trip_legacy_event() {
+        let event = crate::Event::new("event_type");
+
+        let decoded = crate::Event::decode(&event.encode().unwrap()).unwrap();
+
+        assert_eq!(event, decoded);
+        assert_eq!(event.trace_id, decoded.trace_id());
+    }
+
+    #[cfg(feature = "cloudevents")]
+    #[test]
+    fn should_round_trip_cloud_event() {
+        use cloudevents::{EventBuilder, EventBuilderV10};
+
+        let event = EventBuilderV10::new()
+            .source("http://localhost")
+            .id("trace_id")
+            .ty("com.example.hello")
+            .data("application/json", serde_json::json!({"user": "Ram"}))
+            .build()
+            .unwrap();
+
+        let decoded = cloudevents::Event::decode(&event.encode().unwrap()).unwrap();
+
+        assert_eq!(event, decoded);
+        assert_eq!("trace_id", decoded.trace_id());
+    }
+
+    #[cfg(feature = "cloudevents")]
+    #[test]
+    fn should_round_trip_cloud_event_in_binary_mode() {
+        use cloudevents::{EventBuilder, EventBuilderV10};
+
+        let event = EventBuilderV10::new()
+            .source("http://localhost")
+            .id("trace_id")
+            .ty("com.example.hello")
+            .extension("tenant", "acme")
+            .data("application/json", serde_json::json!({"user": "Ram"}))
+            .build()
+            .unwrap();
+
+        let (headers, payload) = event.encode_binary().unwrap();
+        let decoded = cloudevents::Event::decode_message(Some(&headers), &payload).unwrap();
+        let structured = cloudevents::Event::decode_message(None, &event.encode().unwrap());
+
+        let header = |name: &str| headers.get(name).and_then(|values| values.iter().next());
+        assert_eq!(Some(&"1.0".to_owned()), header("ce-specversion"));
+        assert_eq!(Some(&"com.example.hello".to_owned()), header("ce-type"));
+        assert_eq!(Some(&"acme".to_owned()), header("ce-tenant"));
+        assert_eq!(Some(&"application/json".to_owned()), header("content-type"));
+        assert_eq!(br#"{"user":"Ram"}"#.to_vec(), payload);
+        assert_eq!(event, decoded);
+        assert_eq!(event, structured.unwrap());
+    }
+}
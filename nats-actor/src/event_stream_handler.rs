@@ -0,0 +1,120 @@
+extern crate actix;
+extern crate actix_rt;
+use crate::model::event::nats::{ping::PingMessage, pong::PongMessage};
+use actix::prelude::*;
+use serde::{Deserialize, Serialize};
+
+#[derive(Message)]
+#[rtype(result = "Result<bool, std::io::Error>")]
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub enum MyLocalEvent {
+    Ping(PingMessage),
+    Pong(PongMessage),
+}
+
+// Define actor
+pub struct EventStreamHandler;
+
+// Provide Actor implementation for our actor
+impl Actor for EventStreamHandler {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Context<Self>) {
+        println!("Actor is alive");
+    }
+
+    fn stopped(&mut self, ctx: &mut Context<Self>) {
+        println!("Actor is stopped");
+    }
+}
+
+/// Define handler for `Ping` message
+impl Handler<PingMessage> for EventStreamHandler {
+    type Result = Result<bool, std::io::Error>;
+
+    fn handle(&mut self, msg: PingMessage, ctx: &mut Context<Self>) -> Self::Result {
+        println!("Ping received: {:?}", msg);
+
+        Ok(true)
+    }
+}
+
+/// Define handler for `Pong` message
+impl Handler<PongMessage> for EventStreamHandler {
+    type Result = Result<bool, std::io::Error>;
+
+    fn handle(&mut self, msg: PongMessage, ctx: &mut Context<Self>) -> Self::Result {
+        println!("Pong received: {:?}", msg);
+
+        Ok(true)
+    }
+}
+
+/// Define handler for `Pong` message
+impl Handler<MyLocalEvent> for EventStreamHandler {
+    type Result = Result<bool, std::io::Error>;
+
+    fn handle(&mut self, msg: MyLocalEvent, ctx: &mut Context<Self>) -> Self::Result {
+        println!("MyEnum received: {:?}", msg);
+
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix::Actor;
+    use serial_test::serial;
+
+    use super::{EventStreamHandler, MyLocalEvent};
+    use crate::model::event::nats::{ping::PingMessage, pong::PongMessage};
+
+    #[actix_rt::test]
+    #[serial]
+    async fn test_nominal() {
+        // Start MyActor in current thread
+        let addr = EventStreamHandler.start();
+
+        // Send Ping message.
+        // send() message returns Future object, that resolves to message result
+        let result = addr
+            .send(PingMessage {
+                trace_id: "trace_ping".into(),
+                message: "ping".into(),
+            })
+            .await;
+
+        match result {
+            Ok(res) => println!("Got result: {}", res.unwrap()),
+            Err(err) => println!("Got error: {}", err),
+        }
+
+        // Send Pong message.
+        // send() message returns Future object, that resolves to message result
+        let result = addr
+            .send(PongMessage {
+                trace_id: "trace_pong".into(),
+                user_id: 12345,
+            })
+            .await;
+
+        match result {
+            Ok(res) => println!("Got result: {}", res.unwrap()),
+            Err(err) => println!("Got error: {}", err),
+        }
+
+        // Send Ping message wrapped in MyLocalEvent.
+        // send() message returns Future object, that resolves to message result
+        let result = addr
+            .send(MyLocalEvent::Ping(PingMessage {
+                trace_id: "trace_ping".into(),
+                message: "ping".into(),
+            }))
+            .await;
+
+        match result {
+            Ok(res) => println!("Got result: {}", res.unwrap()),
+            Err(err) => println!("Got error: {}", err),
+        }
+    }
+}
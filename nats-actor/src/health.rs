@@ -0,0 +1,255 @@
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::future::Future;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use actix::prelude::*;
+use actix_web::{get, web, HttpResponse};
+use async_nats::Connection;
+use futures_util::future::join_all;
+use serde::{Deserialize, Serialize};
+
+use crate::connection_event::ConnectionEvent;
+use crate::flush;
+use crate::outbox::OutboxStats;
+use crate::rate_limit::RateLimitStats;
+
+/// Maximum time waited for the server to answer the flush measuring the round trip time.
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// The state of the NATS connection held by a publisher or a subscriber.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConnectionState {
+    Connecting,
+    Connected,
+    Disconnected,
+    Closed,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NatsStatus {
+    pub state: ConnectionState,
+    pub last_error: Option<String>,
+    /// The number of times the connection was re-established.
+    pub reconnects: usize,
+    /// The round trip time of a flush to the server. `None` when it could not be measured.
+    pub rtt: Option<Duration>,
+    /// The depth of the outbox of a publisher, when enabled.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub outbox: Option<OutboxStats>,
+    /// The events held back by the rate limits of a publisher, when enabled.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rate_limit: Option<RateLimitStats>,
+}
+
+impl NatsStatus {
+    pub fn is_healthy(&self) -> bool {
+        self.state == ConnectionState::Connected && self.rtt.is_some()
+    }
+}
+
+/// Asks a `NatsPublisher` or a `NatsSubscriber` for the status of its connection.
+#[derive(Message, Debug)]
+#[rtype(result = "NatsStatus")]
+pub struct GetStatus;
+
+///
+/// The connection status of an actor, shared with the futures it spawns.
+///
+#[derive(Clone)]
+pub(crate) struct StatusTracker {
+    status: Rc<RefCell<NatsStatus>>,
+}
+
+impl StatusTracker {
+    pub(crate) fn new(state: ConnectionState) -> StatusTracker {
+        StatusTracker {
+            status: Rc::new(RefCell::new(NatsStatus {
+                state,
+                last_error: None,
+                reconnects: 0,
+                rtt: None,
+                outbox: None,
+                rate_limit: None,
+            })),
+        }
+    }
+
+    pub(crate) fn set_state(&self, state: ConnectionState) {
+        self.status.borrow_mut().state = state;
+    }
+
+    pub(crate) fn reconnected(&self) {
+        let mut status = self.status.borrow_mut();
+        status.state = ConnectionState::Connected;
+        status.reconnects += 1;
+    }
+
+    pub(crate) fn record_error<E: Display>(&self, err: E) {
+        self.status.borrow_mut().last_error = Some(err.to_string());
+    }
+
+    pub(crate) fn on_connection_event(&self, event: &ConnectionEvent) {
+        match event {
+            ConnectionEvent::Disconnected => self.set_state(ConnectionState::Disconnected),
+            ConnectionEvent::Reconnected => self.reconnected(),
+            ConnectionEvent::Closed => self.set_state(ConnectionState::Closed),
+            ConnectionEvent::SlowConsumer { .. } => {}
+        }
+    }
+
+    /// The current status, measuring the round trip time on `connection` when connected.
+    pub(crate) fn status(
+        &self,
+        connection: Option<Connection>,
+    ) -> impl Future<Output = NatsStatus> + 'static {
+        let tracker = self.clone();
+        async move {
+            let connected = tracker.status.borrow().state == ConnectionState::Connected;
+            let rtt = match connection {
+                Some(connection) if connected => {
+                    let start = Instant::now();
+                    match flush(&connection, FLUSH_TIMEOUT).await {
+                        Ok(()) => Some(start.elapsed()),
+                        Err(err) => {
+                            tracker.record_error(err);
+                            None
+                        }
+                    }
+                }
+                _ => None,
+            };
+            NatsStatus {
+                rtt,
+                ..tracker.status.borrow().clone()
+            }
+        }
+    }
+}
+
+///
+/// The actors reported by the `/health/nats` route, registered as app data:
+/// `App::new().app_data(web::Data::new(health)).service(health_nats)`.
+///
+#[derive(Clone, Default)]
+pub struct NatsHealth {
+    actors: Vec<(String, Recipient<GetStatus>)>,
+}
+
+impl NatsHealth {
+    pub fn new() -> NatsHealth {
+        NatsHealth::default()
+    }
+
+    pub fn with<S: Into<String>>(mut self, name: S, actor: Recipient<GetStatus>) -> NatsHealth {
+        self.actors.push((name.into(), actor));
+        self
+    }
+
+    /// The status of each registered actor. An actor that does not answer is reported as closed.
+    pub async fn statuses(&self) -> BTreeMap<String, NatsStatus> {
+        let statuses = join_all(self.actors.iter().map(|(name, actor)| async move {
+            let status = actor
+                .send(GetStatus)
+                .await
+                .unwrap_or_else(|err| NatsStatus {
+                    state: ConnectionState::Closed,
+                    last_error: Some(format! {"Actor not reachable. Err: {}", err}),
+                    reconnects: 0,
+                    rtt: None,
+                    outbox: None,
+                    rate_limit: None,
+                });
+            (name.clone(), status)
+        }))
+        .await;
+        statuses.into_iter().collect()
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct NatsHealthReport {
+    pub healthy: bool,
+    pub actors: BTreeMap<String, NatsStatus>,
+}
+
+///
+/// Readiness probe: answers `200 OK` when all the actors registered in `NatsHealth` are
+/// connected, `503 Service Unavailable` otherwise. The body is a `NatsHealthReport`.
+///
+#[get("/health/nats")]
+pub async fn health_nats(health: web::Data<NatsHealth>) -> HttpResponse {
+    let actors = health.statuses().await;
+    let healthy = actors.values().all(NatsStatus::is_healthy);
+    let report = NatsHealthReport { healthy, actors };
+    if healthy {
+        HttpResponse::Ok().json(report)
+    } else {
+        HttpResponse::ServiceUnavailable().json(report)
+    }
+}
+
+#[cfg(all(test, feature = "legacy"))]
+mod tests {
+    use actix_web::{test, web, App};
+
+    use super::{health_nats, ConnectionState, GetStatus, NatsHealth, NatsHealthReport};
+    use crate::publisher::{NatsPublisher, NatsPublisherConfig};
+    use crate::subscriber::{subscribe, NatsSubscriberConfig};
+    use crate::test_support::FakeNatsServer;
+    use crate::Event;
+
+    #[actix_rt::test]
+    async fn should_report_connection_status() {
+        let mut server = FakeNatsServer::start();
+        let client_settings = server.client_settings();
+
+        let publisher = NatsPublisher::<Event>::start_new(NatsPublisherConfig {
+            client_settings: client_settings.clone(),
+            subject: "health".to_owned(),
+            mailbox_size: 10,
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+        let subscriber = subscribe(
+            NatsSubscriberConfig {
+                client_settings,
+                subject: "health".to_owned(),
+                mailbox_size: 10,
+                verification: None,
+                reassembly: Default::default(),
+            },
+            |_| Ok(()),
+        )
+        .await
+        .unwrap();
+
+        let status = publisher.send(GetStatus).await.unwrap();
+        assert_eq!(ConnectionState::Connected, status.state);
+        assert!(status.is_healthy());
+
+        let health = NatsHealth::new()
+            .with("publisher", publisher.recipient())
+            .with("subscriber", subscriber.recipient());
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(health))
+                .service(health_nats),
+        )
+        .await;
+
+        let request = test::TestRequest::get().uri("/health/nats").to_request();
+        let report: NatsHealthReport = test::call_and_read_body_json(&app, request).await;
+        assert!(report.healthy);
+        assert_eq!(2, report.actors.len());
+
+        server.stop();
+        let request = test::TestRequest::get().uri("/health/nats").to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(503, response.status().as_u16());
+    }
+}
@@ -0,0 +1,469 @@
+use std::fmt;
+use std::io;
+use std::time::Duration;
+
+use actix::prelude::Message;
+use async_nats::{Connection, Headers};
+use log::*;
+use serde::de::{DeserializeOwned, IgnoredAny};
+use serde::{Deserialize, Serialize};
+use tokio::time;
+
+use crate::subject::validate_subject;
+use crate::{Envelope, EventMessage, InternalError};
+
+/// The header carrying the id the streams discard the duplicates of.
+pub const MSG_ID_HEADER: &str = "Nats-Msg-Id";
+
+/// The prefix of the subjects of the JetStream API.
+pub(crate) const API_PREFIX: &str = "$JS.API";
+/// The code of the API error of a stream created with a name already in use.
+const STREAM_NAME_IN_USE: u64 = 10058;
+
+///
+/// Publishes the events to a JetStream stream: each event is sent with a `Nats-Msg-Id` header
+/// set to its `trace_id`, the id of a CloudEvent, and confirmed by the `PubAck` of the stream
+/// instead of a flush. The retried events are thus stored once.
+///
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+pub struct JetStreamConfig {
+    /// The stream created, or updated, when the publisher connects. The stream capturing the
+    /// subjects of the events must exist when not set.
+    #[serde(default)]
+    pub stream: Option<StreamConfig>,
+    /// Maximum time waited for the `PubAck` of an event.
+    #[serde(default = "default_ack_timeout")]
+    pub ack_timeout: Duration,
+}
+
+fn default_ack_timeout() -> Duration {
+    Duration::from_secs(5)
+}
+
+impl JetStreamConfig {
+    pub(crate) fn validate(&self) -> Result<(), InternalError> {
+        match &self.stream {
+            Some(stream) => stream.validate(),
+            None => Ok(()),
+        }
+    }
+}
+
+/// A stream as declared to the server, the other settings keeping the defaults of the server.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+pub struct StreamConfig {
+    pub name: String,
+    /// The subjects stored by the stream, possibly with wildcards. Only the name of the stream
+    /// when empty.
+    #[serde(default)]
+    pub subjects: Vec<String>,
+    #[serde(default)]
+    pub retention: Retention,
+    /// The age after which the messages are discarded, never when not set.
+    #[serde(default)]
+    pub max_age: Option<Duration>,
+    #[serde(default = "default_replicas")]
+    pub replicas: usize,
+    #[serde(default)]
+    pub storage: Storage,
+    /// The window in which the duplicates of a `Nats-Msg-Id` are discarded, 2 minutes by
+    /// default on the server.
+    #[serde(default)]
+    pub duplicate_window: Option<Duration>,
+}
+
+fn default_replicas() -> usize {
+    1
+}
+
+/// When the messages of a stream are removed.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Retention {
+    /// Once over the limits of the stream, such as `max_age`.
+    #[default]
+    Limits,
+    /// Once acknowledged by all the consumers of the stream.
+    Interest,
+    /// Once acknowledged by one consumer.
+    WorkQueue,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Storage {
+    #[default]
+    File,
+    Memory,
+}
+
+impl StreamConfig {
+    pub(crate) fn validate(&self) -> Result<(), InternalError> {
+        let invalid = |cause: String| Err(InternalError::ConfigurationError { cause });
+        validate_name("stream", &self.name)?;
+        if !(1..=5).contains(&self.replicas) {
+            return invalid(format! {"Stream [{}] replicas must be in [1, 5]", self.name});
+        }
+        for subject in &self.subjects {
+            if validate_subject(subject, true).is_err() {
+                return invalid(
+                    format! {"Invalid subject [{}] for stream [{}]", subject, self.name},
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// The configuration in the format of the JetStream API, durations in nanoseconds.
+    fn to_api(&self) -> serde_json::Value {
+        let subjects = if self.subjects.is_empty() {
+            vec![self.name.clone()]
+        } else {
+            self.subjects.clone()
+        };
+        let retention = match self.retention {
+            Retention::Limits => "limits",
+            Retention::Interest => "interest",
+            Retention::WorkQueue => "workqueue",
+        };
+        let storage = match self.storage {
+            Storage::File => "file",
+            Storage::Memory => "memory",
+        };
+        let nanos = |duration: Option<Duration>| duration.map_or(0, |d| d.as_nanos() as u64);
+        serde_json::json!({
+            "name": self.name,
+            "subjects": subjects,
+            "retention": retention,
+            "max_consumers": -1,
+            "max_msgs": -1,
+            "max_bytes": -1,
+            "max_age": nanos(self.max_age),
+            "max_msg_size": -1,
+            "storage": storage,
+            "discard": "old",
+            "num_replicas": self.replicas,
+            "duplicate_window": nanos(self.duplicate_window),
+        })
+    }
+}
+
+/// Rejects the names of streams and consumers that cannot be a token of the API subjects.
+pub(crate) fn validate_name(kind: &str, name: &str) -> Result<(), InternalError> {
+    if name.is_empty()
+        || name.contains(|c: char| c == '.' || c == '*' || c == '>' || c.is_whitespace())
+    {
+        return Err(InternalError::ConfigurationError {
+            cause: format! {"Invalid {} name [{}]", kind, name},
+        });
+    }
+    Ok(())
+}
+
+/// The confirmation of an event stored by a stream.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+pub struct PubAck {
+    pub stream: String,
+    pub seq: u64,
+    /// Whether the stream already stored an event with the same `Nats-Msg-Id`, whose sequence
+    /// is `seq`.
+    #[serde(default)]
+    pub duplicate: bool,
+}
+
+///
+/// Publishes the event like an `EventMessage`, and resolves with its `PubAck` once stored,
+/// whatever the `AckMode`. Requires `NatsPublisherConfig.jetstream`.
+///
+#[derive(Message, Debug)]
+#[rtype(result = "Result<PubAck, InternalError>")]
+pub struct JetStreamEvent<E: Envelope>(pub EventMessage<E>);
+
+#[derive(Debug, Deserialize)]
+struct ApiError {
+    code: u16,
+    #[serde(default)]
+    err_code: u64,
+    #[serde(default)]
+    description: String,
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({})", self.description, self.code)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum ApiResponse<T> {
+    Error { error: ApiError },
+    Ok(T),
+}
+
+fn parse_response<T: DeserializeOwned>(data: &[u8]) -> Result<Result<T, ApiError>, String> {
+    match serde_json::from_slice(data) {
+        Ok(ApiResponse::Ok(response)) => Ok(Ok(response)),
+        Ok(ApiResponse::Error { error }) => Ok(Err(error)),
+        Err(err) => Err(format! {"Invalid JetStream API response. Err: {}", err}),
+    }
+}
+
+/// Adds the `Nats-Msg-Id` header.
+pub(crate) fn with_msg_id(headers: Option<Headers>, msg_id: &str) -> Headers {
+    let mut headers = headers.unwrap_or_default();
+    headers.inner.insert(
+        MSG_ID_HEADER.to_owned(),
+        [msg_id.to_owned()].into_iter().collect(),
+    );
+    headers
+}
+
+///
+/// Creates the stream, or updates it when it exists. Fails when the server does not accept
+/// the configuration, a change of retention for instance.
+///
+pub(crate) async fn declare_stream(
+    connection: &Connection,
+    stream: &StreamConfig,
+    timeout: Duration,
+) -> Result<(), InternalError> {
+    let config = stream.to_api().to_string();
+    for operation in ["CREATE", "UPDATE"] {
+        let api = format!("STREAM.{}.{}", operation, stream.name);
+        match api_request::<IgnoredAny>(connection, &api, &config, timeout).await? {
+            Ok(_) => {
+                info!("Stream [{}] declared", stream.name);
+                return Ok(());
+            }
+            Err(err) if operation == "CREATE" && err.err_code == STREAM_NAME_IN_USE => {
+                debug!("Stream [{}] exists, updating it", stream.name);
+            }
+            Err(err) => {
+                return Err(InternalError::ConfigurationError {
+                    cause: format! {"Stream [{}] not declared: {}", stream.name, err},
+                })
+            }
+        }
+    }
+    Ok(())
+}
+
+///
+/// Creates the durable consumer `config.durable_name` of `stream`, or checks that the existing
+/// one has the same configuration.
+///
+pub(crate) async fn create_consumer(
+    connection: &Connection,
+    stream: &str,
+    config: serde_json::Value,
+    timeout: Duration,
+) -> Result<(), InternalError> {
+    let durable = config["durable_name"]
+        .as_str()
+        .unwrap_or_default()
+        .to_owned();
+    let api = format!("CONSUMER.DURABLE.CREATE.{}.{}", stream, durable);
+    let request = serde_json::json!({ "stream_name": stream, "config": config }).to_string();
+    match api_request::<IgnoredAny>(connection, &api, &request, timeout).await? {
+        Ok(_) => {
+            info!("Consumer [{}] of stream [{}] created", durable, stream);
+            Ok(())
+        }
+        Err(err) => Err(InternalError::ConfigurationError {
+            cause: format! {"Consumer [{}] of stream [{}] not created: {}", durable, stream, err},
+        }),
+    }
+}
+
+/// Sends a request to `$JS.API.<api>`, and parses the response or the `ApiError`.
+async fn api_request<T: DeserializeOwned>(
+    connection: &Connection,
+    api: &str,
+    body: &str,
+    timeout: Duration,
+) -> Result<Result<T, ApiError>, InternalError> {
+    let subject = format!("{}.{}", API_PREFIX, api);
+    let reply = connection
+        .request_timeout(&subject, body, timeout)
+        .await
+        .map_err(|err| InternalError::NatsOperationError {
+            cause: format! {"JetStream API request to [{}] failed. Err: {}", subject, err},
+        })?;
+    parse_response(&reply.data).map_err(|cause| InternalError::NatsOperationError { cause })
+}
+
+///
+/// Publishes a message with a reply inbox subscribed to beforehand, and waits for the `PubAck`
+/// of the stream storing it.
+///
+pub(crate) async fn publish_acked(
+    connection: &Connection,
+    subject: &str,
+    headers: Option<&Headers>,
+    payload: &[u8],
+    timeout: Duration,
+) -> io::Result<PubAck> {
+    let inbox = connection.new_inbox();
+    let subscription = connection.subscribe(&inbox).await?;
+    let reply = async {
+        connection
+            .publish_with_reply_or_headers(subject, Some(&inbox), headers, payload)
+            .await?;
+        Ok::<_, io::Error>(subscription.next().await)
+    };
+    let reply = time::timeout(timeout, reply).await;
+    if let Err(err) = subscription.unsubscribe().await {
+        warn!("Cannot unsubscribe from PubAck inbox. Err: {}", err);
+    }
+    let reply = match reply {
+        Err(_) => {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format! {"No PubAck for [{}] after {:?}", subject, timeout},
+            ))
+        }
+        Ok(reply) => reply?
+            .ok_or_else(|| io::Error::new(io::ErrorKind::BrokenPipe, "PubAck inbox closed"))?,
+    };
+    match parse_response::<PubAck>(&reply.data) {
+        Ok(Ok(ack)) => Ok(ack),
+        Ok(Err(err)) => Err(io::Error::other(
+            format! {"Message to [{}] not stored: {}", subject, err},
+        )),
+        Err(cause) => Err(io::Error::new(io::ErrorKind::InvalidData, cause)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{declare_stream, publish_acked, with_msg_id, Retention, StreamConfig};
+    use crate::test_support::FakeNatsServer;
+    use crate::InternalError;
+
+    fn stream() -> StreamConfig {
+        StreamConfig {
+            name: "ORDERS".to_owned(),
+            subjects: vec!["orders.>".to_owned()],
+            retention: Retention::WorkQueue,
+            max_age: Some(Duration::from_secs(3600)),
+            replicas: 3,
+            storage: Default::default(),
+            duplicate_window: None,
+        }
+    }
+
+    #[actix_rt::test]
+    async fn should_update_existing_stream() {
+        let server = FakeNatsServer::start();
+        let connection = async_nats::connect(&server.address()).await.unwrap();
+        let api = connection
+            .subscribe("$JS.API.STREAM.*.ORDERS")
+            .await
+            .unwrap();
+        connection.flush().await.unwrap();
+        let responder = actix_rt::spawn(async move {
+            let create = api.next().await.unwrap();
+            create
+                .respond(r#"{"error":{"code":400,"err_code":10058,"description":"stream name already in use"}}"#)
+                .await
+                .unwrap();
+            let update = api.next().await.unwrap();
+            update.respond(r#"{"config":{}}"#).await.unwrap();
+            (create, update)
+        });
+
+        declare_stream(&connection, &stream(), Duration::from_secs(1))
+            .await
+            .unwrap();
+
+        let (create, update) = responder.await.unwrap();
+        assert_eq!("$JS.API.STREAM.CREATE.ORDERS", create.subject);
+        assert_eq!("$JS.API.STREAM.UPDATE.ORDERS", update.subject);
+        let config: serde_json::Value = serde_json::from_slice(&update.data).unwrap();
+        assert_eq!("workqueue", config["retention"]);
+        assert_eq!(3_600_000_000_000u64, config["max_age"]);
+        assert_eq!(3, config["num_replicas"]);
+        assert_eq!("orders.>", config["subjects"][0]);
+    }
+
+    #[actix_rt::test]
+    async fn should_return_pub_ack_or_stream_error() {
+        let server = FakeNatsServer::start();
+        let connection = async_nats::connect(&server.address()).await.unwrap();
+        let subscription = connection.subscribe("orders.>").await.unwrap();
+        connection.flush().await.unwrap();
+        actix_rt::spawn(async move {
+            let stored = subscription.next().await.unwrap();
+            let msg_id = stored.headers.as_ref().unwrap().get("Nats-Msg-Id").cloned();
+            assert_eq!(
+                Some("id-1"),
+                msg_id.unwrap().iter().next().map(String::as_str)
+            );
+            stored
+                .respond(r#"{"stream":"ORDERS","seq":7,"duplicate":true}"#)
+                .await
+                .unwrap();
+            let rejected = subscription.next().await.unwrap();
+            rejected
+                .respond(r#"{"error":{"code":503,"description":"insufficient resources"}}"#)
+                .await
+                .unwrap();
+        });
+        let headers = with_msg_id(None, "id-1");
+
+        let ack = publish_acked(
+            &connection,
+            "orders.new",
+            Some(&headers),
+            b"{}",
+            Duration::from_secs(1),
+        )
+        .await
+        .unwrap();
+        let rejected = publish_acked(
+            &connection,
+            "orders.new",
+            None,
+            b"{}",
+            Duration::from_secs(1),
+        )
+        .await
+        .unwrap_err();
+
+        assert_eq!("ORDERS", ack.stream);
+        assert_eq!(7, ack.seq);
+        assert!(ack.duplicate);
+        assert!(rejected.to_string().contains("insufficient resources"));
+        assert!(stream().validate().is_ok());
+        let invalid = StreamConfig {
+            name: "orders.all".to_owned(),
+            ..stream()
+        };
+        assert!(invalid.validate().is_err());
+    }
+
+    #[test]
+    fn should_validate_stream_subjects() {
+        let with_subject = |subject: &str| StreamConfig {
+            subjects: vec![subject.to_owned()],
+            ..stream()
+        };
+
+        assert!(with_subject("orders.*.created").validate().is_ok());
+        assert!(with_subject("orders.>").validate().is_ok());
+        for subject in [
+            "orders..created",
+            "orders.>.created",
+            "orders.a*b",
+            "orders. ",
+        ] {
+            assert!(matches!(
+                with_subject(subject).validate(),
+                Err(InternalError::ConfigurationError { cause }) if cause.contains(subject)
+            ));
+        }
+    }
+}
@@ -1,15 +1,22 @@
+use std::path::PathBuf;
 use std::time::Duration;
 
 use actix::prelude::Message;
+use actix_web::http::StatusCode;
 use async_nats::{Connection, Options};
 use backoff::ExponentialBackoff;
 use backoff::{backoff::Backoff, future::retry};
 
-use chrono::prelude::Local;
 use derive_more::{Display, Error};
-use serde::{Deserialize, Deserializer, Serialize};
+use serde::{Deserialize, Serialize};
 use tracing::{debug, error, info, warn};
 
+use crate::connection_event::{ConnectionEvent, ConnectionEventListeners};
+#[cfg(feature = "legacy")]
+pub use crate::envelope::legacy::{Event, Map, Number, Payload, Value};
+pub use crate::envelope::{ContentMode, Envelope};
+use crate::metrics::NatsMetrics;
+
 #[derive(Clone, Debug, Display, Error)]
 pub enum InternalError {
     #[display(fmt = "Nats server connection failed: {address}")]
@@ -18,129 +25,391 @@ pub enum InternalError {
     NatsOperationError { cause: String },
     #[display(fmt = "Failed while ser-de Nats message {cause}")]
     SerdeError { cause: String },
+    #[display(fmt = "Invalid configuration: {cause}")]
+    ConfigurationError { cause: String },
+    #[display(fmt = "Nats publish failed after {attempts} attempt(s): {cause}")]
+    PublishError { attempts: usize, cause: String },
+    #[display(fmt = "Outbox operation failed: {cause}")]
+    OutboxError { cause: String },
+    #[display(fmt = "Nats publisher is shutting down")]
+    ShuttingDown,
+    #[display(fmt = "Nats request to {subject} got no reply after {timeout:?}")]
+    RequestTimeout { subject: String, timeout: Duration },
+    #[display(fmt = "Nats responder failed with code {code}: {cause}")]
+    ResponderError { code: u16, cause: String },
+    #[display(fmt = "Nats publish to {subject} over the rate limit")]
+    RateLimited { subject: String },
+    #[display(fmt = "Nats message not verified: {cause}")]
+    Unverified { cause: String },
+    #[display(fmt = "Nats message chunks not reassembled: {cause}")]
+    ReassemblyError { cause: String },
     #[display(fmt = "Error: {}", cause)]
     GenericError { cause: String },
 }
 
-/// An Event is published from a service to other services using NATS.
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Default)]
-pub struct Event {
-    #[serde(default = "default_trace_id")]
-    #[serde(deserialize_with = "deserialize_null_trace_id")]
-    pub trace_id: String,
-    #[serde(rename = "type")]
-    pub event_type: String,
-    pub created_ms: u64,
-    pub payload: Payload,
-}
-
-impl Event {
-    pub fn new<S: Into<String>>(event_type: S) -> Event {
-        Event::new_with_payload(event_type, Map::new())
-    }
-
-    pub fn new_with_payload<S: Into<String>>(event_type: S, payload: Payload) -> Event {
-        let dt = Local::now(); // e.g. `2014-11-28T21:45:59.324310806+09:00`
-        let created_ms = dt.timestamp_millis() as u64;
-        Event {
-            trace_id: default_trace_id(),
-            event_type: event_type.into(),
-            created_ms,
-            payload,
+/// Lets HTTP handlers return an `InternalError`: NATS being unavailable is reported as `503`.
+impl actix_web::ResponseError for InternalError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            InternalError::NatsServerConnectionError { .. }
+            | InternalError::NatsOperationError { .. }
+            | InternalError::PublishError { .. }
+            | InternalError::OutboxError { .. }
+            | InternalError::ShuttingDown => StatusCode::SERVICE_UNAVAILABLE,
+            InternalError::RequestTimeout { .. } => StatusCode::GATEWAY_TIMEOUT,
+            InternalError::ResponderError { .. } => StatusCode::BAD_GATEWAY,
+            InternalError::RateLimited { .. } => StatusCode::TOO_MANY_REQUESTS,
+            InternalError::Unverified { .. } => StatusCode::UNAUTHORIZED,
+            InternalError::SerdeError { .. }
+            | InternalError::ReassemblyError { .. }
+            | InternalError::ConfigurationError { .. }
+            | InternalError::GenericError { .. } => StatusCode::INTERNAL_SERVER_ERROR,
         }
     }
 }
 
-pub type Payload = Map<String, Value>;
-pub type Value = serde_json::Value;
-pub type Map<K, V> = serde_json::Map<K, V>;
-pub type Number = serde_json::Number;
-
-#[inline]
-fn default_trace_id() -> String {
-    uuid::Uuid::new_v4().to_string()
-}
-
-fn deserialize_null_trace_id<'de, D>(deserializer: D) -> Result<String, D::Error>
-where
-    D: Deserializer<'de>,
-{
-    let opt = Option::deserialize(deserializer)?;
-    Ok(opt.unwrap_or_else(default_trace_id))
+#[derive(Message, Debug)]
+#[rtype(result = "Result<(), InternalError>")]
+pub struct EventMessage<E: Envelope> {
+    pub event: E,
+    /// Publishes the event to this subject instead of the subject of the publisher config.
+    pub subject: Option<String>,
 }
 
+///
+/// Publishes the event like an `EventMessage`, and resolves once it is published and flushed
+/// to the server, whatever the `AckMode` of the `NatsPublisher`.
+///
 #[derive(Message, Debug)]
 #[rtype(result = "Result<(), InternalError>")]
-pub struct EventMessage {
-    pub event: Event,
+pub struct AckedEvent<E: Envelope>(pub EventMessage<E>);
+
+///
+/// Events published together by a `NatsPublisher`, to the subjects of its config. The events of
+/// the batches received in a row are coalesced and flushed per `BatchConfig.max_size`.
+///
+#[derive(Message, Debug)]
+#[rtype(result = "Result<BatchReport, InternalError>")]
+pub struct EventBatch<E: Envelope>(pub Vec<E>);
+
+/// The outcome of an `EventBatch`, once all its events are flushed or failed.
+#[derive(Debug, Clone, Default)]
+pub struct BatchReport {
+    /// The number of events published and flushed.
+    pub published: usize,
+    /// The events not published, by index in the batch.
+    pub failed: Vec<(usize, InternalError)>,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
 pub struct NatsClientSettings {
     pub addresses: Vec<String>,
     pub max_reconnects: Option<usize>,
     pub retry_timeout: Option<Duration>,
+    pub auth: Option<NatsAuth>,
+    pub tls: Option<NatsTlsSettings>,
+    #[serde(default)]
+    pub retry_policy: RetryPolicy,
+}
+
+/// Credentials presented to the NATS server when connecting.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum NatsAuth {
+    UserPassword {
+        user: String,
+        password: String,
+    },
+    Token {
+        token: String,
+    },
+    #[serde(rename = "nkey")]
+    NKey {
+        seed: String,
+    },
+    Credentials {
+        path: PathBuf,
+    },
+}
+
+/// TLS settings. All paths point to PEM encoded files.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct NatsTlsSettings {
+    #[serde(default)]
+    pub required: bool,
+    pub client_cert: Option<PathBuf>,
+    pub client_key: Option<PathBuf>,
+    pub ca_bundle: Option<PathBuf>,
 }
 
 ///
 /// Back-off policy for retry.
 ///
-fn backoff(timeout: Option<Duration>) -> ExponentialBackoff {
-    ExponentialBackoff {
-        max_elapsed_time: timeout,
-        ..Default::default()
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct RetryPolicy {
+    pub initial_interval: Duration,
+    pub multiplier: f64,
+    pub max_interval: Duration,
+    /// Randomization factor in `[0, 1]`: each interval is picked in `interval * (1 ± jitter)`.
+    pub jitter: f64,
+    /// Number of attempts after which the operation is given up, retries forever if `None`.
+    pub max_attempts: Option<usize>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            initial_interval: Duration::from_millis(500),
+            multiplier: 1.5,
+            max_interval: Duration::from_secs(60),
+            jitter: 0.5,
+            max_attempts: None,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Creates the back-off for a new operation, given up after `max_elapsed_time` if set.
+    pub fn backoff(&self, max_elapsed_time: Option<Duration>) -> RetryBackoff {
+        RetryBackoff {
+            inner: ExponentialBackoff {
+                current_interval: self.initial_interval,
+                initial_interval: self.initial_interval,
+                randomization_factor: self.jitter,
+                multiplier: self.multiplier,
+                max_interval: self.max_interval,
+                max_elapsed_time,
+                ..Default::default()
+            },
+            attempts: 0,
+            max_attempts: self.max_attempts,
+        }
+    }
+}
+
+/// An `ExponentialBackoff` that also stops after `max_attempts` attempts.
+#[derive(Debug)]
+pub struct RetryBackoff {
+    inner: ExponentialBackoff,
+    attempts: usize,
+    max_attempts: Option<usize>,
+}
+
+impl RetryBackoff {
+    /// The number of failed attempts so far.
+    pub fn attempts(&self) -> usize {
+        self.attempts
     }
 }
 
+impl Backoff for RetryBackoff {
+    fn reset(&mut self) {
+        self.inner.reset();
+        self.attempts = 0;
+    }
+
+    fn next_backoff(&mut self) -> Option<Duration> {
+        self.attempts += 1;
+        match self.max_attempts {
+            Some(max_attempts) if self.attempts >= max_attempts => None,
+            _ => self.inner.next_backoff(),
+        }
+    }
+}
+
+///
+/// Builds the connection options (authentication, TLS, reconnects) for the given settings.
+/// The connection lifecycle is reported to the `listeners`.
+///
+fn client_options(
+    config: &NatsClientSettings,
+    listeners: &ConnectionEventListeners,
+) -> Result<Options, InternalError> {
+    let options = match &config.auth {
+        None => Options::new(),
+        Some(NatsAuth::UserPassword { user, password }) => Options::with_user_pass(user, password),
+        Some(NatsAuth::Token { token }) => Options::with_token(token),
+        Some(NatsAuth::NKey { seed }) => {
+            let key_pair = nkeys::KeyPair::from_seed(seed)
+                .and_then(|key_pair| {
+                    // The nonce is signed in a callback unable to fail the connection: a
+                    // key pair unable to sign must be reported here.
+                    key_pair.sign(b"nonce").map(|_| key_pair)
+                })
+                .map_err(|err| InternalError::ConfigurationError {
+                    cause: format! {"Invalid NKey seed. Err: {}", err},
+                })?;
+            Options::with_nkey(&key_pair.public_key(), move |nonce| {
+                key_pair.sign(nonce).unwrap_or_else(|err| {
+                    error!("Cannot sign NATS nonce with NKey. Err: {}", err);
+                    Vec::new()
+                })
+            })
+        }
+        Some(NatsAuth::Credentials { path }) => Options::with_credentials(path),
+    };
+
+    let options = match &config.tls {
+        None => options,
+        Some(tls) => {
+            let mut options = options.tls_required(tls.required);
+            if let Some(ca_bundle) = &tls.ca_bundle {
+                options = options.add_root_certificate(ca_bundle);
+            }
+            match (&tls.client_cert, &tls.client_key) {
+                (Some(cert), Some(key)) => options.client_cert(cert, key),
+                (None, None) => options,
+                _ => {
+                    return Err(InternalError::ConfigurationError {
+                        cause: "TLS client_cert and client_key must be set together".to_owned(),
+                    })
+                }
+            }
+        }
+    };
+
+    let on_disconnect = listeners.clone();
+    let on_reconnect = listeners.clone();
+    let on_close = listeners.clone();
+    let addresses = config.addresses.join(",");
+
+    Ok(options
+        .disconnect_callback(move || {
+            error!("connection lost");
+            on_disconnect.notify(ConnectionEvent::Disconnected);
+        })
+        .reconnect_callback(move || {
+            info!("connection reestablished");
+            NatsMetrics::global().reconnected(&addresses);
+            on_reconnect.notify(ConnectionEvent::Reconnected);
+        })
+        .close_callback(move || {
+            warn!("connection closed");
+            on_close.notify(ConnectionEvent::Closed);
+        })
+        .max_reconnects(config.max_reconnects))
+}
+
 pub async fn connect_with_retry(config: &NatsClientSettings) -> Result<Connection, InternalError> {
+    connect_with_retry_and_listeners(config, &ConnectionEventListeners::default()).await
+}
+
+///
+/// Same as `connect_with_retry`, reporting the lifecycle of the connection to `listeners`.
+///
+pub async fn connect_with_retry_and_listeners(
+    config: &NatsClientSettings,
+    listeners: &ConnectionEventListeners,
+) -> Result<Connection, InternalError> {
     info!("Connecting to NATS...");
     let addresses = config.addresses.join(",");
 
     let connect_op = || async {
-        let options = Options::new()
-            .disconnect_callback(|| error!("connection lost"))
-            .max_reconnects(config.max_reconnects);
+        let options = client_options(config, listeners).map_err(backoff::Error::Permanent)?;
 
-        Ok(options.connect(&addresses).await?)
+        options.connect(&addresses).await.map_err(|err| {
+            warn!("Cannot connect to NATS. Err: {}", err);
+            backoff::Error::transient(InternalError::NatsServerConnectionError {
+                address: addresses.clone(),
+            })
+        })
     };
 
-    retry(backoff(config.retry_timeout), connect_op)
-        .await
-        .map_err(|_| InternalError::NatsServerConnectionError { address: addresses })
+    retry(
+        config.retry_policy.backoff(config.retry_timeout),
+        connect_op,
+    )
+    .await
+}
+
+///
+/// Flushes `connection`, failing after `timeout`. The timeout is enforced on our side as the
+/// client does not always honour it while reconnecting.
+///
+pub(crate) async fn flush(connection: &Connection, timeout: Duration) -> Result<(), InternalError> {
+    match tokio::time::timeout(timeout, connection.flush_timeout(timeout)).await {
+        Ok(result) => result.map_err(|err| InternalError::NatsOperationError {
+            cause: format! {"Flush failed. Err: {}", err},
+        }),
+        Err(_) => Err(InternalError::NatsOperationError {
+            cause: format! {"Flush timed out after {:?}", timeout},
+        }),
+    }
 }
 
 pub async fn connect(config: &NatsClientSettings) -> Result<Connection, InternalError> {
+    connect_with_listeners(config, &ConnectionEventListeners::default()).await
+}
+
+///
+/// Same as `connect`, reporting the lifecycle of the connection to `listeners`.
+///
+pub async fn connect_with_listeners(
+    config: &NatsClientSettings,
+    listeners: &ConnectionEventListeners,
+) -> Result<Connection, InternalError> {
     info!("Connecting to NATS...");
     let addresses = config.addresses.join(",");
 
-    let options = Options::new()
-        .disconnect_callback(|| error!("connection lost"))
-        .max_reconnects(config.max_reconnects);
-
-    options
+    client_options(config, listeners)?
         .connect(&addresses)
         .await
         .map_err(|_| InternalError::NatsServerConnectionError { address: addresses })
 }
 
+pub mod chunking;
+pub mod compression;
+pub mod config_loader;
+pub mod connection_event;
+pub mod consumer;
+pub mod dead_letter;
+pub mod envelope;
+#[cfg(feature = "cloudevents")]
+pub mod event_stream_handler;
+pub mod health;
+pub mod jetstream;
+pub mod metrics;
+#[cfg(feature = "cloudevents")]
+pub mod model;
+pub mod outbox;
 pub mod publisher;
+pub mod rate_limit;
+pub mod registry;
+pub mod relay;
+pub mod requester;
+pub mod shutdown;
+pub mod signing;
+pub mod subject;
 pub mod subscriber;
+#[cfg(any(test, feature = "test-support"))]
+pub mod test_support;
 
 #[cfg(test)]
 mod tests {
     use serial_test::serial;
+    use std::process::{Child, Command};
     use std::time::Duration;
+    use tempfile::TempDir;
 
+    #[cfg(feature = "legacy")]
+    use crate::Event;
     use crate::{
+        connect, connect_with_listeners,
+        connection_event::{ConnectionEvent, ConnectionEventListeners},
         publisher::{NatsPublisher, NatsPublisherConfig},
         subscriber::{subscribe, NatsSubscriberConfig},
-        Event, EventMessage, NatsClientSettings,
+        test_support::FakeNatsServer,
+        EventMessage, InternalError, NatsAuth, NatsClientSettings, NatsTlsSettings, RetryPolicy,
     };
+    use backoff::backoff::Backoff;
 
+    #[cfg(feature = "legacy")]
     #[actix_rt::test]
     #[serial]
     async fn should_publish_to_nats() {
-        let nats_address = format!("127.0.0.1:{}", 4222);
+        let server = FakeNatsServer::start();
+        let nats_address = server.address();
 
         let event = Event::new(format!("event_type_{}", 12345));
         let subject = format!("test_subject_{}", 12345);
@@ -153,9 +422,281 @@ mod tests {
                     addresses: vec![nats_address.to_owned()],
                     max_reconnects: Some(5),
                     retry_timeout: Some(Duration::from_secs(30)),
+                    ..Default::default()
+                },
+                subject: subject.to_owned(),
+                mailbox_size: 100,
+                verification: None,
+                reassembly: Default::default(),
+            },
+            move |event| {
+                sender.send(event).unwrap();
+                Ok(())
+            },
+        )
+        .await
+        .unwrap();
+
+        let publisher = NatsPublisher::start_new(NatsPublisherConfig {
+            client_settings: NatsClientSettings {
+                addresses: vec![nats_address.to_owned()],
+                max_reconnects: Some(5),
+                retry_timeout: Some(Duration::from_secs(30)),
+                ..Default::default()
+            },
+            subject: subject.to_owned(),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+        publisher.do_send(EventMessage {
+            event: event.clone(),
+            subject: None,
+        });
+
+        assert_eq!(
+            event,
+            receiver.recv().await.unwrap().event::<Event>().unwrap()
+        );
+    }
+
+    #[cfg(feature = "legacy")]
+    #[actix_rt::test]
+    #[serial]
+    async fn should_decompress_compressed_events() {
+        use crate::compression::{Codec, CompressionConfig, CONTENT_ENCODING_HEADER};
+
+        let server = FakeNatsServer::start();
+        let raw = async_nats::connect(&server.address()).await.unwrap();
+        let raw_subscription = raw.subscribe("compressed").await.unwrap();
+        raw.flush().await.unwrap();
+        let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+        subscribe(
+            NatsSubscriberConfig {
+                client_settings: server.client_settings(),
+                subject: "compressed".to_owned(),
+                mailbox_size: 100,
+                verification: None,
+                reassembly: Default::default(),
+            },
+            move |msg| {
+                sender.send(msg).unwrap();
+                Ok(())
+            },
+        )
+        .await
+        .unwrap();
+        let publisher = NatsPublisher::start_new(NatsPublisherConfig {
+            client_settings: server.client_settings(),
+            subject: "compressed".to_owned(),
+            compression: Some(CompressionConfig {
+                codec: Codec::Gzip,
+                threshold: 256,
+                level: None,
+            }),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+        let mut event = Event::new("large");
+        event
+            .payload
+            .insert("data".to_owned(), "compressible ".repeat(1000).into());
+
+        publisher.do_send(EventMessage {
+            event: event.clone(),
+            subject: None,
+        });
+
+        let compressed = raw_subscription.next().await.unwrap();
+        assert!(compressed
+            .headers
+            .unwrap()
+            .contains_key(CONTENT_ENCODING_HEADER));
+        let received = receiver.recv().await.unwrap();
+        assert!(compressed.data.len() < received.msg.data.len() / 10);
+        assert!(received.headers().is_none());
+        assert_eq!(event, received.event::<Event>().unwrap());
+    }
+
+    #[cfg(feature = "legacy")]
+    #[actix_rt::test]
+    #[serial]
+    async fn should_reassemble_chunked_events() {
+        use crate::chunking::ChunkingConfig;
+        use crate::Value;
+
+        let server = FakeNatsServer::start();
+        let raw = async_nats::connect(&server.address()).await.unwrap();
+        let raw_subscription = raw.subscribe("chunked").await.unwrap();
+        raw.flush().await.unwrap();
+        let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+        subscribe(
+            NatsSubscriberConfig {
+                client_settings: server.client_settings(),
+                subject: "chunked".to_owned(),
+                mailbox_size: 100,
+                verification: None,
+                reassembly: Default::default(),
+            },
+            move |msg| {
+                sender.send(msg).unwrap();
+                Ok(())
+            },
+        )
+        .await
+        .unwrap();
+        let publisher = NatsPublisher::start_new(NatsPublisherConfig {
+            client_settings: server.client_settings(),
+            subject: "chunked".to_owned(),
+            chunking: Some(ChunkingConfig { max_payload: 4096 }),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+        let mut event = Event::new("large");
+        let data: Vec<Value> = (0..2000).map(|i| format!("item {}", i).into()).collect();
+        event.payload.insert("data".to_owned(), data.into());
+
+        publisher.do_send(EventMessage {
+            event: event.clone(),
+            subject: None,
+        });
+
+        let received = receiver.recv().await.unwrap();
+        assert_eq!(event, received.event::<Event>().unwrap());
+        assert!(received.headers().is_none());
+        let size = received.msg.data.len();
+        let mut chunked_size = 0;
+        while chunked_size < size {
+            let chunk = raw_subscription.next().await.unwrap();
+            assert!(chunk.data.len() < 4096);
+            chunked_size += chunk.data.len();
+        }
+        assert_eq!(size, chunked_size);
+    }
+
+    #[cfg(feature = "legacy")]
+    #[actix_rt::test]
+    #[serial]
+    async fn should_reject_unverified_messages() {
+        use crate::dead_letter::{DeadLetterCallback, DeadLetterSink};
+        use crate::envelope::Envelope;
+        use crate::signing::{
+            Algorithm, KeyRingUpdate, RotateSigningKey, SigningKey, VerificationConfig,
+        };
+
+        let key = |id: &str, seed: u8| SigningKey {
+            id: id.to_owned(),
+            algorithm: Algorithm::Ed25519,
+            secret: base64::encode([seed; 32]),
+        };
+        let server = FakeNatsServer::start();
+        let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+        let (rejected_sender, mut rejected) = tokio::sync::mpsc::unbounded_channel();
+        let subscriber = subscribe(
+            NatsSubscriberConfig {
+                client_settings: server.client_settings(),
+                subject: "signed".to_owned(),
+                mailbox_size: 100,
+                verification: Some(VerificationConfig {
+                    keys: vec![key("key-1", 1).verifying_key().unwrap()],
+                    rejected: Some(DeadLetterSink::Callback(DeadLetterCallback::new(
+                        move |letter| {
+                            rejected_sender.send(letter).unwrap();
+                            Ok(())
+                        },
+                    ))),
+                }),
+                reassembly: Default::default(),
+            },
+            move |msg| {
+                sender.send(msg.event::<Event>().unwrap()).unwrap();
+                Ok(())
+            },
+        )
+        .await
+        .unwrap();
+        let publisher = NatsPublisher::start_new(NatsPublisherConfig {
+            client_settings: server.client_settings(),
+            subject: "signed".to_owned(),
+            signing: Some(key("key-1", 1)),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+        let publish = |event_type: &str| {
+            publisher.do_send(EventMessage {
+                event: Event::new(event_type),
+                subject: None,
+            })
+        };
+
+        publish("signed");
+        assert_eq!("signed", receiver.recv().await.unwrap().event_type);
+        let forger = async_nats::connect(&server.address()).await.unwrap();
+        forger
+            .publish("signed", Event::new("forged").encode().unwrap())
+            .await
+            .unwrap();
+        let letter = rejected.recv().await.unwrap();
+        assert_eq!("forged", letter.event["type"]);
+        assert!(letter.last_error.contains("not signed"));
+
+        publisher
+            .send(RotateSigningKey(key("key-2", 2)))
+            .await
+            .unwrap()
+            .unwrap();
+        publish("too early");
+        let letter = rejected.recv().await.unwrap();
+        assert!(letter.last_error.contains("Unknown signing key [key-2]"));
+        subscriber
+            .send(KeyRingUpdate::Add(key("key-2", 2).verifying_key().unwrap()))
+            .await
+            .unwrap()
+            .unwrap();
+        publish("rotated");
+        assert_eq!("rotated", receiver.recv().await.unwrap().event_type);
+    }
+
+    #[cfg(feature = "cloudevents")]
+    #[actix_rt::test]
+    #[serial]
+    async fn should_publish_cloud_event_to_nats() {
+        use cloudevents::{EventBuilder, EventBuilderV10};
+
+        let server = FakeNatsServer::start();
+        let nats_address = server.address();
+
+        let uuid = uuid::Uuid::new_v4();
+        let payload = serde_json::json!({"user": "Ram", "loc": "India"});
+
+        let event = EventBuilderV10::new()
+            .source("http://localhost")
+            .id(uuid.to_hyphenated().to_string())
+            .subject("greetings")
+            .ty("com.example.hello")
+            .data("application/json", payload)
+            .build()
+            .unwrap();
+
+        let subject = format!("test_subject_{}", 12345);
+
+        let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+
+        subscribe(
+            NatsSubscriberConfig {
+                client_settings: NatsClientSettings {
+                    addresses: vec![nats_address.to_owned()],
+                    max_reconnects: Some(5),
+                    retry_timeout: Some(Duration::from_secs(30)),
+                    ..Default::default()
                 },
                 subject: subject.to_owned(),
                 mailbox_size: 100,
+                verification: None,
+                reassembly: Default::default(),
             },
             move |event| {
                 sender.send(event).unwrap();
@@ -170,19 +711,197 @@ mod tests {
                 addresses: vec![nats_address.to_owned()],
                 max_reconnects: Some(5),
                 retry_timeout: Some(Duration::from_secs(30)),
+                ..Default::default()
             },
             subject: subject.to_owned(),
-            mailbox_size: 100,
+            ..Default::default()
         })
         .await
         .unwrap();
         publisher.do_send(EventMessage {
             event: event.clone(),
+            subject: None,
         });
 
         assert_eq!(
             event,
-            serde_json::from_slice(&receiver.recv().await.unwrap().msg.data).unwrap()
+            receiver
+                .recv()
+                .await
+                .unwrap()
+                .event::<cloudevents::Event>()
+                .unwrap()
+        );
+    }
+
+    #[actix_rt::test]
+    async fn should_notify_disconnection_and_reconnection() {
+        let server = FakeNatsServer::start();
+        let listeners = ConnectionEventListeners::default();
+        let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+        listeners.register_callback(move |event| {
+            let _ = sender.send(event.clone());
+        });
+
+        let connection = connect_with_listeners(&server.client_settings(), &listeners)
+            .await
+            .unwrap();
+        server.kill_connections();
+
+        assert_eq!(Some(ConnectionEvent::Disconnected), receiver.recv().await);
+        assert_eq!(Some(ConnectionEvent::Reconnected), receiver.recv().await);
+        connection.flush().await.unwrap();
+        assert_eq!(1, server.connections());
+    }
+
+    #[actix_rt::test]
+    async fn should_fail_to_connect_with_an_invalid_nkey_seed() {
+        let server = FakeNatsServer::start();
+        let settings = NatsClientSettings {
+            auth: Some(NatsAuth::NKey {
+                seed: "SUAINVALIDSEED".to_owned(),
+            }),
+            ..server.client_settings()
+        };
+
+        match connect(&settings).await {
+            Err(InternalError::ConfigurationError { .. }) => {}
+            other => panic!("Unexpected result: {:?}", other.map(|_| ())),
+        }
+        assert_eq!(0, server.connections());
+    }
+
+    #[test]
+    fn should_stop_retrying_after_max_attempts() {
+        let mut backoff = RetryPolicy {
+            initial_interval: Duration::from_millis(100),
+            multiplier: 2.0,
+            max_interval: Duration::from_millis(300),
+            jitter: 0.0,
+            max_attempts: Some(4),
+        }
+        .backoff(None);
+
+        assert_eq!(Some(Duration::from_millis(100)), backoff.next_backoff());
+        assert_eq!(Some(Duration::from_millis(200)), backoff.next_backoff());
+        assert_eq!(Some(Duration::from_millis(300)), backoff.next_backoff());
+        assert_eq!(None, backoff.next_backoff());
+        assert_eq!(4, backoff.attempts());
+
+        backoff.reset();
+        assert_eq!(Some(Duration::from_millis(100)), backoff.next_backoff());
+    }
+
+    /// A `nats-server` child process requiring TLS and user/password authentication.
+    struct SecuredNatsServer {
+        process: Child,
+        port: u16,
+        dir: TempDir,
+    }
+
+    impl SecuredNatsServer {
+        fn start() -> SecuredNatsServer {
+            let dir = tempfile::tempdir().unwrap();
+            let port = port_check::free_local_port().unwrap();
+
+            let mut ca_params = rcgen::CertificateParams::new(vec![]);
+            ca_params.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+            let ca = rcgen::Certificate::from_params(ca_params).unwrap();
+            let server = rcgen::Certificate::from_params(rcgen::CertificateParams::new(vec![
+                "localhost".to_owned(),
+            ]))
+            .unwrap();
+
+            std::fs::write(dir.path().join("ca.pem"), ca.serialize_pem().unwrap()).unwrap();
+            std::fs::write(
+                dir.path().join("server.pem"),
+                server.serialize_pem_with_signer(&ca).unwrap(),
+            )
+            .unwrap();
+            std::fs::write(
+                dir.path().join("server-key.pem"),
+                server.serialize_private_key_pem(),
+            )
+            .unwrap();
+            std::fs::write(
+                dir.path().join("nats.conf"),
+                format!(
+                    r#"
+                    port: {port}
+                    tls {{
+                        cert_file: "{dir}/server.pem"
+                        key_file: "{dir}/server-key.pem"
+                    }}
+                    authorization {{
+                        user: "test_user"
+                        password: "test_password"
+                    }}
+                    "#,
+                    port = port,
+                    dir = dir.path().display()
+                ),
+            )
+            .unwrap();
+
+            let process = Command::new("nats-server")
+                .arg("-c")
+                .arg(dir.path().join("nats.conf"))
+                .spawn()
+                .expect("nats-server must be available on PATH");
+
+            while !port_check::is_port_reachable(("127.0.0.1", port)) {
+                std::thread::sleep(Duration::from_millis(50));
+            }
+
+            SecuredNatsServer { process, port, dir }
+        }
+
+        fn client_settings(&self, auth: Option<NatsAuth>) -> NatsClientSettings {
+            NatsClientSettings {
+                addresses: vec![format!("tls://localhost:{}", self.port)],
+                max_reconnects: Some(0),
+                auth,
+                tls: Some(NatsTlsSettings {
+                    required: true,
+                    ca_bundle: Some(self.dir.path().join("ca.pem")),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            }
+        }
+    }
+
+    impl Drop for SecuredNatsServer {
+        fn drop(&mut self) {
+            let _ = self.process.kill();
+        }
+    }
+
+    #[actix_rt::test]
+    #[serial]
+    #[ignore = "requires nats-server on PATH"]
+    async fn should_connect_with_tls_and_credentials() {
+        let server = SecuredNatsServer::start();
+
+        let client = connect(&server.client_settings(Some(NatsAuth::UserPassword {
+            user: "test_user".to_owned(),
+            password: "test_password".to_owned(),
+        })))
+        .await
+        .unwrap();
+
+        let subscription = client.subscribe("secured_subject").await.unwrap();
+        client.publish("secured_subject", "hello").await.unwrap();
+        assert_eq!(b"hello".to_vec(), subscription.next().await.unwrap().data);
+
+        assert!(connect(&server.client_settings(None)).await.is_err());
+        assert!(
+            connect(&server.client_settings(Some(NatsAuth::UserPassword {
+                user: "test_user".to_owned(),
+                password: "wrong_password".to_owned(),
+            })))
+            .await
+            .is_err()
         );
     }
 }
@@ -0,0 +1,285 @@
+use actix_web::{get, HttpResponse};
+use once_cell::sync::Lazy;
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge,
+    IntGaugeVec, Opts, Registry, TextEncoder,
+};
+
+use crate::dead_letter::now_ms;
+use crate::outbox::OutboxStats;
+use crate::rate_limit::Overflow;
+
+/// Buckets of the latencies and durations, in seconds.
+const DURATION_BUCKETS: &[f64] = &[
+    0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0,
+];
+
+static METRICS: Lazy<NatsMetrics> = Lazy::new(NatsMetrics::new);
+
+///
+/// The metrics of the publishers, the subscribers and the connections.
+/// They are labelled with the `subject` configured for the actor, which may be a subject
+/// template or contain wildcards, to keep their cardinality bounded.
+///
+pub struct NatsMetrics {
+    registry: Registry,
+    published: IntCounterVec,
+    failed: IntCounterVec,
+    retried: IntCounterVec,
+    throttled: IntCounterVec,
+    publish_latency: HistogramVec,
+    /// The mailbox depth of the publishers. Actix does not expose the length of a mailbox, so
+    /// an event is counted from its handler on: while queued by the rate limits, buffered in a
+    /// batch or retried, until confirmed or given up on.
+    in_flight: IntGaugeVec,
+    outbox_depth: IntGaugeVec,
+    outbox_bytes: IntGaugeVec,
+    received: IntCounterVec,
+    callback_errors: IntCounterVec,
+    rejected: IntCounterVec,
+    callback_duration: HistogramVec,
+    mailbox_depth: IntGaugeVec,
+    reconnects: IntCounterVec,
+}
+
+impl NatsMetrics {
+    fn new() -> NatsMetrics {
+        let registry = Registry::new();
+        let counter = |name: &str, help: &str, labels: &[&str]| {
+            let counter = IntCounterVec::new(Opts::new(name, help), labels).expect("valid metric");
+            registry
+                .register(Box::new(counter.clone()))
+                .expect("metric registered once");
+            counter
+        };
+        let gauge = |name: &str, help: &str| {
+            let gauge =
+                IntGaugeVec::new(Opts::new(name, help), &["subject"]).expect("valid metric");
+            registry
+                .register(Box::new(gauge.clone()))
+                .expect("metric registered once");
+            gauge
+        };
+        let histogram = |name: &str, help: &str| {
+            let opts = HistogramOpts::new(name, help).buckets(DURATION_BUCKETS.to_vec());
+            let histogram = HistogramVec::new(opts, &["subject"]).expect("valid metric");
+            registry
+                .register(Box::new(histogram.clone()))
+                .expect("metric registered once");
+            histogram
+        };
+
+        NatsMetrics {
+            published: counter(
+                "nats_publisher_published_total",
+                "Events published and, when acknowledged, flushed",
+                &["subject"],
+            ),
+            failed: counter(
+                "nats_publisher_failed_total",
+                "Events given up on",
+                &["subject"],
+            ),
+            retried: counter(
+                "nats_publisher_retried_total",
+                "Publish attempts retried",
+                &["subject"],
+            ),
+            throttled: counter(
+                "nats_publisher_throttled_total",
+                "Events over the rate limits, by overflow strategy",
+                &["subject", "overflow"],
+            ),
+            publish_latency: histogram(
+                "nats_publisher_latency_seconds",
+                "Time from the first publish attempt to the confirmation of the event",
+            ),
+            in_flight: gauge(
+                "nats_publisher_in_flight_events",
+                "Mailbox depth of the publisher: events handled and not confirmed or given up on yet",
+            ),
+            outbox_depth: gauge(
+                "nats_publisher_outbox_events",
+                "Events written to the outbox and not confirmed as published yet",
+            ),
+            outbox_bytes: gauge(
+                "nats_publisher_outbox_bytes",
+                "Size of the events written to the outbox and not confirmed as published yet",
+            ),
+            received: counter(
+                "nats_subscriber_received_total",
+                "Messages received",
+                &["subject"],
+            ),
+            callback_errors: counter(
+                "nats_subscriber_callback_errors_total",
+                "Messages the callback failed to process",
+                &["subject"],
+            ),
+            rejected: counter(
+                "nats_subscriber_rejected_total",
+                "Messages not delivered to the callback because their signature is not verified",
+                &["subject"],
+            ),
+            callback_duration: histogram(
+                "nats_subscriber_callback_duration_seconds",
+                "Time spent in the callback per message",
+            ),
+            mailbox_depth: gauge(
+                "nats_subscriber_mailbox_depth",
+                "Messages received and waiting for the callback",
+            ),
+            reconnects: counter(
+                "nats_reconnects_total",
+                "Connections re-established, by server addresses",
+                &["addresses"],
+            ),
+            registry,
+        }
+    }
+
+    pub fn global() -> &'static NatsMetrics {
+        &METRICS
+    }
+
+    /// The registry of the metrics, which may also hold the metrics of the service.
+    pub fn registry(&self) -> &Registry {
+        &self.registry
+    }
+
+    /// The metrics in the Prometheus text format.
+    pub fn encode(&self) -> String {
+        let mut buffer = vec![];
+        if let Err(err) = TextEncoder::new().encode(&self.registry.gather(), &mut buffer) {
+            log::error!("Cannot encode the metrics. Err: {}", err);
+        }
+        String::from_utf8_lossy(&buffer).into_owned()
+    }
+
+    pub(crate) fn publisher(&self, subject: &str) -> PublisherMetrics {
+        let throttled = |overflow: &str| self.throttled.with_label_values(&[subject, overflow]);
+        PublisherMetrics {
+            published: self.published.with_label_values(&[subject]),
+            failed: self.failed.with_label_values(&[subject]),
+            retried: self.retried.with_label_values(&[subject]),
+            queued: throttled("queue"),
+            shed: throttled("shed"),
+            rejected: throttled("reject"),
+            latency: self.publish_latency.with_label_values(&[subject]),
+            in_flight: self.in_flight.with_label_values(&[subject]),
+            outbox: OutboxMetrics {
+                events: self.outbox_depth.with_label_values(&[subject]),
+                bytes: self.outbox_bytes.with_label_values(&[subject]),
+            },
+        }
+    }
+
+    pub(crate) fn subscriber(&self, subject: &str) -> SubscriberMetrics {
+        SubscriberMetrics {
+            received: self.received.with_label_values(&[subject]),
+            errors: self.callback_errors.with_label_values(&[subject]),
+            rejected: self.rejected.with_label_values(&[subject]),
+            duration: self.callback_duration.with_label_values(&[subject]),
+            mailbox_depth: self.mailbox_depth.with_label_values(&[subject]),
+        }
+    }
+
+    pub(crate) fn reconnected(&self, addresses: &str) {
+        self.reconnects.with_label_values(&[addresses]).inc();
+    }
+}
+
+/// The metrics of a `NatsPublisher`.
+#[derive(Clone)]
+pub(crate) struct PublisherMetrics {
+    published: IntCounter,
+    failed: IntCounter,
+    retried: IntCounter,
+    queued: IntCounter,
+    shed: IntCounter,
+    rejected: IntCounter,
+    latency: Histogram,
+    pub(crate) in_flight: IntGauge,
+    pub(crate) outbox: OutboxMetrics,
+}
+
+impl PublisherMetrics {
+    pub(crate) fn published(&self, first_attempt_ms: u64) {
+        self.published.inc();
+        let latency_ms = now_ms().saturating_sub(first_attempt_ms);
+        self.latency.observe(latency_ms as f64 / 1000.0);
+    }
+
+    pub(crate) fn failed(&self) {
+        self.failed.inc();
+    }
+
+    pub(crate) fn retried(&self) {
+        self.retried.inc();
+    }
+
+    pub(crate) fn throttled(&self, overflow: Overflow) {
+        match overflow {
+            Overflow::Queue => self.queued.inc(),
+            Overflow::Shed => self.shed.inc(),
+            Overflow::Reject => self.rejected.inc(),
+        }
+    }
+}
+
+/// The depth of the `Outbox` of a `NatsPublisher`.
+#[derive(Clone)]
+pub(crate) struct OutboxMetrics {
+    events: IntGauge,
+    bytes: IntGauge,
+}
+
+impl OutboxMetrics {
+    pub(crate) fn set(&self, stats: &OutboxStats) {
+        self.events.set(stats.entries as i64);
+        self.bytes.set(stats.bytes as i64);
+    }
+}
+
+/// The metrics of a `NatsSubscriber`.
+#[derive(Clone)]
+pub(crate) struct SubscriberMetrics {
+    pub(crate) received: IntCounter,
+    pub(crate) errors: IntCounter,
+    pub(crate) rejected: IntCounter,
+    pub(crate) duration: Histogram,
+    pub(crate) mailbox_depth: IntGauge,
+}
+
+///
+/// Exposes the `NatsMetrics` to Prometheus: `App::new().service(metrics)`.
+///
+#[get("/metrics")]
+pub async fn metrics() -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type(prometheus::TEXT_FORMAT)
+        .body(NatsMetrics::global().encode())
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{test, App};
+
+    use super::{metrics, NatsMetrics};
+
+    #[actix_rt::test]
+    async fn should_expose_metrics() {
+        let publisher = NatsMetrics::global().publisher("metrics.test");
+        publisher.published(0);
+        publisher.failed();
+        let app = test::init_service(App::new().service(metrics)).await;
+
+        let request = test::TestRequest::get().uri("/metrics").to_request();
+        let body = test::call_and_read_body(&app, request).await;
+
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        assert!(body.contains(r#"nats_publisher_published_total{subject="metrics.test"} 1"#));
+        assert!(body.contains(r#"nats_publisher_failed_total{subject="metrics.test"} 1"#));
+        assert!(body.contains("nats_publisher_latency_seconds_bucket"));
+    }
+}
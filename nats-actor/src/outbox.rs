@@ -0,0 +1,603 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use log::*;
+use serde::{Deserialize, Serialize};
+
+use crate::metrics::OutboxMetrics;
+use crate::InternalError;
+
+const SEGMENT_EXTENSION: &str = "seg";
+const ACK_EXTENSION: &str = "ack";
+/// Record length, id, creation time and subject length.
+const HEADER_SIZE: usize = 4 + 8 + 8 + 2;
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct OutboxConfig {
+    /// The directory of the segment files, created if missing. It must not be shared with
+    /// another publisher.
+    pub directory: PathBuf,
+    /// The size in bytes from which a new segment file is started.
+    #[serde(default = "default_segment_size")]
+    pub segment_size: u64,
+    /// The maximum size in bytes of the pending events. New events are refused beyond.
+    #[serde(default)]
+    pub max_size: Option<u64>,
+    /// The events pending for longer are sent to the dead-letter sink instead of being replayed.
+    #[serde(default)]
+    pub max_age: Option<Duration>,
+}
+
+fn default_segment_size() -> u64 {
+    16 * 1024 * 1024
+}
+
+/// An event written to the outbox and not confirmed as published yet.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OutboxEntry {
+    pub id: u64,
+    pub created_ms: u64,
+    pub subject: String,
+    pub payload: Vec<u8>,
+}
+
+/// The depth of an outbox.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OutboxStats {
+    pub entries: usize,
+    pub bytes: u64,
+    pub oldest_created_ms: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Record {
+    offset: u64,
+    len: u64,
+    created_ms: u64,
+}
+
+struct Segment {
+    first_id: u64,
+    size: u64,
+    pending: BTreeMap<u64, Record>,
+}
+
+///
+/// An append-only log of the events being published, split in segment files.
+/// An event is appended before being published and acknowledged once the server confirmed it.
+/// The acknowledged ids are appended to a file next to their segment, and a segment is deleted
+/// once all its events are acknowledged. The pending events are replayed after a restart.
+///
+pub struct Outbox {
+    config: OutboxConfig,
+    /// Sorted by first id, the last one is the segment appended to.
+    segments: Vec<Segment>,
+    active: Option<File>,
+    next_id: u64,
+    /// The pending events currently being published by this process.
+    held: HashSet<u64>,
+    bytes: u64,
+    metrics: Option<OutboxMetrics>,
+}
+
+impl Outbox {
+    /// Opens the outbox in `config.directory`, loading the events left pending by a previous run.
+    pub fn open(config: OutboxConfig) -> Result<Outbox, InternalError> {
+        fs::create_dir_all(&config.directory).map_err(io_error(&config.directory))?;
+
+        let mut first_ids = vec![];
+        for entry in fs::read_dir(&config.directory).map_err(io_error(&config.directory))? {
+            let path = entry.map_err(io_error(&config.directory))?.path();
+            if path.extension().and_then(|ext| ext.to_str()) != Some(SEGMENT_EXTENSION) {
+                continue;
+            }
+            match path
+                .file_stem()
+                .and_then(|stem| stem.to_str()?.parse::<u64>().ok())
+            {
+                Some(first_id) => first_ids.push(first_id),
+                None => warn!("Ignoring unexpected outbox file {:?}", path),
+            }
+        }
+        first_ids.sort_unstable();
+
+        let mut outbox = Outbox {
+            config,
+            segments: vec![],
+            active: None,
+            next_id: 0,
+            held: HashSet::new(),
+            bytes: 0,
+            metrics: None,
+        };
+        let last = first_ids.last().copied();
+        for first_id in first_ids {
+            let (segment, next_id) = outbox.load_segment(first_id)?;
+            outbox.next_id = outbox.next_id.max(next_id);
+            if segment.pending.is_empty() && Some(first_id) != last {
+                outbox.remove_segment_files(first_id)?;
+                continue;
+            }
+            outbox.bytes += segment
+                .pending
+                .values()
+                .map(|record| record.len)
+                .sum::<u64>();
+            outbox.segments.push(segment);
+        }
+        if let Some(segment) = outbox.segments.last() {
+            let path = outbox.segment_path(segment.first_id, SEGMENT_EXTENSION);
+            let file = OpenOptions::new()
+                .append(true)
+                .open(&path)
+                .map_err(io_error(&path))?;
+            outbox.active = Some(file);
+        }
+        info!(
+            "Outbox opened in {:?} with {} pending event(s)",
+            outbox.config.directory,
+            outbox.stats().entries
+        );
+        Ok(outbox)
+    }
+
+    /// Reports the depth of the outbox, starting with the events loaded by `open`.
+    pub(crate) fn with_metrics(mut self, metrics: OutboxMetrics) -> Outbox {
+        metrics.set(&self.stats());
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// Writes an event, held by the caller until acknowledged or released. Returns its id.
+    pub fn append(
+        &mut self,
+        subject: &str,
+        payload: &[u8],
+        created_ms: u64,
+    ) -> Result<u64, InternalError> {
+        let id = self.next_id;
+        let record = encode_record(id, created_ms, subject, payload)?;
+        let len = record.len() as u64;
+        if let Some(max_size) = self.config.max_size {
+            if self.bytes + len > max_size {
+                return Err(InternalError::OutboxError {
+                    cause: format! {"Outbox full with {} bytes pending", self.bytes},
+                });
+            }
+        }
+        if self.active.is_none()
+            || self
+                .segments
+                .last()
+                .is_none_or(|segment| segment.size >= self.config.segment_size)
+        {
+            self.roll(id)?;
+        }
+
+        let segment = self.segments.last_mut().expect("Active segment");
+        let file = self.active.as_mut().expect("Active segment file");
+        let path = &self.config.directory;
+        file.write_all(&record).map_err(io_error(path))?;
+        segment.pending.insert(
+            id,
+            Record {
+                offset: segment.size,
+                len,
+                created_ms,
+            },
+        );
+        segment.size += len;
+        self.bytes += len;
+        self.next_id += 1;
+        self.held.insert(id);
+        self.report();
+        Ok(id)
+    }
+
+    /// Removes a published event from the outbox.
+    pub fn ack(&mut self, id: u64) -> Result<(), InternalError> {
+        self.held.remove(&id);
+        let index = match self.segment_index(id) {
+            Some(index) => index,
+            None => return Ok(()),
+        };
+        let record = match self.segments[index].pending.remove(&id) {
+            Some(record) => record,
+            None => return Ok(()),
+        };
+        self.bytes -= record.len;
+        self.report();
+
+        let first_id = self.segments[index].first_id;
+        if self.segments[index].pending.is_empty() && index + 1 < self.segments.len() {
+            self.segments.remove(index);
+            return self.remove_segment_files(first_id);
+        }
+        let path = self.segment_path(first_id, ACK_EXTENSION);
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .and_then(|mut file| file.write_all(&id.to_le_bytes()))
+            .map_err(io_error(&path))
+    }
+
+    /// Gives up publishing an event for now: it is returned again by `take_pending`.
+    pub fn release(&mut self, id: u64) {
+        self.held.remove(&id);
+    }
+
+    /// The pending events not held by the process, in order. They are held until acknowledged
+    /// or released.
+    pub fn take_pending(&mut self) -> Result<Vec<OutboxEntry>, InternalError> {
+        let mut entries = vec![];
+        for segment in self.segments.iter() {
+            let mut file = None;
+            for (id, record) in segment.pending.iter() {
+                if self.held.contains(id) {
+                    continue;
+                }
+                let path = self.segment_path(segment.first_id, SEGMENT_EXTENSION);
+                if file.is_none() {
+                    file = Some(File::open(&path).map_err(io_error(&path))?);
+                }
+                let file = file.as_mut().expect("Segment file");
+                let mut data = vec![0; record.len as usize];
+                file.seek(SeekFrom::Start(record.offset))
+                    .and_then(|_| file.read_exact(&mut data))
+                    .map_err(io_error(&path))?;
+                let (entry, _) =
+                    decode_record(&data).ok_or_else(|| InternalError::OutboxError {
+                        cause: format! {"Corrupted event {} in {:?}", id, path},
+                    })?;
+                entries.push(entry);
+            }
+        }
+        self.held.extend(entries.iter().map(|entry| entry.id));
+        Ok(entries)
+    }
+
+    /// Whether an event created at `created_ms` is too old to be replayed at `now_ms`.
+    pub fn is_expired(&self, created_ms: u64, now_ms: u64) -> bool {
+        self.config
+            .max_age
+            .is_some_and(|max_age| now_ms.saturating_sub(created_ms) > max_age.as_millis() as u64)
+    }
+
+    pub fn stats(&self) -> OutboxStats {
+        OutboxStats {
+            entries: self.segments.iter().map(|s| s.pending.len()).sum(),
+            bytes: self.bytes,
+            oldest_created_ms: self
+                .segments
+                .iter()
+                .flat_map(|segment| segment.pending.values())
+                .map(|record| record.created_ms)
+                .min(),
+        }
+    }
+
+    fn report(&self) {
+        if let Some(metrics) = &self.metrics {
+            metrics.set(&self.stats());
+        }
+    }
+
+    /// Starts a new segment beginning at `first_id`.
+    fn roll(&mut self, first_id: u64) -> Result<(), InternalError> {
+        let path = self.segment_path(first_id, SEGMENT_EXTENSION);
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .map_err(io_error(&path))?;
+        if let Some(previous) = self.segments.last() {
+            if previous.pending.is_empty() {
+                let previous = previous.first_id;
+                self.segments.pop();
+                self.remove_segment_files(previous)?;
+            }
+        }
+        debug!("Outbox starting segment {:?}", path);
+        self.active = Some(file);
+        self.segments.push(Segment {
+            first_id,
+            size: 0,
+            pending: BTreeMap::new(),
+        });
+        Ok(())
+    }
+
+    ///
+    /// Reads a segment and its acknowledgements, truncating a record partially written.
+    /// Also returns the id following the last event of the segment.
+    ///
+    fn load_segment(&self, first_id: u64) -> Result<(Segment, u64), InternalError> {
+        let path = self.segment_path(first_id, SEGMENT_EXTENSION);
+        let data = fs::read(&path).map_err(io_error(&path))?;
+        let mut pending = BTreeMap::new();
+        let mut offset = 0;
+        let mut next_id = first_id;
+        while let Some((entry, len)) = decode_record(&data[offset..]) {
+            next_id = entry.id + 1;
+            pending.insert(
+                entry.id,
+                Record {
+                    offset: offset as u64,
+                    len: len as u64,
+                    created_ms: entry.created_ms,
+                },
+            );
+            offset += len;
+        }
+        if offset < data.len() {
+            warn!(
+                "Outbox segment {:?} ends with a partial event, truncating it",
+                path
+            );
+            OpenOptions::new()
+                .write(true)
+                .open(&path)
+                .and_then(|file| file.set_len(offset as u64))
+                .map_err(io_error(&path))?;
+        }
+
+        let ack_path = self.segment_path(first_id, ACK_EXTENSION);
+        match fs::read(&ack_path) {
+            Ok(acks) => {
+                for id in acks.chunks_exact(8) {
+                    pending.remove(&u64::from_le_bytes(id.try_into().expect("8 bytes")));
+                }
+            }
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+            Err(err) => return Err(io_error(&ack_path)(err)),
+        }
+
+        let segment = Segment {
+            first_id,
+            size: offset as u64,
+            pending,
+        };
+        Ok((segment, next_id))
+    }
+
+    fn segment_index(&self, id: u64) -> Option<usize> {
+        self.segments
+            .partition_point(|segment| segment.first_id <= id)
+            .checked_sub(1)
+    }
+
+    fn remove_segment_files(&self, first_id: u64) -> Result<(), InternalError> {
+        for extension in [SEGMENT_EXTENSION, ACK_EXTENSION] {
+            let path = self.segment_path(first_id, extension);
+            match fs::remove_file(&path) {
+                Err(err) if err.kind() != std::io::ErrorKind::NotFound => {
+                    return Err(io_error(&path)(err))
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn segment_path(&self, first_id: u64, extension: &str) -> PathBuf {
+        self.config
+            .directory
+            .join(format!("{:020}.{}", first_id, extension))
+    }
+}
+
+fn encode_record(
+    id: u64,
+    created_ms: u64,
+    subject: &str,
+    payload: &[u8],
+) -> Result<Vec<u8>, InternalError> {
+    let subject_len = u16::try_from(subject.len()).map_err(|_| InternalError::OutboxError {
+        cause: format! {"Subject too long: {}", subject},
+    })?;
+    let len = HEADER_SIZE + subject.len() + payload.len();
+    let record_len = u32::try_from(len - 4).map_err(|_| InternalError::OutboxError {
+        cause: format! {"Event too large: {} bytes", payload.len()},
+    })?;
+    let mut record = Vec::with_capacity(len);
+    record.extend_from_slice(&record_len.to_le_bytes());
+    record.extend_from_slice(&id.to_le_bytes());
+    record.extend_from_slice(&created_ms.to_le_bytes());
+    record.extend_from_slice(&subject_len.to_le_bytes());
+    record.extend_from_slice(subject.as_bytes());
+    record.extend_from_slice(payload);
+    Ok(record)
+}
+
+/// Decodes the record at the start of `data` with its length, `None` if incomplete.
+fn decode_record(data: &[u8]) -> Option<(OutboxEntry, usize)> {
+    let record_len = u32::from_le_bytes(data.get(0..4)?.try_into().ok()?) as usize;
+    let record = data.get(4..4 + record_len)?;
+    let id = u64::from_le_bytes(record.get(0..8)?.try_into().ok()?);
+    let created_ms = u64::from_le_bytes(record.get(8..16)?.try_into().ok()?);
+    let subject_len = u16::from_le_bytes(record.get(16..18)?.try_into().ok()?) as usize;
+    let subject = std::str::from_utf8(record.get(18..18 + subject_len)?).ok()?;
+    let entry = OutboxEntry {
+        id,
+        created_ms,
+        subject: subject.to_owned(),
+        payload: record[18 + subject_len..].to_vec(),
+    };
+    Some((entry, 4 + record_len))
+}
+
+fn io_error(path: &Path) -> impl Fn(std::io::Error) -> InternalError + '_ {
+    move |err| InternalError::OutboxError {
+        cause: format! {"{:?}: {}", path, err},
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::OpenOptions;
+    use std::io::Write;
+    use std::time::Duration;
+
+    use super::{Outbox, OutboxConfig, HEADER_SIZE, SEGMENT_EXTENSION};
+    use crate::metrics::{metrics, NatsMetrics};
+
+    fn config(directory: &tempfile::TempDir) -> OutboxConfig {
+        OutboxConfig {
+            directory: directory.path().to_owned(),
+            segment_size: 100,
+            max_size: None,
+            max_age: None,
+        }
+    }
+
+    #[test]
+    fn should_replay_pending_events_in_order_after_reopening() {
+        let directory = tempfile::tempdir().unwrap();
+        let mut outbox = Outbox::open(config(&directory)).unwrap();
+        let ids: Vec<u64> = (0..10)
+            .map(|i| {
+                outbox
+                    .append("events", format!("event {}", i).as_bytes(), i)
+                    .unwrap()
+            })
+            .collect();
+        for id in ids.iter().filter(|id| *id % 3 == 0) {
+            outbox.ack(*id).unwrap();
+        }
+        assert!(outbox.take_pending().unwrap().is_empty());
+        drop(outbox);
+
+        let mut outbox = Outbox::open(config(&directory)).unwrap();
+        let pending = outbox.take_pending().unwrap();
+
+        let payloads: Vec<String> = pending
+            .iter()
+            .map(|entry| String::from_utf8(entry.payload.clone()).unwrap())
+            .collect();
+        assert_eq!(
+            vec!["event 1", "event 2", "event 4", "event 5", "event 7", "event 8"],
+            payloads
+        );
+        assert_eq!(6, outbox.stats().entries);
+        assert_eq!(Some(1), outbox.stats().oldest_created_ms);
+        assert!(outbox.append("events", b"event 10", 10).unwrap() > ids[9]);
+    }
+
+    #[test]
+    fn should_delete_acknowledged_segments() {
+        let directory = tempfile::tempdir().unwrap();
+        let mut outbox = Outbox::open(config(&directory)).unwrap();
+        let ids: Vec<u64> = (0..10)
+            .map(|_| outbox.append("events", &[0; 40], 0).unwrap())
+            .collect();
+        let segments = || {
+            std::fs::read_dir(directory.path())
+                .unwrap()
+                .filter(|entry| {
+                    entry.as_ref().unwrap().path().extension().unwrap() == SEGMENT_EXTENSION
+                })
+                .count()
+        };
+        assert!(segments() > 1);
+
+        for id in ids {
+            outbox.ack(id).unwrap();
+        }
+
+        // Only the segment appended to is kept
+        assert_eq!(1, segments());
+        assert_eq!(0, outbox.stats().entries);
+        assert_eq!(0, outbox.stats().bytes);
+    }
+
+    #[test]
+    fn should_truncate_partially_written_event() {
+        let directory = tempfile::tempdir().unwrap();
+        let mut outbox = Outbox::open(config(&directory)).unwrap();
+        outbox.append("events", b"event", 0).unwrap();
+        drop(outbox);
+        let segment = std::fs::read_dir(directory.path())
+            .unwrap()
+            .next()
+            .unwrap()
+            .unwrap()
+            .path();
+        OpenOptions::new()
+            .append(true)
+            .open(segment)
+            .unwrap()
+            .write_all(&[42, 0, 0])
+            .unwrap();
+
+        let mut outbox = Outbox::open(config(&directory)).unwrap();
+        outbox.append("events", b"other event", 0).unwrap();
+        drop(outbox);
+
+        let mut outbox = Outbox::open(config(&directory)).unwrap();
+        assert_eq!(2, outbox.take_pending().unwrap().len());
+    }
+
+    #[test]
+    fn should_enforce_size_and_age_limits() {
+        let directory = tempfile::tempdir().unwrap();
+        let mut outbox = Outbox::open(OutboxConfig {
+            max_size: Some(100),
+            max_age: Some(Duration::from_secs(1)),
+            ..config(&directory)
+        })
+        .unwrap();
+
+        let id = outbox.append("events", &[0; 60], 0).unwrap();
+        assert!(outbox.append("events", &[0; 60], 0).is_err());
+        outbox.ack(id).unwrap();
+        assert!(outbox.append("events", &[0; 60], 0).is_ok());
+
+        assert!(!outbox.is_expired(1_000, 1_500));
+        assert!(outbox.is_expired(1_000, 2_500));
+    }
+
+    #[actix_rt::test]
+    async fn should_report_depth_metrics() {
+        use actix_web::{test, App};
+
+        let scrape = || async {
+            let app = test::init_service(App::new().service(metrics)).await;
+            let request = test::TestRequest::get().uri("/metrics").to_request();
+            String::from_utf8(test::call_and_read_body(&app, request).await.to_vec()).unwrap()
+        };
+        let directory = tempfile::tempdir().unwrap();
+        let record_size = HEADER_SIZE + "events".len() + "event".len();
+        let mut outbox = Outbox::open(config(&directory))
+            .unwrap()
+            .with_metrics(NatsMetrics::global().publisher("outbox.appended").outbox);
+
+        let id = outbox.append("events", b"event", 0).unwrap();
+        outbox.append("events", b"event", 0).unwrap();
+        let appended = scrape().await;
+        outbox.ack(id).unwrap();
+        let acknowledged = scrape().await;
+        drop(outbox);
+        let _outbox = Outbox::open(config(&directory))
+            .unwrap()
+            .with_metrics(NatsMetrics::global().publisher("outbox.replayed").outbox);
+        let replayed = scrape().await;
+
+        assert!(appended.contains(r#"nats_publisher_outbox_events{subject="outbox.appended"} 2"#));
+        assert!(appended.contains(&format!(
+            r#"nats_publisher_outbox_bytes{{subject="outbox.appended"}} {}"#,
+            2 * record_size
+        )));
+        assert!(
+            acknowledged.contains(r#"nats_publisher_outbox_events{subject="outbox.appended"} 1"#)
+        );
+        assert!(acknowledged.contains(&format!(
+            r#"nats_publisher_outbox_bytes{{subject="outbox.appended"}} {}"#,
+            record_size
+        )));
+        assert!(replayed.contains(r#"nats_publisher_outbox_events{subject="outbox.replayed"} 1"#));
+    }
+}
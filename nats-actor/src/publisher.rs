@@ -1,84 +1,433 @@
 // Adopted from https://github.com/WuerthPhoenix/tornado/blob/develop/tornado/common/src/actors/nats_publisher.rs
 
 use actix::prelude::*;
-use async_nats::Connection;
+use async_nats::{Connection, Headers};
+use backoff::backoff::Backoff;
 use log::*;
 use serde::{Deserialize, Serialize};
-use std::io::Error;
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+use std::future;
+use std::io::{self, Error};
+use std::marker::PhantomData;
 use std::ops::Deref;
 use std::rc::Rc;
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, oneshot};
 use tokio::time;
 use tracing_futures::Instrument;
 
-use crate::{connect_with_retry, EventMessage, InternalError, NatsClientSettings};
+use crate::chunking::{publish_chunked, ChunkingConfig};
+use crate::compression::{compress, CompressionConfig};
+use crate::connection_event::ConnectionEvent;
+use crate::dead_letter::{now_ms, DeadLetter, DeadLetterSink};
+use crate::health::{ConnectionState, GetStatus, NatsStatus, StatusTracker};
+use crate::jetstream::{
+    declare_stream, publish_acked, with_msg_id, JetStreamConfig, JetStreamEvent, PubAck,
+};
+use crate::metrics::{NatsMetrics, PublisherMetrics};
+use crate::outbox::{Outbox, OutboxConfig, OutboxEntry};
+use crate::rate_limit::{Overflow, RateLimitPolicy, RateLimitStats, RateLimiter};
+use crate::registry::{NatsConnectionRegistry, SharedConnection};
+use crate::shutdown::{InFlight, Shutdown};
+use crate::signing::{RotateSigningKey, Signer, SigningKey};
+use crate::subject::{validate_subject, SubjectTemplate};
+use crate::{
+    flush, AckedEvent, BatchReport, ContentMode, Envelope, EventBatch, EventMessage, InternalError,
+    NatsClientSettings, RetryBackoff, RetryPolicy,
+};
 
-const NATS_CONNECTION_RETRY_INTERVAL_SECS: u64 = 10;
+/// Maximum time waited for the server to confirm the events written to the outbox, and the
+/// `AckedEvent`s in `AckMode::None`.
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(5);
 
-pub struct NatsPublisher {
+/// The `max_attempts` of the default `NatsPublisherConfig.retry_policy`.
+pub const DEFAULT_PUBLISH_MAX_ATTEMPTS: usize = 10;
+
+/// Publishes the events of type `E` received as `EventMessage`s to `config.subject`.
+pub struct NatsPublisher<E: Envelope> {
     config: NatsPublisherConfig,
-    nats_connection: Rc<Option<Connection>>,
+    subject: SubjectTemplate,
+    registry: NatsConnectionRegistry,
+    nats_connection: Rc<Option<SharedConnection>>,
     restarted: bool,
+    restart_backoff: RetryBackoff,
+    reconnect_exhausted: bool,
+    status: StatusTracker,
+    /// Events accepted and neither published nor given up yet
+    in_flight: InFlight,
+    shutting_down: bool,
+    outbox: Option<Rc<RefCell<Outbox>>>,
+    /// The events of the `EventBatch`es waiting to be published
+    buffer: Vec<Buffered>,
+    linger: Option<SpawnHandle>,
+    /// Publishes the chunks of buffered events one after the other, to keep their order
+    chunks: Option<mpsc::UnboundedSender<(Connection, Vec<Buffered>)>>,
+    rate_limiter: Option<RateLimiter>,
+    /// The events delayed by the rate limits, in order, with their subject
+    throttled: VecDeque<(String, Attempt<E>)>,
+    signer: Option<Rc<Signer>>,
+    metrics: PublisherMetrics,
+    envelope: PhantomData<E>,
 }
 
-impl actix::io::WriteHandler<Error> for NatsPublisher {}
+impl<E: Envelope> actix::io::WriteHandler<Error> for NatsPublisher<E> {}
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct NatsPublisherConfig {
     pub client_settings: NatsClientSettings,
+    /// The subject of the events, possibly a `SubjectTemplate` such as `events.{type}`.
+    /// Overridden by `EventMessage.subject`.
     pub subject: String,
     pub mailbox_size: usize,
+    /// Policy for re-sending failed events and for reconnecting after a failure. When not set,
+    /// the events are given up on after `DEFAULT_PUBLISH_MAX_ATTEMPTS` attempts.
+    #[serde(default = "default_retry_policy")]
+    pub retry_policy: RetryPolicy,
+    #[serde(default)]
+    pub ack_mode: AckMode,
+    /// Receives the events given up on after the retries of the `retry_policy`, or that could
+    /// not be published at all. They are only logged when not set.
+    #[serde(default)]
+    pub dead_letter: Option<DeadLetterSink>,
+    /// Persists the events until they are confirmed by the server, to replay them after a
+    /// restart or a reconnection.
+    #[serde(default)]
+    pub outbox: Option<OutboxConfig>,
+    #[serde(default)]
+    pub batch: BatchConfig,
+    /// `ContentMode::Binary` requires an envelope supporting it, such as `cloudevents::Event`.
+    #[serde(default)]
+    pub content_mode: ContentMode,
+    #[serde(default)]
+    pub rate_limit: RateLimitPolicy,
+    /// Compresses the large payloads, which the subscribers decompress transparently.
+    #[serde(default)]
+    pub compression: Option<CompressionConfig>,
+    /// Signs the messages, for the subscribers to verify them. See `RotateSigningKey`.
+    #[serde(default)]
+    pub signing: Option<SigningKey>,
+    /// Splits the events larger than the `max_payload` of the server, for the subscribers to
+    /// reassemble them.
+    #[serde(default)]
+    pub chunking: Option<ChunkingConfig>,
+    /// Publishes the events to a JetStream stream, see `JetStreamEvent`. Not supported with
+    /// `chunking`.
+    #[serde(default)]
+    pub jetstream: Option<JetStreamConfig>,
+}
+
+impl NatsPublisherConfig {
+    /// A configuration publishing to `subject`, with all the optional features turned off.
+    pub fn new<S: Into<String>>(client_settings: NatsClientSettings, subject: S) -> Self {
+        NatsPublisherConfig {
+            client_settings,
+            subject: subject.into(),
+            ..Default::default()
+        }
+    }
+}
+
+impl Default for NatsPublisherConfig {
+    fn default() -> Self {
+        NatsPublisherConfig {
+            client_settings: Default::default(),
+            subject: String::new(),
+            mailbox_size: 100,
+            retry_policy: default_retry_policy(),
+            ack_mode: Default::default(),
+            dead_letter: None,
+            outbox: None,
+            batch: Default::default(),
+            content_mode: Default::default(),
+            rate_limit: Default::default(),
+            compression: None,
+            signing: None,
+            chunking: None,
+            jetstream: None,
+        }
+    }
+}
+
+fn default_retry_policy() -> RetryPolicy {
+    RetryPolicy {
+        max_attempts: Some(DEFAULT_PUBLISH_MAX_ATTEMPTS),
+        ..Default::default()
+    }
+}
+
+///
+/// How the events of the `EventBatch`es are coalesced. They are published and flushed once
+/// `max_size` events are buffered, or `linger` after the first one.
+/// They are neither retried nor written to the outbox, the failures are reported instead.
+///
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(default)]
+pub struct BatchConfig {
+    pub max_size: usize,
+    pub linger: Duration,
+    /// Maximum time waited for the server to confirm a flush.
+    pub flush_timeout: Duration,
+}
+
+impl Default for BatchConfig {
+    fn default() -> Self {
+        BatchConfig {
+            max_size: 1000,
+            linger: Duration::from_millis(5),
+            flush_timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+/// When the result of `publisher.send(EventMessage { .. })` resolves.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum AckMode {
+    /// As soon as the event is accepted. Publish failures are only logged.
+    #[default]
+    None,
+    /// Once the event is published and flushed to the server within `timeout`, or when
+    /// the publish fails after the retries of the `retry_policy`. Once stored by the stream
+    /// in JetStream mode, the `PubAck` replacing the flush.
+    Flush { timeout: Duration },
+}
+
+/// Reports the outcome of an event in `AckMode::Flush`, with its `PubAck` in JetStream mode.
+type Ack = oneshot::Sender<Result<Option<PubAck>, InternalError>>;
+
+/// An event being published, carried over its retries.
+#[derive(Debug)]
+struct Attempt<E: Envelope> {
+    msg: EventMessage<E>,
+    backoff: RetryBackoff,
+    ack: Option<Ack>,
+    first_attempt_ms: u64,
+    outbox_id: Option<u64>,
+}
+
+/// An event of an `EventBatch` waiting to be published.
+struct Buffered {
+    subject: String,
+    headers: Option<Headers>,
+    payload: Vec<u8>,
+    index: usize,
+    batch: Rc<BatchProgress>,
+    accepted_ms: u64,
+}
+
+/// Collects the outcome of the events of an `EventBatch`.
+struct BatchProgress {
+    remaining: Cell<usize>,
+    report: RefCell<BatchReport>,
+    done: RefCell<Option<oneshot::Sender<BatchReport>>>,
+}
+
+impl BatchProgress {
+    fn complete(&self, index: usize, result: Result<(), InternalError>) {
+        let mut report = self.report.borrow_mut();
+        match result {
+            Ok(()) => report.published += 1,
+            Err(err) => report.failed.push((index, err)),
+        }
+        self.remaining.set(self.remaining.get() - 1);
+        if self.remaining.get() == 0 {
+            report.failed.sort_by_key(|(index, _)| *index);
+            if let Some(done) = self.done.borrow_mut().take() {
+                let _ = done.send(std::mem::take(&mut *report));
+            }
+        }
+    }
+}
+
+/// Publishes each chunk of buffered events, then flushes it once.
+async fn publish_chunks(
+    mut chunks: mpsc::UnboundedReceiver<(Connection, Vec<Buffered>)>,
+    in_flight: InFlight,
+    status: StatusTracker,
+    metrics: PublisherMetrics,
+    delivery: Delivery,
+    timeout: Duration,
+) {
+    while let Some((client, chunk)) = chunks.recv().await {
+        debug!("NatsPublisher publishing {} batched event(s)", chunk.len());
+        let mut published = Vec::with_capacity(chunk.len());
+        for item in chunk {
+            let published_item = delivery
+                .send(&client, &item.subject, item.headers.as_ref(), &item.payload)
+                .await;
+            match published_item {
+                // Stored by the stream, not flushed
+                Ok(Some(_)) => {
+                    metrics.published(item.accepted_ms);
+                    in_flight.finish();
+                    item.batch.complete(item.index, Ok(()));
+                }
+                Ok(None) => published.push(item),
+                Err(err) => {
+                    error!(
+                        "NatsPublisher error sending batched event to NATS. Err: {}",
+                        err
+                    );
+                    status.record_error(format! {"Publish failed. Err: {}", err});
+                    metrics.failed();
+                    in_flight.finish();
+                    let cause = err.to_string();
+                    item.batch.complete(
+                        item.index,
+                        Err(InternalError::PublishError { attempts: 1, cause }),
+                    );
+                }
+            }
+        }
+        let result = flush(&client, timeout).await;
+        for item in published {
+            match result {
+                Ok(()) => metrics.published(item.accepted_ms),
+                Err(_) => metrics.failed(),
+            }
+            in_flight.finish();
+            item.batch.complete(item.index, result.clone());
+        }
+    }
 }
 
-impl NatsPublisher {
+/// An event re-sent by the publisher to itself after a failed attempt.
+#[derive(Message, Debug)]
+#[rtype(result = "Result<(), InternalError>")]
+struct RetryEventMessage<E: Envelope>(Attempt<E>);
+
+impl<E: Envelope> NatsPublisher<E> {
     pub async fn start_new(
         config: NatsPublisherConfig,
-    ) -> Result<Addr<NatsPublisher>, InternalError> {
+    ) -> Result<Addr<NatsPublisher<E>>, InternalError> {
+        NatsPublisher::start_with_registry(config, NatsConnectionRegistry::global().clone()).await
+    }
+
+    /// Starts a publisher borrowing its connection from `registry`.
+    /// The publisher listens to the connection lifecycle to react to a closed connection.
+    pub async fn start_with_registry(
+        config: NatsPublisherConfig,
+        registry: NatsConnectionRegistry,
+    ) -> Result<Addr<NatsPublisher<E>>, InternalError> {
+        let subject = SubjectTemplate::parse(&config.subject)?;
+        config.rate_limit.validate()?;
+        if let Some(compression) = &config.compression {
+            compression.validate()?;
+        }
+        if let Some(chunking) = &config.chunking {
+            chunking.validate()?;
+        }
+        if let Some(jetstream) = &config.jetstream {
+            jetstream.validate()?;
+            if config.chunking.is_some() {
+                return Err(InternalError::ConfigurationError {
+                    cause: "Chunking is not supported in JetStream mode".to_owned(),
+                });
+            }
+        }
+        let signer = match &config.signing {
+            Some(key) => Some(Rc::new(Signer::new(key)?)),
+            None => None,
+        };
+        let rate_limiter = RateLimiter::new(&config.rate_limit);
+        let metrics = NatsMetrics::global().publisher(&config.subject);
+        let outbox = match &config.outbox {
+            Some(outbox) => Some(Rc::new(RefCell::new(
+                Outbox::open(outbox.clone())?.with_metrics(metrics.outbox.clone()),
+            ))),
+            None => None,
+        };
+        let listeners = registry.listeners(&config.client_settings)?;
         Ok(actix::Supervisor::start(
-            move |ctx: &mut Context<NatsPublisher>| {
+            move |ctx: &mut Context<NatsPublisher<E>>| {
                 ctx.set_mailbox_capacity(config.mailbox_size);
+                listeners.register(ctx.address().recipient());
+                let restart_backoff = config.retry_policy.backoff(None);
                 NatsPublisher {
                     config,
+                    subject,
+                    registry,
                     nats_connection: Rc::new(None),
                     restarted: false,
+                    restart_backoff,
+                    reconnect_exhausted: false,
+                    status: StatusTracker::new(ConnectionState::Connecting),
+                    in_flight: InFlight::with_gauge(metrics.in_flight.clone()),
+                    shutting_down: false,
+                    outbox,
+                    buffer: vec![],
+                    linger: None,
+                    chunks: None,
+                    rate_limiter,
+                    throttled: VecDeque::new(),
+                    signer,
+                    metrics,
+                    envelope: PhantomData,
                 }
             },
         ))
     }
 }
 
-impl Actor for NatsPublisher {
+impl<E: Envelope> Actor for NatsPublisher<E> {
     type Context = Context<Self>;
 
     fn started(&mut self, ctx: &mut Self::Context) {
+        if self.shutting_down {
+            debug!("NatsPublisher is shut down, not reconnecting");
+            return;
+        }
+
+        let restart_delay = if self.restarted {
+            match self.restart_backoff.next_backoff() {
+                Some(delay) => delay,
+                None => {
+                    error!(
+                        "NatsPublisher gave up reconnecting to NATS after {} attempts",
+                        self.restart_backoff.attempts()
+                    );
+                    self.reconnect_exhausted = true;
+                    self.status.set_state(ConnectionState::Closed);
+                    // Fails the events delayed by the rate limits
+                    self.release_throttled(ctx);
+                    return;
+                }
+            }
+        } else {
+            Duration::ZERO
+        };
+
         info!(
             "Connecting to NATS server: {:?}",
             self.config.client_settings.addresses
         );
 
         let client_config = self.config.client_settings.clone();
-        let nats_connection = self.nats_connection.clone();
+        let registry = self.registry.clone();
+        let jetstream = self.config.jetstream.clone();
         let restarted = self.restarted;
+        // Releases the connection held before the failure, if any
+        self.nats_connection = Rc::new(None);
+        self.status.set_state(ConnectionState::Connecting);
         ctx.wait(
             async move {
                 if restarted {
                     info!(
-                        "NatsPublisher was restarted after a failure. Waiting {} seconds before proceeding ...",
-                        NATS_CONNECTION_RETRY_INTERVAL_SECS
+                        "NatsPublisher was restarted after a failure. Waiting {:?} before proceeding ...",
+                        restart_delay
                     );
-                    time::sleep(time::Duration::from_secs(NATS_CONNECTION_RETRY_INTERVAL_SECS)).await;
-                }
-                if let Some(connection) = nats_connection.deref() {
-                    connection.close().await.unwrap();
-                    match connection.close().await {
-                        Ok(()) => {debug!(
-                            "NatsPublisher successfully closed previously opened NATS connection."
-                        );}
-                        Err(err) => {
-                            error!("NatsPublisher error while closing previously opened NATS connection. Err: {:?}", err)
-                        }
-                    };
+                    time::sleep(restart_delay).await;
+                }
+                let client = registry.acquire(&client_config).await?;
+                if let Some(JetStreamConfig {
+                    stream: Some(stream),
+                    ack_timeout,
+                }) = &jetstream
+                {
+                    declare_stream(&client, stream, *ack_timeout).await?;
                 }
-                connect_with_retry(&client_config).await
+                Ok::<_, InternalError>(client)
             }
             .into_actor(self)
                 .map(move |client, act, ctx| match client {
@@ -88,10 +437,20 @@ impl Actor for NatsPublisher {
                             &act.config.client_settings.addresses
                         );
                         act.nats_connection = Rc::new(Some(client));
+                        act.restart_backoff.reset();
+                        if act.restarted {
+                            act.status.reconnected();
+                        } else {
+                            act.status.set_state(ConnectionState::Connected);
+                        }
+                        act.replay_outbox();
+                        act.release_throttled(ctx);
                     }
                     Err(err) => {
                         act.nats_connection = Rc::new(None);
                         warn!("NatsPublisher connection failed. Err: {}", err);
+                        act.status.set_state(ConnectionState::Disconnected);
+                        act.status.record_error(err);
                         ctx.stop();
                     }
                 }),
@@ -99,61 +458,1317 @@ impl Actor for NatsPublisher {
     }
 }
 
-impl actix::Supervised for NatsPublisher {
-    fn restarting(&mut self, _ctx: &mut Context<NatsPublisher>) {
+impl<E: Envelope> actix::Supervised for NatsPublisher<E> {
+    fn restarting(&mut self, _ctx: &mut Context<NatsPublisher<E>>) {
         info!("Restarting NatsPublisher");
         self.restarted = true;
     }
 }
 
-impl Handler<EventMessage> for NatsPublisher {
+impl<E: Envelope> Handler<ConnectionEvent> for NatsPublisher<E> {
+    type Result = ();
+
+    fn handle(&mut self, msg: ConnectionEvent, _: &mut Context<Self>) {
+        self.status.on_connection_event(&msg);
+        match msg {
+            ConnectionEvent::Closed => {
+                warn!("NatsPublisher NATS connection closed. It will reconnect on the next event.");
+                self.nats_connection = Rc::new(None);
+            }
+            ConnectionEvent::Disconnected => warn!("NatsPublisher NATS connection lost"),
+            ConnectionEvent::Reconnected => self.replay_outbox(),
+            event => debug!("NatsPublisher received connection event {:?}", event),
+        }
+    }
+}
+
+impl<E: Envelope> Handler<Shutdown> for NatsPublisher<E> {
+    type Result = ResponseActFuture<Self, Result<(), InternalError>>;
+
+    fn handle(&mut self, msg: Shutdown, ctx: &mut Context<Self>) -> Self::Result {
+        info!(
+            "NatsPublisher shutting down with {} event(s) in flight",
+            self.in_flight.count()
+        );
+        self.shutting_down = true;
+        self.flush_buffer(ctx);
+        let in_flight = self.in_flight.clone();
+        let timeout = msg.timeout;
+        Box::pin(
+            async move {
+                time::timeout(timeout, in_flight.idle())
+                    .await
+                    .map_err(|_| InternalError::NatsOperationError {
+                        cause: format! {"{} event(s) still in flight after {:?}", in_flight.count(), timeout},
+                    })
+            }
+            .into_actor(self)
+            .then(move |result, act, _| {
+                let connection = act.nats_connection.deref().as_deref().cloned();
+                // Releases the connection, which is closed once flushed if no other actor uses it
+                act.nats_connection = Rc::new(None);
+                act.status.set_state(ConnectionState::Closed);
+                async move {
+                    if let Some(connection) = connection {
+                        flush(&connection, timeout).await?;
+                    }
+                    info!("NatsPublisher shut down");
+                    result
+                }
+                .into_actor(act)
+            }),
+        )
+    }
+}
+
+impl<E: Envelope> Handler<GetStatus> for NatsPublisher<E> {
+    type Result = ResponseFuture<NatsStatus>;
+
+    fn handle(&mut self, _: GetStatus, _: &mut Context<Self>) -> Self::Result {
+        let connection = self.nats_connection.deref().as_deref().cloned();
+        let outbox = self.outbox.as_ref().map(|outbox| outbox.borrow().stats());
+        let rate_limit = self.rate_limiter.as_ref().map(|limiter| RateLimitStats {
+            waiting: self.throttled.len(),
+            ..limiter.stats
+        });
+        let status = self.status.status(connection);
+        Box::pin(async move {
+            NatsStatus {
+                outbox,
+                rate_limit,
+                ..status.await
+            }
+        })
+    }
+}
+
+impl<E: Envelope> Handler<RotateSigningKey> for NatsPublisher<E> {
+    type Result = Result<(), InternalError>;
+
+    fn handle(&mut self, msg: RotateSigningKey, _: &mut Context<Self>) -> Self::Result {
+        let signer = Signer::new(&msg.0)?;
+        info!("NatsPublisher now signing with key [{}]", msg.0.id);
+        self.config.signing = Some(msg.0);
+        self.signer = Some(Rc::new(signer));
+        Ok(())
+    }
+}
+
+impl<E: Envelope> Handler<EventMessage<E>> for NatsPublisher<E> {
+    type Result = ResponseFuture<Result<(), InternalError>>;
+
+    fn handle(&mut self, msg: EventMessage<E>, ctx: &mut Context<Self>) -> Self::Result {
+        if self.shutting_down {
+            return Box::pin(future::ready(Err(InternalError::ShuttingDown)));
+        }
+        self.in_flight.start();
+        let mut attempt = Attempt {
+            msg,
+            backoff: self.config.retry_policy.backoff(None),
+            ack: None,
+            first_attempt_ms: now_ms(),
+            outbox_id: None,
+        };
+        match self.config.ack_mode {
+            AckMode::None => Box::pin(future::ready(self.admit(attempt, ctx))),
+            AckMode::Flush { .. } => {
+                let (ack, acked) = oneshot::channel();
+                attempt.ack = Some(ack);
+                // The failures are reported through the ack too
+                let _ = self.admit(attempt, ctx);
+                Box::pin(async move { acknowledged(acked).await.map(|_| ()) })
+            }
+        }
+    }
+}
+
+impl<E: Envelope> Handler<AckedEvent<E>> for NatsPublisher<E> {
+    type Result = ResponseFuture<Result<(), InternalError>>;
+
+    fn handle(&mut self, msg: AckedEvent<E>, ctx: &mut Context<Self>) -> Self::Result {
+        if self.shutting_down {
+            return Box::pin(future::ready(Err(InternalError::ShuttingDown)));
+        }
+        self.in_flight.start();
+        let (ack, acked) = oneshot::channel();
+        let attempt = Attempt {
+            msg: msg.0,
+            backoff: self.config.retry_policy.backoff(None),
+            ack: Some(ack),
+            first_attempt_ms: now_ms(),
+            outbox_id: None,
+        };
+        // The failures are reported through the ack too
+        let _ = self.admit(attempt, ctx);
+        Box::pin(async move { acknowledged(acked).await.map(|_| ()) })
+    }
+}
+
+impl<E: Envelope> Handler<JetStreamEvent<E>> for NatsPublisher<E> {
+    type Result = ResponseFuture<Result<PubAck, InternalError>>;
+
+    fn handle(&mut self, msg: JetStreamEvent<E>, ctx: &mut Context<Self>) -> Self::Result {
+        if self.shutting_down {
+            return Box::pin(future::ready(Err(InternalError::ShuttingDown)));
+        }
+        if self.config.jetstream.is_none() {
+            return Box::pin(future::ready(Err(InternalError::ConfigurationError {
+                cause: "NatsPublisher not in JetStream mode".to_owned(),
+            })));
+        }
+        self.in_flight.start();
+        let (ack, acked) = oneshot::channel();
+        let attempt = Attempt {
+            msg: msg.0,
+            backoff: self.config.retry_policy.backoff(None),
+            ack: Some(ack),
+            first_attempt_ms: now_ms(),
+            outbox_id: None,
+        };
+        // The failures are reported through the ack too
+        let _ = self.admit(attempt, ctx);
+        Box::pin(async move {
+            acknowledged(acked)
+                .await?
+                .ok_or_else(|| InternalError::NatsOperationError {
+                    cause: "NatsPublisher published the event without PubAck".to_owned(),
+                })
+        })
+    }
+}
+
+/// The outcome of an event, once reported to its ack.
+async fn acknowledged(
+    acked: oneshot::Receiver<Result<Option<PubAck>, InternalError>>,
+) -> Result<Option<PubAck>, InternalError> {
+    acked.await.unwrap_or_else(|_| {
+        Err(InternalError::NatsOperationError {
+            cause: "NatsPublisher stopped before acknowledging the event".to_owned(),
+        })
+    })
+}
+
+impl<E: Envelope> Handler<EventBatch<E>> for NatsPublisher<E> {
+    type Result = ResponseFuture<Result<BatchReport, InternalError>>;
+
+    fn handle(&mut self, batch: EventBatch<E>, ctx: &mut Context<Self>) -> Self::Result {
+        if self.shutting_down {
+            return Box::pin(future::ready(Err(InternalError::ShuttingDown)));
+        }
+        if self.nats_connection.is_none() {
+            if !self.reconnect_exhausted {
+                warn!("NatsPublisher processing batch but NATS connection not yet established. Stopping actor to reconnect ...");
+                ctx.stop();
+            }
+            return Box::pin(future::ready(Err(
+                InternalError::NatsServerConnectionError {
+                    address: self.config.client_settings.addresses.join(","),
+                },
+            )));
+        }
+        if batch.0.is_empty() {
+            return Box::pin(future::ready(Ok(BatchReport::default())));
+        }
+
+        let (done, report) = oneshot::channel();
+        let progress = Rc::new(BatchProgress {
+            remaining: Cell::new(batch.0.len()),
+            report: RefCell::new(BatchReport::default()),
+            done: RefCell::new(Some(done)),
+        });
+        let content_mode = self.config.content_mode;
+        let sealer = self.sealer();
+        let accepted_ms = now_ms();
+        for (index, event) in batch.0.into_iter().enumerate() {
+            let encoded = self.subject.render(&event).and_then(|subject| {
+                let (headers, payload) = event.encode_as(content_mode)?;
+                let sealed = sealer.seal(&subject, Some(event.trace_id()), headers, payload)?;
+                Ok((subject, sealed))
+            });
+            match encoded {
+                Ok((subject, (headers, payload))) => {
+                    self.in_flight.start();
+                    self.buffer.push(Buffered {
+                        subject,
+                        headers,
+                        payload,
+                        index,
+                        batch: progress.clone(),
+                        accepted_ms,
+                    });
+                }
+                Err(err) => progress.complete(index, Err(err)),
+            }
+        }
+
+        if self.buffer.len() >= self.config.batch.max_size {
+            self.flush_buffer(ctx);
+        } else if !self.buffer.is_empty() && self.linger.is_none() {
+            let linger = ctx.run_later(self.config.batch.linger, |act, ctx| {
+                act.linger = None;
+                act.flush_buffer(ctx);
+            });
+            self.linger = Some(linger);
+        }
+
+        Box::pin(async move {
+            report.await.map_err(|_| InternalError::NatsOperationError {
+                cause: "NatsPublisher stopped before publishing the batch".to_owned(),
+            })
+        })
+    }
+}
+
+impl<E: Envelope> Handler<RetryEventMessage<E>> for NatsPublisher<E> {
     type Result = Result<(), InternalError>;
 
-    fn handle(&mut self, msg: EventMessage, ctx: &mut Context<Self>) -> Self::Result {
-        let trace_id = msg.event.trace_id.as_str();
+    fn handle(&mut self, msg: RetryEventMessage<E>, ctx: &mut Context<Self>) -> Self::Result {
+        self.publish(msg.0, ctx)
+    }
+}
+
+impl<E: Envelope> NatsPublisher<E> {
+    fn publish(
+        &mut self,
+        mut attempt: Attempt<E>,
+        ctx: &mut Context<Self>,
+    ) -> Result<(), InternalError> {
+        let trace_id = attempt.msg.event.trace_id();
         let span = tracing::error_span!("NatsPublisher", trace_id).entered();
 
         trace!(
             "NatsPublisher handling Event to be sent to Nats - {:?}",
-            &msg.event
+            &attempt.msg.event
         );
 
         let address = ctx.address();
+        let connection = self.nats_connection.deref().as_deref().cloned();
+        let outcomes = self.outcomes(connection.clone());
 
-        if let Some(connection) = self.nats_connection.deref() {
-            let event =
-                serde_json::to_vec(&msg.event).map_err(|err| InternalError::SerdeError {
-                    cause: format! {"{}", err},
-                })?;
+        let subject = self.subject_of(&attempt.msg);
+        let content_mode = self.config.content_mode;
+        let prepared =
+            subject.and_then(|subject| Ok((subject, attempt.msg.event.encode_as(content_mode)?)));
+        let (subject, (headers, payload)) = match prepared {
+            Ok(prepared) => prepared,
+            Err(err) => {
+                error!("NatsPublisher cannot publish event. Err: {}", err);
+                let subject = attempt
+                    .msg
+                    .subject
+                    .clone()
+                    .unwrap_or_else(|| self.subject.to_string());
+                outcomes.failed(attempt, subject, err.clone());
+                return Err(err);
+            }
+        };
 
-            let client = connection.clone();
-            let config = self.config.clone();
+        if let (Some(outbox), None) = (&self.outbox, attempt.outbox_id) {
+            // The outbox keeps the structured mode, the event is encoded again on replay
+            let appended = match headers {
+                None => outbox
+                    .borrow_mut()
+                    .append(&subject, &payload, attempt.first_attempt_ms),
+                Some(_) => attempt.msg.event.encode().and_then(|structured| {
+                    outbox
+                        .borrow_mut()
+                        .append(&subject, &structured, attempt.first_attempt_ms)
+                }),
+            };
+            match appended {
+                Ok(id) => attempt.outbox_id = Some(id),
+                Err(err) => {
+                    error!(
+                        "NatsPublisher cannot write event to the outbox. Err: {}",
+                        err
+                    );
+                    outcomes.failed(attempt, subject, err.clone());
+                    return Err(err);
+                }
+            }
+        }
+
+        let msg_id = Some(attempt.msg.event.trace_id());
+        let (headers, payload) = match self.sealer().seal(&subject, msg_id, headers, payload) {
+            Ok(sealed) => sealed,
+            Err(err) => {
+                error!("NatsPublisher cannot seal event. Err: {}", err);
+                outcomes.failed(attempt, subject, err.clone());
+                return Err(err);
+            }
+        };
+        if let Some(chunking) = &self.config.chunking {
+            if let Err(err) = chunking.check(headers.as_ref(), &payload) {
+                error!("NatsPublisher cannot chunk event. Err: {}", err);
+                outcomes.failed(attempt, subject, err.clone());
+                return Err(err);
+            }
+        }
+
+        if let Some(client) = connection {
+            let ack_mode = self.config.ack_mode;
+            let delivery = self.delivery();
+            let status = self.status.clone();
 
             actix::spawn(async move {
                 debug!("NatsPublisher publishing event to NATS");
-                match client.publish(&config.subject, &event).await {
-                    Ok(_) => trace!(
-                        "NatsPublisher publish event to NATS succeeded. Event: {:?}",
-                        &msg
-                    ),
+                let published = delivery
+                    .send(&client, &subject, headers.as_ref(), &payload)
+                    .await;
+                match published {
+                    Ok(pub_ack) => {
+                        trace!(
+                            "NatsPublisher publish event to NATS succeeded. Event: {:?}",
+                            &attempt.msg
+                        );
+                        let result = match ack_mode {
+                            // Stored by the stream
+                            _ if pub_ack.is_some() => Ok(pub_ack),
+                            AckMode::Flush { timeout } if attempt.ack.is_some() => {
+                                flush(&client, timeout).await.map(|_| None)
+                            }
+                            _ if attempt.ack.is_some() || attempt.outbox_id.is_some() => {
+                                flush(&client, FLUSH_TIMEOUT).await.map(|_| None)
+                            }
+                            _ => Ok(None),
+                        };
+                        outcomes.published(
+                            attempt.ack,
+                            attempt.outbox_id,
+                            attempt.first_attempt_ms,
+                            result,
+                        );
+                    }
                     Err(e) => {
                         error!("NatsPublisher error sending event to NATS. Err: {:?}", e);
-                        time::sleep(time::Duration::from_secs(1)).await;
-                        address.try_send(msg).unwrap_or_else(|err| error!("NatsPublisherActor -  Error while sending event to itself. Error: {}", err));
+                        status.record_error(format! {"Publish failed. Err: {}", e});
+                        let mut attempt = attempt;
+                        match attempt.backoff.next_backoff() {
+                            Some(delay) => {
+                                outcomes.metrics.retried();
+                                time::sleep(delay).await;
+                                address.try_send(RetryEventMessage(attempt)).unwrap_or_else(|err| {
+                                    error!("NatsPublisherActor -  Error while sending event to itself. Error: {}", err);
+                                    let cause = format! {"Cannot retry the event. Err: {}", err};
+                                    outcomes.deferred(err.into_inner().0, subject, InternalError::NatsOperationError { cause });
+                                });
+                            }
+                            None => {
+                                error!(
+                                    "NatsPublisher giving up sending event to NATS after {} attempts. Event: {:?}",
+                                    attempt.backoff.attempts(),
+                                    &attempt.msg
+                                );
+                                let attempts = attempt.backoff.attempts();
+                                outcomes.failed(attempt, subject, InternalError::PublishError { attempts, cause: e.to_string() });
+                            }
+                        }
                     }
                 }
             }.instrument(span.exit()));
+        } else if self.reconnect_exhausted {
+            let err = InternalError::NatsServerConnectionError {
+                address: self.config.client_settings.addresses.join(","),
+            };
+            outcomes.failed(attempt, subject, err.clone());
+            return Err(err);
         } else {
             warn!("NatsPublisher processing event but NATS connection not yet established. Stopping actor and reprocessing the event ...");
             ctx.stop();
-            address.try_send(msg).unwrap_or_else(|err| {
-                error!(
-                    "NatsPublisher error while sending event to itself. Err: {:?}",
-                    err
-                )
-            });
+            address
+                .try_send(RetryEventMessage(attempt))
+                .unwrap_or_else(|err| {
+                    error!(
+                        "NatsPublisher error while sending event to itself. Err: {:?}",
+                        err
+                    );
+                    let cause = format! {"Cannot retry the event. Err: {}", err};
+                    outcomes.deferred(
+                        err.into_inner().0,
+                        subject,
+                        InternalError::NatsOperationError { cause },
+                    );
+                });
         }
 
         Ok(())
     }
+
+    /// The subject of the event: the override of `msg`, or the subject template rendered.
+    fn subject_of(&self, msg: &EventMessage<E>) -> Result<String, InternalError> {
+        match &msg.subject {
+            Some(subject) => validate_subject(subject, false).map(|_| subject.clone()),
+            None => self.subject.render(&msg.event),
+        }
+    }
+
+    /// Publishes the event, unless it is over the rate limits of `NatsPublisherConfig.rate_limit`.
+    fn admit(&mut self, attempt: Attempt<E>, ctx: &mut Context<Self>) -> Result<(), InternalError> {
+        let subject = match (&self.rate_limiter, self.subject_of(&attempt.msg)) {
+            (Some(_), Ok(subject)) => subject,
+            // Not limited, or reported as failed by `publish`
+            _ => return self.publish(attempt, ctx),
+        };
+        let limiter = self.rate_limiter.as_mut().expect("rate limiter");
+        // The events waiting for the limits go first
+        let wait = if self.throttled.is_empty() {
+            match limiter.try_acquire(&subject, Instant::now()) {
+                Ok(()) => return self.publish(attempt, ctx),
+                Err(wait) => Some(wait),
+            }
+        } else {
+            None
+        };
+
+        debug!("NatsPublisher event to [{}] over the rate limit", subject);
+        let err = InternalError::RateLimited {
+            subject: subject.clone(),
+        };
+        self.metrics.throttled(limiter.overflow());
+        match limiter.overflow() {
+            Overflow::Queue if self.throttled.len() < self.config.mailbox_size => {
+                limiter.stats.queued += 1;
+                self.throttled.push_back((subject, attempt));
+                if let Some(wait) = wait {
+                    ctx.run_later(wait, |act, ctx| act.release_throttled(ctx));
+                }
+                Ok(())
+            }
+            Overflow::Shed => {
+                limiter.stats.shed += 1;
+                let connection = self.nats_connection.deref().as_deref().cloned();
+                self.outcomes(connection)
+                    .failed(attempt, subject, err.clone());
+                Err(err)
+            }
+            Overflow::Queue | Overflow::Reject => {
+                limiter.stats.rejected += 1;
+                self.metrics.failed();
+                self.in_flight.finish();
+                if let Some(ack) = attempt.ack {
+                    let _ = ack.send(Err(err.clone()));
+                }
+                Err(err)
+            }
+        }
+    }
+
+    /// Publishes the events delayed by the rate limits that are allowed by now.
+    fn release_throttled(&mut self, ctx: &mut Context<Self>) {
+        while let Some((subject, _)) = self.throttled.front() {
+            let limiter = match self.rate_limiter.as_mut() {
+                Some(limiter) => limiter,
+                None => return,
+            };
+            // Without connection, `publish` reconnects or fails the events
+            let allowed = if self.nats_connection.is_some() {
+                limiter.try_acquire(subject, Instant::now())
+            } else {
+                Ok(())
+            };
+            match allowed {
+                Ok(()) => {
+                    let (_, attempt) = self.throttled.pop_front().expect("throttled event");
+                    // The failures are reported through the outcomes
+                    let _ = self.publish(attempt, ctx);
+                }
+                Err(wait) => {
+                    ctx.run_later(wait, |act, ctx| act.release_throttled(ctx));
+                    return;
+                }
+            }
+        }
+    }
+
+    ///
+    /// Publishes in order the events left in the outbox by a previous run or by the attempts
+    /// that could not be retried. They are removed from the outbox once flushed, and kept for
+    /// the next reconnection when the publish fails.
+    ///
+    fn replay_outbox(&mut self) {
+        let (outbox, client) = match (&self.outbox, self.nats_connection.deref()) {
+            (Some(outbox), Some(client)) => (outbox.clone(), Connection::clone(client)),
+            _ => return,
+        };
+        let entries = match outbox.borrow_mut().take_pending() {
+            Ok(entries) => entries,
+            Err(err) => {
+                error!("NatsPublisher cannot read the outbox. Err: {}", err);
+                return;
+            }
+        };
+        if entries.is_empty() {
+            return;
+        }
+        info!(
+            "NatsPublisher replaying {} event(s) from the outbox",
+            entries.len()
+        );
+        for _ in entries.iter() {
+            self.in_flight.start();
+        }
+        let outcomes = self.outcomes(Some(client.clone()));
+        let content_mode = self.config.content_mode;
+        let sealer = self.sealer();
+        let delivery = self.delivery();
+        actix::spawn(async move {
+            let now = now_ms();
+            let mut entries = entries.into_iter();
+            let mut published = vec![];
+            for entry in entries.by_ref() {
+                if outbox.borrow().is_expired(entry.created_ms, now) {
+                    outcomes.dropped::<E>(entry, "Event expired in the outbox".to_owned());
+                    continue;
+                }
+                let encoded = match content_mode {
+                    ContentMode::Structured => Ok((None, entry.payload.clone())),
+                    ContentMode::Binary => E::decode(&entry.payload)
+                        .and_then(|event| event.encode_binary())
+                        .map(|(headers, payload)| (Some(headers), payload)),
+                };
+                let msg_id = if sealer.deduplicated {
+                    E::decode(&entry.payload)
+                        .map(|event| event.trace_id().to_owned())
+                        .ok()
+                } else {
+                    None
+                };
+                let encoded = encoded.and_then(|(headers, payload)| {
+                    sealer.seal(&entry.subject, msg_id.as_deref(), headers, payload)
+                });
+                let result = match &encoded {
+                    Ok((headers, payload)) => {
+                        delivery
+                            .send(&client, &entry.subject, headers.as_ref(), payload)
+                            .await
+                    }
+                    Err(err) => {
+                        error!(
+                            "NatsPublisher cannot encode event from the outbox. Err: {}",
+                            err
+                        );
+                        outcomes.dropped::<E>(entry, err.to_string());
+                        continue;
+                    }
+                };
+                match result {
+                    Ok(_) => published.push((entry.id, entry.created_ms)),
+                    Err(err) => {
+                        warn!("NatsPublisher outbox replay interrupted. Err: {}", err);
+                        outcomes.released(entry.id);
+                        break;
+                    }
+                }
+            }
+            for entry in entries {
+                outcomes.released(entry.id);
+            }
+            let result = flush(&client, FLUSH_TIMEOUT).await;
+            for (id, created_ms) in published {
+                outcomes.published(None, Some(id), created_ms, result.clone().map(|_| None));
+            }
+        });
+    }
+
+    /// Publishes the buffered events of the `EventBatch`es, `BatchConfig.max_size` per flush.
+    fn flush_buffer(&mut self, ctx: &mut Context<Self>) {
+        if let Some(linger) = self.linger.take() {
+            ctx.cancel_future(linger);
+        }
+        let max_size = self.config.batch.max_size.max(1);
+        while !self.buffer.is_empty() {
+            let chunk: Vec<Buffered> = self
+                .buffer
+                .drain(..self.buffer.len().min(max_size))
+                .collect();
+            self.publish_chunk(chunk);
+        }
+    }
+
+    fn publish_chunk(&mut self, chunk: Vec<Buffered>) {
+        let in_flight = self.in_flight.clone();
+        let client = match self.nats_connection.deref() {
+            Some(client) => Connection::clone(client),
+            None => {
+                let address = self.config.client_settings.addresses.join(",");
+                for item in chunk {
+                    in_flight.finish();
+                    let address = address.clone();
+                    item.batch.complete(
+                        item.index,
+                        Err(InternalError::NatsServerConnectionError { address }),
+                    );
+                }
+                return;
+            }
+        };
+        let delivery = self.delivery();
+        let chunks = self.chunks.get_or_insert_with(|| {
+            let (sender, receiver) = mpsc::unbounded_channel();
+            actix::spawn(publish_chunks(
+                receiver,
+                in_flight,
+                self.status.clone(),
+                self.metrics.clone(),
+                delivery,
+                self.config.batch.flush_timeout,
+            ));
+            sender
+        });
+        // The receiver lives as long as the sender
+        let _ = chunks.send((client, chunk));
+    }
+
+    fn sealer(&self) -> Sealer {
+        Sealer {
+            deduplicated: self.config.jetstream.is_some(),
+            compression: self.config.compression,
+            signer: self.signer.clone(),
+        }
+    }
+
+    fn delivery(&self) -> Delivery {
+        Delivery {
+            chunking: self.config.chunking,
+            ack_timeout: self
+                .config
+                .jetstream
+                .as_ref()
+                .map(|jetstream| jetstream.ack_timeout),
+        }
+    }
+
+    fn outcomes(&self, connection: Option<Connection>) -> Outcomes {
+        Outcomes {
+            in_flight: self.in_flight.clone(),
+            dead_letter: self.config.dead_letter.clone(),
+            outbox: self.outbox.clone(),
+            connection,
+            metrics: self.metrics.clone(),
+        }
+    }
+}
+
+/// Adds the `Nats-Msg-Id` header, compresses then signs the encoded events, as configured.
+pub(crate) struct Sealer {
+    /// Whether the events carry their `Nats-Msg-Id`, in JetStream mode.
+    pub(crate) deduplicated: bool,
+    pub(crate) compression: Option<CompressionConfig>,
+    pub(crate) signer: Option<Rc<Signer>>,
+}
+
+impl Sealer {
+    pub(crate) fn seal(
+        &self,
+        subject: &str,
+        msg_id: Option<&str>,
+        headers: Option<Headers>,
+        payload: Vec<u8>,
+    ) -> Result<(Option<Headers>, Vec<u8>), InternalError> {
+        let headers = match msg_id {
+            Some(msg_id) if self.deduplicated => Some(with_msg_id(headers, msg_id)),
+            _ => headers,
+        };
+        let (headers, payload) = compress(self.compression.as_ref(), headers, payload)?;
+        let headers = match &self.signer {
+            Some(signer) => signer.sign(subject, headers, &payload),
+            None => headers,
+        };
+        Ok((headers, payload))
+    }
+}
+
+/// Sends the sealed events.
+#[derive(Clone, Copy)]
+struct Delivery {
+    chunking: Option<ChunkingConfig>,
+    /// Set in JetStream mode, where the events wait for their `PubAck`.
+    ack_timeout: Option<Duration>,
+}
+
+impl Delivery {
+    /// Publishes the event, and waits for its `PubAck` in JetStream mode.
+    async fn send(
+        &self,
+        client: &Connection,
+        subject: &str,
+        headers: Option<&Headers>,
+        payload: &[u8],
+    ) -> io::Result<Option<PubAck>> {
+        match self.ack_timeout {
+            Some(timeout) => publish_acked(client, subject, headers, payload, timeout)
+                .await
+                .map(Some),
+            None => publish_chunked(client, subject, headers, payload, self.chunking.as_ref())
+                .await
+                .map(|_| None),
+        }
+    }
+}
+
+///
+/// Reports the outcome of the events: releases their in-flight slot, resolves their ack in
+/// `AckMode::Flush` and sends the events given up on to the dead-letter sink.
+///
+struct Outcomes {
+    in_flight: InFlight,
+    dead_letter: Option<DeadLetterSink>,
+    outbox: Option<Rc<RefCell<Outbox>>>,
+    connection: Option<Connection>,
+    metrics: PublisherMetrics,
+}
+
+impl Outcomes {
+    /// The event was published, and confirmed by the server, or stored by the stream, if
+    /// `result` is `Ok`.
+    fn published(
+        &self,
+        ack: Option<Ack>,
+        outbox_id: Option<u64>,
+        first_attempt_ms: u64,
+        result: Result<Option<PubAck>, InternalError>,
+    ) {
+        match result {
+            Ok(_) => self.metrics.published(first_attempt_ms),
+            Err(_) => self.metrics.failed(),
+        }
+        if let Some(id) = outbox_id {
+            match &result {
+                Ok(_) => self.remove_from_outbox(id),
+                // The event may not have reached the server, it is replayed on reconnection
+                Err(_) => self.released(id),
+            }
+        }
+        self.in_flight.finish();
+        if let Some(ack) = ack {
+            let _ = ack.send(result);
+        }
+    }
+
+    /// The event cannot be retried for now. It is replayed on reconnection when in the outbox,
+    /// otherwise given up on.
+    fn deferred<E: Envelope>(&self, attempt: Attempt<E>, subject: String, err: InternalError) {
+        match attempt.outbox_id {
+            Some(id) => {
+                self.released(id);
+                self.in_flight.finish();
+                if let Some(ack) = attempt.ack {
+                    let _ = ack.send(Err(err));
+                }
+            }
+            None => self.failed(attempt, subject, err),
+        }
+    }
+
+    /// The event is given up on.
+    fn failed<E: Envelope>(&self, attempt: Attempt<E>, subject: String, err: InternalError) {
+        let letter = DeadLetter::new(
+            subject,
+            &attempt.msg.event,
+            attempt.backoff.attempts(),
+            &err,
+            attempt.first_attempt_ms,
+        );
+        if let Some(ack) = attempt.ack {
+            let _ = ack.send(Err(err));
+        }
+        self.dead_letter(letter, attempt.outbox_id);
+    }
+
+    /// The event of the outbox cannot be published anymore, because it stayed in the outbox for
+    /// longer than its `max_age` for instance.
+    fn dropped<E: Envelope>(&self, entry: OutboxEntry, last_error: String) {
+        let event = E::decode(&entry.payload)
+            .ok()
+            .and_then(|event| serde_json::to_value(event).ok())
+            .unwrap_or_else(|| {
+                serde_json::Value::String(String::from_utf8_lossy(&entry.payload).into_owned())
+            });
+        let letter = DeadLetter {
+            subject: entry.subject,
+            event,
+            attempts: 0,
+            last_error,
+            first_attempt_ms: entry.created_ms,
+            failed_ms: now_ms(),
+        };
+        self.dead_letter(letter, Some(entry.id));
+    }
+
+    /// Releases the event from the process, keeping it in the outbox.
+    fn released(&self, id: u64) {
+        if let Some(outbox) = &self.outbox {
+            outbox.borrow_mut().release(id);
+        }
+    }
+
+    fn remove_from_outbox(&self, id: u64) {
+        if let Some(outbox) = &self.outbox {
+            if let Err(err) = outbox.borrow_mut().ack(id) {
+                error!(
+                    "NatsPublisher cannot remove event {} from the outbox. Err: {}",
+                    id, err
+                );
+            }
+        }
+    }
+
+    fn dead_letter(&self, letter: DeadLetter, outbox_id: Option<u64>) {
+        self.metrics.failed();
+        // Accounted for by the dead-letter sink or the logs from now on
+        if let Some(id) = outbox_id {
+            self.remove_from_outbox(id);
+        }
+        let sink = match &self.dead_letter {
+            Some(sink) => sink.clone(),
+            None => {
+                error!("NatsPublisher gave up on event. Letter: {:?}", letter);
+                return self.in_flight.finish();
+            }
+        };
+        // The event stays in flight until it reaches the sink, so a shutdown waits for it
+        let in_flight = self.in_flight.clone();
+        let connection = self.connection.clone();
+        actix::spawn(async move {
+            if let Err(err) = sink.send(letter.clone(), connection).await {
+                error!(
+                    "NatsPublisher cannot send the event to the dead-letter sink. Letter: {:?}. Err: {}",
+                    letter, err
+                );
+            }
+            in_flight.finish();
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[cfg(feature = "legacy")]
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    #[cfg(feature = "legacy")]
+    use actix_web::ResponseError;
+
+    use super::{AckMode, NatsPublisher, NatsPublisherConfig};
+    #[cfg(feature = "legacy")]
+    use crate::dead_letter::{DeadLetterCallback, DeadLetterSink};
+    #[cfg(feature = "legacy")]
+    use crate::health::GetStatus;
+    #[cfg(feature = "legacy")]
+    use crate::outbox::{Outbox, OutboxConfig};
+    #[cfg(feature = "legacy")]
+    use crate::shutdown::Shutdown;
+    use crate::test_support::FakeNatsServer;
+    #[cfg(feature = "legacy")]
+    use crate::{Envelope, Event, EventMessage, InternalError, RetryPolicy};
+
+    fn config(server: &FakeNatsServer) -> NatsPublisherConfig {
+        NatsPublisherConfig {
+            client_settings: server.client_settings(),
+            subject: "ack".to_owned(),
+            mailbox_size: 10,
+            ack_mode: AckMode::Flush {
+                timeout: Duration::from_millis(500),
+            },
+            ..Default::default()
+        }
+    }
+
+    #[cfg(feature = "legacy")]
+    #[actix_rt::test]
+    async fn should_acknowledge_flushed_event() {
+        let server = FakeNatsServer::start();
+        let connection = async_nats::connect(&server.address()).await.unwrap();
+        let subscription = connection.subscribe("ack").await.unwrap();
+        connection.flush().await.unwrap();
+        let publisher = NatsPublisher::<Event>::start_new(config(&server))
+            .await
+            .unwrap();
+
+        let result = publisher
+            .send(EventMessage {
+                event: Event::new("event_type"),
+                subject: None,
+            })
+            .await
+            .unwrap();
+
+        assert!(result.is_ok());
+        assert!(subscription.next().await.is_some());
+    }
+
+    #[cfg(feature = "legacy")]
+    #[actix_rt::test]
+    async fn should_report_unavailable_server_in_ack_mode() {
+        let mut server = FakeNatsServer::start();
+        // Gives up reconnecting quickly once the server is gone
+        let mut config = config(&server);
+        config.client_settings.max_reconnects = Some(0);
+        config.client_settings.retry_timeout = Some(Duration::from_millis(100));
+        config.retry_policy = RetryPolicy {
+            initial_interval: Duration::from_millis(10),
+            max_attempts: Some(1),
+            ..Default::default()
+        };
+        let publisher = NatsPublisher::<Event>::start_new(config).await.unwrap();
+        // Waits for the publisher to be connected
+        publisher.send(GetStatus).await.unwrap();
+        server.stop();
+
+        let err = publisher
+            .send(EventMessage {
+                event: Event::new("event_type"),
+                subject: None,
+            })
+            .await
+            .unwrap()
+            .unwrap_err();
+
+        assert_eq!(503, err.status_code().as_u16());
+    }
+
+    #[cfg(feature = "legacy")]
+    #[actix_rt::test]
+    async fn should_route_events_by_subject() {
+        let server = FakeNatsServer::start();
+        let connection = async_nats::connect(&server.address()).await.unwrap();
+        let subscription = connection.subscribe(">").await.unwrap();
+        connection.flush().await.unwrap();
+        let mut config = config(&server);
+        config.subject = "events.{event_type}".to_owned();
+        let publisher = NatsPublisher::<Event>::start_new(config).await.unwrap();
+
+        for (event_type, subject) in [("com.example.ping", None), ("pong", Some("audit.pong"))] {
+            publisher
+                .send(EventMessage {
+                    event: Event::new(event_type),
+                    subject: subject.map(str::to_owned),
+                })
+                .await
+                .unwrap()
+                .unwrap();
+        }
+        let invalid = publisher
+            .send(EventMessage {
+                event: Event::new("ping pong"),
+                subject: None,
+            })
+            .await
+            .unwrap();
+
+        assert_eq!(
+            "events.com.example.ping",
+            subscription.next().await.unwrap().subject
+        );
+        assert_eq!("audit.pong", subscription.next().await.unwrap().subject);
+        assert!(invalid.is_err());
+    }
+
+    #[cfg(feature = "legacy")]
+    #[actix_rt::test]
+    async fn should_publish_batches_and_report_failed_events() {
+        use crate::EventBatch;
+
+        let server = FakeNatsServer::start();
+        let connection = async_nats::connect(&server.address()).await.unwrap();
+        let subscription = connection.subscribe("events.>").await.unwrap();
+        connection.flush().await.unwrap();
+        let mut config = config(&server);
+        config.subject = "events.{event_type}".to_owned();
+        config.batch.max_size = 1000;
+        let publisher = NatsPublisher::<Event>::start_new(config).await.unwrap();
+
+        let events = (0..2500).map(|i| Event::new(format!("event_{}", i)));
+        let report = publisher
+            .send(EventBatch(events.collect()))
+            .await
+            .unwrap()
+            .unwrap();
+        let mixed = publisher
+            .send(EventBatch(vec![
+                Event::new("first"),
+                Event::new("ping pong"),
+            ]))
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(2500, report.published);
+        assert!(report.failed.is_empty());
+        for i in 0..2500 {
+            let message = subscription.next().await.unwrap();
+            assert_eq!(format!("events.event_{}", i), message.subject);
+        }
+        assert_eq!(1, mixed.published);
+        assert_eq!(1, mixed.failed.len());
+        assert_eq!(1, mixed.failed[0].0);
+        assert_eq!("events.first", subscription.next().await.unwrap().subject);
+    }
+
+    #[cfg(feature = "cloudevents")]
+    #[actix_rt::test]
+    async fn should_publish_cloud_events_in_binary_mode() {
+        use cloudevents::{AttributesReader, EventBuilder, EventBuilderV10};
+
+        use crate::subscriber::NatsMessage;
+        use crate::{ContentMode, EventMessage};
+
+        let server = FakeNatsServer::start();
+        let connection = async_nats::connect(&server.address()).await.unwrap();
+        let subscription = connection.subscribe("ack").await.unwrap();
+        connection.flush().await.unwrap();
+        let mut config = config(&server);
+        config.content_mode = ContentMode::Binary;
+        let publisher = NatsPublisher::<cloudevents::Event>::start_new(config)
+            .await
+            .unwrap();
+        let event = EventBuilderV10::new()
+            .source("http://localhost")
+            .id("trace_id")
+            .ty("com.example.hello")
+            .data("text/plain", "hello")
+            .build()
+            .unwrap();
+
+        publisher
+            .send(EventMessage {
+                event: event.clone(),
+                subject: None,
+            })
+            .await
+            .unwrap()
+            .unwrap();
+
+        let msg = subscription.next().await.unwrap();
+        assert_eq!(b"hello".to_vec(), msg.data);
+        assert!(msg.headers.as_ref().unwrap().contains_key("ce-id"));
+        let received: cloudevents::Event = NatsMessage::new(msg).event().unwrap();
+        assert_eq!(event.id(), received.id());
+        assert_eq!(event.ty(), received.ty());
+        assert_eq!(Some("text/plain"), received.datacontenttype());
+    }
+
+    #[cfg(feature = "legacy")]
+    #[actix_rt::test]
+    async fn should_queue_or_reject_events_over_rate_limit() {
+        use crate::rate_limit::{Overflow, RateLimit, RateLimitPolicy};
+
+        let server = FakeNatsServer::start();
+        let connection = async_nats::connect(&server.address()).await.unwrap();
+        let subscription = connection.subscribe("ack").await.unwrap();
+        connection.flush().await.unwrap();
+        let limit = RateLimit {
+            rate: 20.0,
+            burst: 1,
+        };
+        let mut queue = config(&server);
+        queue.rate_limit = RateLimitPolicy {
+            global: Some(limit),
+            ..Default::default()
+        };
+        let mut reject = queue.clone();
+        reject.rate_limit.overflow = Overflow::Reject;
+        let queue = NatsPublisher::<Event>::start_new(queue).await.unwrap();
+        let reject = NatsPublisher::<Event>::start_new(reject).await.unwrap();
+        let event = || EventMessage {
+            event: Event::new("event"),
+            subject: None,
+        };
+
+        let start = std::time::Instant::now();
+        let sent = futures_util::future::join_all((0..3).map(|_| queue.send(event()))).await;
+        let elapsed = start.elapsed();
+        reject.send(event()).await.unwrap().unwrap();
+        let rejected = reject.send(event()).await.unwrap();
+
+        assert!(sent.into_iter().all(|result| result.unwrap().is_ok()));
+        assert!(elapsed >= Duration::from_millis(90), "{:?}", elapsed);
+        for _ in 0..4 {
+            subscription.next().await.unwrap();
+        }
+        assert!(matches!(rejected, Err(InternalError::RateLimited { .. })));
+        let queued = queue.send(GetStatus).await.unwrap().rate_limit.unwrap();
+        assert_eq!(2, queued.queued);
+        assert_eq!(0, queued.waiting);
+        let rejected = reject.send(GetStatus).await.unwrap().rate_limit.unwrap();
+        assert_eq!(1, rejected.rejected);
+    }
+
+    #[cfg(feature = "legacy")]
+    #[actix_rt::test]
+    async fn should_return_pub_ack_of_stream_in_jetstream_mode() {
+        use std::collections::HashMap;
+
+        use crate::jetstream::{JetStreamConfig, JetStreamEvent, StreamConfig};
+
+        let server = FakeNatsServer::start();
+        let connection = async_nats::connect(&server.address()).await.unwrap();
+        let api = connection
+            .subscribe("$JS.API.STREAM.CREATE.EVENTS")
+            .await
+            .unwrap();
+        let stream = connection.subscribe("ack").await.unwrap();
+        connection.flush().await.unwrap();
+        // Stores the messages, by `Nats-Msg-Id`
+        actix_rt::spawn(async move {
+            api.next().await.unwrap().respond("{}").await.unwrap();
+            let mut sequences = HashMap::new();
+            while let Some(msg) = stream.next().await {
+                let headers = msg.headers.clone().unwrap();
+                let msg_id = headers["Nats-Msg-Id"].iter().next().unwrap().clone();
+                let next = sequences.len() as u64 + 1;
+                let duplicate = sequences.contains_key(&msg_id);
+                let seq = *sequences.entry(msg_id).or_insert(next);
+                let ack =
+                    serde_json::json!({"stream": "EVENTS", "seq": seq, "duplicate": duplicate});
+                msg.respond(ack.to_string()).await.unwrap();
+            }
+        });
+        let mut config = config(&server);
+        config.jetstream = Some(JetStreamConfig {
+            stream: Some(StreamConfig {
+                name: "EVENTS".to_owned(),
+                subjects: vec!["ack".to_owned()],
+                retention: Default::default(),
+                max_age: None,
+                replicas: 1,
+                storage: Default::default(),
+                duplicate_window: None,
+            }),
+            ack_timeout: Duration::from_secs(1),
+        });
+        let publisher = NatsPublisher::<Event>::start_new(config).await.unwrap();
+        let event = Event::new("event_type");
+        let message = |event: &Event| {
+            JetStreamEvent(EventMessage {
+                event: event.clone(),
+                subject: None,
+            })
+        };
+
+        let first = publisher.send(message(&event)).await.unwrap().unwrap();
+        let retried = publisher.send(message(&event)).await.unwrap().unwrap();
+        let other = publisher
+            .send(message(&Event::new("event_type")))
+            .await
+            .unwrap()
+            .unwrap();
+        publisher
+            .send(EventMessage {
+                event: Event::new("event_type"),
+                subject: None,
+            })
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(
+            ("EVENTS", 1, false),
+            (first.stream.as_str(), first.seq, first.duplicate)
+        );
+        assert_eq!((1, true), (retried.seq, retried.duplicate));
+        assert_eq!((2, false), (other.seq, other.duplicate));
+    }
+
+    #[cfg(feature = "legacy")]
+    #[actix_rt::test]
+    async fn should_send_failed_events_to_dead_letter_sink() {
+        let server = FakeNatsServer::start();
+        let letters = Arc::new(Mutex::new(vec![]));
+        let sink = letters.clone();
+        let mut config = config(&server);
+        config.subject = "events.{event_type}".to_owned();
+        config.dead_letter = Some(DeadLetterSink::Callback(DeadLetterCallback::new(
+            move |letter| {
+                sink.lock().unwrap().push(letter);
+                Ok(())
+            },
+        )));
+        let publisher = NatsPublisher::<Event>::start_new(config).await.unwrap();
+
+        let result = publisher
+            .send(EventMessage {
+                event: Event::new("ping pong"),
+                subject: None,
+            })
+            .await
+            .unwrap();
+        publisher
+            .send(Shutdown {
+                timeout: Duration::from_secs(1),
+            })
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert!(result.is_err());
+        let letters = letters.lock().unwrap();
+        assert_eq!(1, letters.len());
+        assert_eq!("events.{event_type}", letters[0].subject);
+        assert_eq!("ping pong", letters[0].event["type"]);
+        assert_eq!(0, letters[0].attempts);
+        assert!(letters[0].first_attempt_ms <= letters[0].failed_ms);
+    }
+
+    #[cfg(feature = "legacy")]
+    #[actix_rt::test]
+    async fn should_replay_outbox_left_by_previous_run() {
+        let server = FakeNatsServer::start();
+        let connection = async_nats::connect(&server.address()).await.unwrap();
+        let subscription = connection.subscribe("outbox").await.unwrap();
+        connection.flush().await.unwrap();
+        let directory = tempfile::tempdir().unwrap();
+        let outbox_config = OutboxConfig {
+            directory: directory.path().to_owned(),
+            segment_size: 1024,
+            max_size: None,
+            max_age: None,
+        };
+        let mut outbox = Outbox::open(outbox_config.clone()).unwrap();
+        for event_type in ["first", "second", "third"] {
+            let event = Event::new(event_type).encode().unwrap();
+            outbox.append("outbox", &event, 0).unwrap();
+        }
+        drop(outbox);
+
+        let mut config = config(&server);
+        config.subject = "outbox".to_owned();
+        config.outbox = Some(outbox_config);
+        let publisher = NatsPublisher::<Event>::start_new(config).await.unwrap();
+        // The replayed events are not ordered with the new ones
+        while publisher
+            .send(GetStatus)
+            .await
+            .unwrap()
+            .outbox
+            .unwrap()
+            .entries
+            > 0
+        {
+            actix_rt::time::sleep(Duration::from_millis(10)).await;
+        }
+        publisher
+            .send(EventMessage {
+                event: Event::new("fourth"),
+                subject: None,
+            })
+            .await
+            .unwrap()
+            .unwrap();
+
+        for event_type in ["first", "second", "third", "fourth"] {
+            let message = subscription.next().await.unwrap();
+            assert_eq!(event_type, Event::decode(&message.data).unwrap().event_type);
+        }
+        publisher
+            .send(Shutdown {
+                timeout: Duration::from_secs(1),
+            })
+            .await
+            .unwrap()
+            .unwrap();
+        let status = publisher.send(GetStatus).await.unwrap();
+        assert_eq!(0, status.outbox.unwrap().entries);
+    }
 }
@@ -0,0 +1,223 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+use crate::InternalError;
+
+/// The number of per subject buckets above which the full ones are forgotten.
+const MAX_SUBJECT_BUCKETS: usize = 1024;
+
+/// A token bucket: `burst` events at once, refilled at `rate` events per second.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
+pub struct RateLimit {
+    pub rate: f64,
+    pub burst: u32,
+}
+
+/// What the `NatsPublisher` does with an event over the rate limit.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Overflow {
+    /// Delays the event until the limit allows it. At most `mailbox_size` events are delayed,
+    /// the next ones are rejected.
+    #[default]
+    Queue,
+    /// Gives up on the event as when its retries are exhausted: it fails with
+    /// `InternalError::RateLimited` and is sent to the dead-letter sink.
+    Shed,
+    /// Fails the event with `InternalError::RateLimited`, leaving it to the caller.
+    Reject,
+}
+
+///
+/// Limits the rate of the `EventMessage`s published, across all subjects and per subject.
+/// The retries and the `EventBatch`es are not limited.
+///
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Default)]
+#[serde(default)]
+pub struct RateLimitPolicy {
+    pub global: Option<RateLimit>,
+    /// The limit of each subject, unless overridden in `subjects`.
+    pub per_subject: Option<RateLimit>,
+    pub subjects: HashMap<String, RateLimit>,
+    pub overflow: Overflow,
+}
+
+impl RateLimitPolicy {
+    pub(crate) fn validate(&self) -> Result<(), InternalError> {
+        let limits = self
+            .global
+            .iter()
+            .chain(self.per_subject.iter())
+            .chain(self.subjects.values());
+        for limit in limits {
+            if !limit.rate.is_finite() || limit.rate <= 0.0 || limit.burst == 0 {
+                return Err(InternalError::ConfigurationError {
+                    cause: format! {"Invalid rate limit {:?}: rate and burst must be positive", limit},
+                });
+            }
+        }
+        Ok(())
+    }
+
+    fn is_enabled(&self) -> bool {
+        self.global.is_some() || self.per_subject.is_some() || !self.subjects.is_empty()
+    }
+}
+
+/// The events held back by the rate limits of a publisher, since it started.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RateLimitStats {
+    pub queued: u64,
+    pub shed: u64,
+    pub rejected: u64,
+    /// The number of events waiting for the limits.
+    pub waiting: usize,
+}
+
+struct TokenBucket {
+    limit: RateLimit,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn new(limit: RateLimit, now: Instant) -> TokenBucket {
+        TokenBucket {
+            limit,
+            tokens: limit.burst as f64,
+            updated: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.limit.rate).min(self.limit.burst as f64);
+        self.updated = now;
+    }
+
+    /// The time until a token is available.
+    fn wait_time(&mut self, now: Instant) -> Duration {
+        self.refill(now);
+        if self.tokens >= 1.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64((1.0 - self.tokens) / self.limit.rate)
+        }
+    }
+
+    fn is_full(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        self.tokens >= self.limit.burst as f64
+    }
+}
+
+/// The token buckets of a `RateLimitPolicy`.
+pub(crate) struct RateLimiter {
+    policy: RateLimitPolicy,
+    global: Option<TokenBucket>,
+    subjects: HashMap<String, TokenBucket>,
+    pub(crate) stats: RateLimitStats,
+}
+
+impl RateLimiter {
+    /// `None` when `policy` sets no limit.
+    pub(crate) fn new(policy: &RateLimitPolicy) -> Option<RateLimiter> {
+        policy.is_enabled().then(|| RateLimiter {
+            policy: policy.clone(),
+            global: policy
+                .global
+                .map(|limit| TokenBucket::new(limit, Instant::now())),
+            subjects: HashMap::new(),
+            stats: RateLimitStats::default(),
+        })
+    }
+
+    pub(crate) fn overflow(&self) -> Overflow {
+        self.policy.overflow
+    }
+
+    /// Takes a token from the buckets of `subject`, or tells how long to wait for one.
+    pub(crate) fn try_acquire(&mut self, subject: &str, now: Instant) -> Result<(), Duration> {
+        let limit = self
+            .policy
+            .subjects
+            .get(subject)
+            .or(self.policy.per_subject.as_ref())
+            .copied();
+        if limit.is_some()
+            && !self.subjects.contains_key(subject)
+            && self.subjects.len() >= MAX_SUBJECT_BUCKETS
+        {
+            self.subjects.retain(|_, bucket| !bucket.is_full(now));
+        }
+        let mut subject_bucket = limit.map(|limit| {
+            self.subjects
+                .entry(subject.to_owned())
+                .or_insert_with(|| TokenBucket::new(limit, now))
+        });
+        let wait = [self.global.as_mut(), subject_bucket.as_deref_mut()]
+            .into_iter()
+            .flatten()
+            .map(|bucket| bucket.wait_time(now))
+            .max()
+            .unwrap_or_default();
+        if !wait.is_zero() {
+            return Err(wait);
+        }
+        for bucket in [self.global.as_mut(), subject_bucket].into_iter().flatten() {
+            bucket.tokens -= 1.0;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::{RateLimit, RateLimitPolicy, RateLimiter};
+
+    #[test]
+    fn should_limit_globally_and_per_subject() {
+        let policy = RateLimitPolicy {
+            global: Some(RateLimit {
+                rate: 10.0,
+                burst: 3,
+            }),
+            per_subject: Some(RateLimit {
+                rate: 1.0,
+                burst: 2,
+            }),
+            ..Default::default()
+        };
+        let mut limiter = RateLimiter::new(&policy).unwrap();
+        let now = Instant::now();
+
+        assert_eq!(Ok(()), limiter.try_acquire("a", now));
+        assert_eq!(Ok(()), limiter.try_acquire("a", now));
+        assert_eq!(Err(Duration::from_secs(1)), limiter.try_acquire("a", now));
+        assert_eq!(Ok(()), limiter.try_acquire("b", now));
+        assert_eq!(
+            Err(Duration::from_millis(100)),
+            limiter.try_acquire("b", now)
+        );
+        let later = now + Duration::from_millis(100);
+        assert_eq!(Ok(()), limiter.try_acquire("b", later));
+        assert!(RateLimiter::new(&RateLimitPolicy::default()).is_none());
+    }
+
+    #[test]
+    fn should_reject_invalid_limits() {
+        let policy = RateLimitPolicy {
+            global: Some(RateLimit {
+                rate: 0.0,
+                burst: 1,
+            }),
+            ..Default::default()
+        };
+
+        assert!(policy.validate().is_err());
+    }
+}
@@ -0,0 +1,178 @@
+use std::collections::HashMap;
+use std::ops::Deref;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use async_nats::Connection;
+use log::*;
+use once_cell::sync::Lazy;
+
+use crate::connection_event::{ConnectionEvent, ConnectionEventListeners};
+use crate::{connect_with_retry_and_listeners, InternalError, NatsClientSettings};
+
+static GLOBAL_REGISTRY: Lazy<NatsConnectionRegistry> = Lazy::new(NatsConnectionRegistry::default);
+
+///
+/// Shares one NATS connection among all the publishers and subscribers created with
+/// the same `NatsClientSettings`.
+/// The connection is opened on the first `acquire` and dropped when the last `SharedConnection`
+/// is released. A connection closed by the client (e.g. after `max_reconnects`) is replaced
+/// on the next `acquire`.
+///
+#[derive(Clone, Default)]
+pub struct NatsConnectionRegistry {
+    entries: Arc<Mutex<HashMap<String, Arc<RegistryEntry>>>>,
+}
+
+struct RegistryEntry {
+    connecting: tokio::sync::Mutex<()>,
+    state: Mutex<EntryState>,
+    closed: Arc<AtomicBool>,
+    listeners: ConnectionEventListeners,
+}
+
+#[derive(Default)]
+struct EntryState {
+    connection: Option<Connection>,
+    references: usize,
+}
+
+impl NatsConnectionRegistry {
+    /// The registry shared by the whole process.
+    pub fn global() -> &'static NatsConnectionRegistry {
+        &GLOBAL_REGISTRY
+    }
+
+    /// Returns the connection for the given settings, connecting (with retry) if needed.
+    pub async fn acquire(
+        &self,
+        settings: &NatsClientSettings,
+    ) -> Result<SharedConnection, InternalError> {
+        let entry = self.entry(settings)?;
+        let _connecting = entry.connecting.lock().await;
+
+        {
+            let mut state = entry.state.lock().unwrap();
+            if entry.closed.swap(false, Ordering::SeqCst) {
+                debug!(
+                    "Discarding closed NATS connection to {:?}",
+                    settings.addresses
+                );
+                state.connection = None;
+            }
+            if let Some(connection) = state.connection.clone() {
+                state.references += 1;
+                return Ok(SharedConnection {
+                    connection,
+                    entry: entry.clone(),
+                });
+            }
+        }
+
+        let connection = connect_with_retry_and_listeners(settings, &entry.listeners).await?;
+
+        let mut state = entry.state.lock().unwrap();
+        state.connection = Some(connection.clone());
+        state.references += 1;
+        Ok(SharedConnection {
+            connection,
+            entry: entry.clone(),
+        })
+    }
+
+    /// The listeners notified of the lifecycle of the connection for the given settings.
+    pub fn listeners(
+        &self,
+        settings: &NatsClientSettings,
+    ) -> Result<ConnectionEventListeners, InternalError> {
+        Ok(self.entry(settings)?.listeners.clone())
+    }
+
+    /// The number of `SharedConnection`s currently held for the given settings.
+    pub fn references(&self, settings: &NatsClientSettings) -> Result<usize, InternalError> {
+        Ok(self.entry(settings)?.state.lock().unwrap().references)
+    }
+
+    fn entry(&self, settings: &NatsClientSettings) -> Result<Arc<RegistryEntry>, InternalError> {
+        // NatsClientSettings holds floats, so its serialized form is used as key
+        let key = serde_json::to_string(settings).map_err(|err| InternalError::SerdeError {
+            cause: format! {"{}", err},
+        })?;
+
+        let mut entries = self.entries.lock().unwrap();
+        let entry = entries.entry(key).or_insert_with(|| {
+            let closed = Arc::new(AtomicBool::new(false));
+            let listeners = ConnectionEventListeners::default();
+            let on_close = closed.clone();
+            listeners.register_callback(move |event| {
+                if let ConnectionEvent::Closed = event {
+                    on_close.store(true, Ordering::SeqCst);
+                }
+            });
+            Arc::new(RegistryEntry {
+                connecting: tokio::sync::Mutex::new(()),
+                state: Mutex::new(EntryState::default()),
+                closed,
+                listeners,
+            })
+        });
+        Ok(entry.clone())
+    }
+}
+
+///
+/// A connection borrowed from the `NatsConnectionRegistry`, released when dropped.
+///
+pub struct SharedConnection {
+    connection: Connection,
+    entry: Arc<RegistryEntry>,
+}
+
+impl Deref for SharedConnection {
+    type Target = Connection;
+
+    fn deref(&self) -> &Connection {
+        &self.connection
+    }
+}
+
+impl Drop for SharedConnection {
+    fn drop(&mut self) {
+        let mut state = self.entry.state.lock().unwrap();
+        state.references -= 1;
+        if state.references == 0 {
+            debug!("Last reference released, dropping NATS connection");
+            state.connection = None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serial_test::serial;
+
+    use super::NatsConnectionRegistry;
+    use crate::test_support::FakeNatsServer;
+    use crate::NatsClientSettings;
+
+    #[actix_rt::test]
+    #[serial]
+    async fn should_share_one_connection_per_settings() {
+        let server = FakeNatsServer::start();
+        let registry = NatsConnectionRegistry::default();
+        let settings = NatsClientSettings {
+            max_reconnects: Some(5),
+            ..server.client_settings()
+        };
+
+        let first = registry.acquire(&settings).await.unwrap();
+        let second = registry.acquire(&settings).await.unwrap();
+        assert_eq!(first.client_id(), second.client_id());
+        assert_eq!(2, registry.references(&settings).unwrap());
+        assert_eq!(1, server.connections());
+
+        drop(first);
+        drop(second);
+        assert_eq!(0, registry.references(&settings).unwrap());
+    }
+}
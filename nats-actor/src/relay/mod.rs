@@ -0,0 +1,343 @@
+use std::marker::PhantomData;
+use std::sync::Arc;
+use std::time::Duration;
+
+use actix::prelude::*;
+use log::*;
+use serde::{Deserialize, Serialize};
+
+use crate::dead_letter::{now_ms, DeadLetter, DeadLetterSink};
+use crate::shutdown::Shutdown;
+use crate::{AckedEvent, Envelope, EventMessage, InternalError};
+
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
+
+/// An event recorded in an `OutboxStore`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OutboxRecord {
+    pub id: u64,
+    /// Overrides the subject of the publisher, see `EventMessage.subject`.
+    pub subject: Option<String>,
+    /// The event, encoded by its `Envelope`.
+    pub payload: Vec<u8>,
+    pub created_ms: u64,
+}
+
+///
+/// The events written by a service to its own database, in the transaction of the changes
+/// they announce. The `OutboxRelay` publishes them once committed.
+/// The methods are called from the thread of the relay, so they are expected to be quick.
+///
+pub trait OutboxStore: Send + Sync + 'static {
+    /// The transaction of the caller the events are inserted in.
+    type Transaction<'t>;
+
+    /// Inserts an event in `transaction`. Returns its id.
+    fn insert_record(
+        &self,
+        transaction: &Self::Transaction<'_>,
+        subject: Option<&str>,
+        payload: &[u8],
+        created_ms: u64,
+    ) -> Result<u64, InternalError>;
+
+    /// Up to `limit` committed events not sent yet, in insertion order.
+    fn fetch_unsent(&self, limit: usize) -> Result<Vec<OutboxRecord>, InternalError>;
+
+    fn mark_sent(&self, ids: &[u64]) -> Result<(), InternalError>;
+
+    /// Inserts the event of `msg` in `transaction`. Returns its id.
+    fn insert<E: Envelope>(
+        &self,
+        transaction: &Self::Transaction<'_>,
+        msg: &EventMessage<E>,
+    ) -> Result<u64, InternalError> {
+        let payload = msg.event.encode()?;
+        self.insert_record(transaction, msg.subject.as_deref(), &payload, now_ms())
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct OutboxRelayConfig {
+    /// The time waited before polling the store again once all its events are sent.
+    pub poll_interval: Duration,
+    /// The maximum number of events fetched per poll.
+    pub batch_size: usize,
+    /// Receives the events that cannot be decoded, which are then marked as sent. Without it,
+    /// they are left unsent and block the relay. `DeadLetterSink::Subject` is not supported,
+    /// the relay has no connection of its own.
+    #[serde(default)]
+    pub dead_letter: Option<DeadLetterSink>,
+}
+
+///
+/// Publishes the events of an `OutboxStore` in order through a `NatsPublisher`, and marks them
+/// as sent once flushed to the server, see `AckedEvent`. An event may be published twice if
+/// the relay stops in between, never lost.
+///
+pub struct OutboxRelay<S: OutboxStore, E: Envelope> {
+    config: OutboxRelayConfig,
+    store: Arc<S>,
+    publisher: Recipient<AckedEvent<E>>,
+    envelope: PhantomData<E>,
+}
+
+impl<S: OutboxStore, E: Envelope> OutboxRelay<S, E> {
+    pub fn start(
+        config: OutboxRelayConfig,
+        store: Arc<S>,
+        publisher: Recipient<AckedEvent<E>>,
+    ) -> Addr<OutboxRelay<S, E>> {
+        OutboxRelay {
+            config,
+            store,
+            publisher,
+            envelope: PhantomData,
+        }
+        .start()
+    }
+
+    fn poll(&mut self, ctx: &mut Context<Self>) {
+        let records = match self.store.fetch_unsent(self.config.batch_size) {
+            Ok(records) => records,
+            Err(err) => {
+                error!("OutboxRelay cannot fetch the unsent events. Err: {}", err);
+                ctx.run_later(self.config.poll_interval, |act, ctx| act.poll(ctx));
+                return;
+            }
+        };
+        let full_batch = !records.is_empty() && records.len() == self.config.batch_size;
+        let publisher = self.publisher.clone();
+        let dead_letter = self.config.dead_letter.clone();
+
+        // Waits for the batch before handling another message, such as `Shutdown`
+        ctx.wait(
+            async move {
+                let mut sent = vec![];
+                for record in records {
+                    let event = match E::decode(&record.payload) {
+                        Ok(event) => event,
+                        Err(err) => match rejected(&record, err, dead_letter.as_ref()).await {
+                            Ok(()) => {
+                                sent.push(record.id);
+                                continue;
+                            }
+                            Err(err) => {
+                                error!(
+                                    "OutboxRelay cannot send event {} that cannot be decoded, leaving it unsent. Payload: {:?}. Err: {}",
+                                    record.id,
+                                    String::from_utf8_lossy(&record.payload),
+                                    err
+                                );
+                                return (sent, false);
+                            }
+                        },
+                    };
+                    let msg = AckedEvent(EventMessage {
+                        event,
+                        subject: record.subject,
+                    });
+                    let result = publisher.send(msg).await.unwrap_or_else(|err| {
+                        Err(InternalError::NatsOperationError {
+                            cause: format! {"NatsPublisher not reachable. Err: {}", err},
+                        })
+                    });
+                    match result {
+                        Ok(()) => sent.push(record.id),
+                        Err(err) => {
+                            warn!(
+                                "OutboxRelay cannot publish event {}, retrying later. Err: {}",
+                                record.id, err
+                            );
+                            return (sent, false);
+                        }
+                    }
+                }
+                (sent, full_batch)
+            }
+            .into_actor(self)
+            .map(|(sent, more), act, ctx| {
+                if !sent.is_empty() {
+                    debug!("OutboxRelay sent {} event(s)", sent.len());
+                    if let Err(err) = act.store.mark_sent(&sent) {
+                        error!(
+                            "OutboxRelay cannot mark events {:?} as sent, they will be sent again. Err: {}",
+                            sent, err
+                        );
+                    }
+                }
+                let delay = if more {
+                    Duration::ZERO
+                } else {
+                    act.config.poll_interval
+                };
+                ctx.run_later(delay, |act, ctx| act.poll(ctx));
+            }),
+        );
+    }
+}
+
+/// Sends the undecodable `record` to the `dead_letter` sink. Fails with `err` without sink.
+async fn rejected(
+    record: &OutboxRecord,
+    err: InternalError,
+    dead_letter: Option<&DeadLetterSink>,
+) -> Result<(), InternalError> {
+    let sink = dead_letter.ok_or_else(|| err.clone())?;
+    let letter = DeadLetter {
+        first_attempt_ms: record.created_ms,
+        ..DeadLetter::rejected(
+            record.subject.clone().unwrap_or_default(),
+            &record.payload,
+            &err,
+        )
+    };
+    warn!(
+        "OutboxRelay sending event {} that cannot be decoded to the dead-letter sink. Err: {}",
+        record.id, err
+    );
+    sink.send(letter, None).await
+}
+
+impl<S: OutboxStore, E: Envelope> Actor for OutboxRelay<S, E> {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        info!("OutboxRelay started");
+        self.poll(ctx);
+    }
+}
+
+/// Stops the relay once the batch being published is done. It is meant to be registered in the
+/// `GracefulShutdown` before the publisher.
+impl<S: OutboxStore, E: Envelope> Handler<Shutdown> for OutboxRelay<S, E> {
+    type Result = Result<(), InternalError>;
+
+    fn handle(&mut self, _: Shutdown, ctx: &mut Context<Self>) -> Self::Result {
+        info!("OutboxRelay shut down");
+        ctx.stop();
+        Ok(())
+    }
+}
+
+#[cfg(all(test, feature = "legacy"))]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use super::{OutboxRecord, OutboxRelay, OutboxRelayConfig, OutboxStore};
+    use crate::InternalError;
+
+    /// Commits the events on insertion.
+    #[derive(Default)]
+    struct MemoryStore {
+        records: Mutex<Vec<(OutboxRecord, bool)>>,
+    }
+
+    impl OutboxStore for MemoryStore {
+        type Transaction<'t> = ();
+
+        fn insert_record(
+            &self,
+            _: &(),
+            subject: Option<&str>,
+            payload: &[u8],
+            created_ms: u64,
+        ) -> Result<u64, InternalError> {
+            let mut records = self.records.lock().unwrap();
+            let id = records.len() as u64;
+            let record = OutboxRecord {
+                id,
+                subject: subject.map(str::to_owned),
+                payload: payload.to_vec(),
+                created_ms,
+            };
+            records.push((record, false));
+            Ok(id)
+        }
+
+        fn fetch_unsent(&self, limit: usize) -> Result<Vec<OutboxRecord>, InternalError> {
+            let records = self.records.lock().unwrap();
+            Ok(records
+                .iter()
+                .filter(|(_, sent)| !sent)
+                .map(|(record, _)| record.clone())
+                .take(limit)
+                .collect())
+        }
+
+        fn mark_sent(&self, ids: &[u64]) -> Result<(), InternalError> {
+            let mut records = self.records.lock().unwrap();
+            for id in ids {
+                records[*id as usize].1 = true;
+            }
+            Ok(())
+        }
+    }
+
+    #[actix_rt::test]
+    async fn should_publish_stored_events_in_order() {
+        use crate::dead_letter::{DeadLetterCallback, DeadLetterSink};
+        use crate::publisher::{NatsPublisher, NatsPublisherConfig};
+        use crate::test_support::FakeNatsServer;
+        use crate::{Envelope, Event, EventMessage};
+
+        let server = FakeNatsServer::start();
+        let connection = async_nats::connect(&server.address()).await.unwrap();
+        let subscription = connection.subscribe("relay.>").await.unwrap();
+        connection.flush().await.unwrap();
+        // Flushed by the relay whatever the `AckMode`
+        let publisher = NatsPublisher::<Event>::start_new(NatsPublisherConfig {
+            client_settings: server.client_settings(),
+            subject: "relay.{event_type}".to_owned(),
+            mailbox_size: 10,
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+        let store = Arc::new(MemoryStore::default());
+        for i in 0..5 {
+            let msg = EventMessage {
+                event: Event::new(format!("event_{}", i)),
+                subject: None,
+            };
+            store.insert(&(), &msg).unwrap();
+            if i == 2 {
+                store.insert_record(&(), None, b"not an event", 0).unwrap();
+            }
+        }
+        let dead_letters = Arc::new(Mutex::new(vec![]));
+        let received = dead_letters.clone();
+
+        OutboxRelay::start(
+            OutboxRelayConfig {
+                poll_interval: Duration::from_millis(10),
+                batch_size: 2,
+                dead_letter: Some(DeadLetterSink::Callback(DeadLetterCallback::new(
+                    move |letter| {
+                        received.lock().unwrap().push(letter);
+                        Ok(())
+                    },
+                ))),
+            },
+            store.clone(),
+            publisher.recipient(),
+        );
+
+        for i in 0..5 {
+            let message = subscription.next().await.unwrap();
+            assert_eq!(format!("relay.event_{}", i), message.subject);
+            assert_eq!(
+                format!("event_{}", i),
+                Event::decode(&message.data).unwrap().event_type
+            );
+        }
+        while !store.fetch_unsent(10).unwrap().is_empty() {
+            actix_rt::time::sleep(Duration::from_millis(10)).await;
+        }
+        let dead_letters = dead_letters.lock().unwrap();
+        assert_eq!(1, dead_letters.len());
+        assert_eq!("not an event", dead_letters[0].event);
+    }
+}
@@ -0,0 +1,179 @@
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use rusqlite::{params, Connection};
+
+use crate::dead_letter::now_ms;
+use crate::relay::{OutboxRecord, OutboxStore};
+use crate::InternalError;
+
+pub const DEFAULT_TABLE: &str = "nats_outbox";
+
+///
+/// An `OutboxStore` in a table of a SQLite database. The events are inserted in the
+/// transactions of the service, and read by the relay through its own connection.
+///
+pub struct SqliteOutboxStore {
+    connection: Mutex<Connection>,
+    table: String,
+}
+
+impl SqliteOutboxStore {
+    /// Opens the database at `path` for the relay, creating the `nats_outbox` table if missing.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<SqliteOutboxStore, InternalError> {
+        SqliteOutboxStore::open_with_table(path, DEFAULT_TABLE)
+    }
+
+    pub fn open_with_table<P: AsRef<Path>>(
+        path: P,
+        table: &str,
+    ) -> Result<SqliteOutboxStore, InternalError> {
+        if table.is_empty() || !table.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            return Err(InternalError::ConfigurationError {
+                cause: format! {"Invalid outbox table name [{}]", table},
+            });
+        }
+        let connection = Connection::open(path).map_err(sqlite_error)?;
+        connection
+            .busy_timeout(Duration::from_secs(5))
+            .map_err(sqlite_error)?;
+        // Lets the service write while the relay reads
+        connection
+            .pragma_update(None, "journal_mode", "WAL")
+            .map_err(sqlite_error)?;
+        SqliteOutboxStore::create_table(&connection, table)?;
+        Ok(SqliteOutboxStore {
+            connection: Mutex::new(connection),
+            table: table.to_owned(),
+        })
+    }
+
+    /// Creates the outbox `table` in the database of `connection` if missing.
+    pub fn create_table(connection: &Connection, table: &str) -> Result<(), InternalError> {
+        connection
+            .execute_batch(&format!(
+                "CREATE TABLE IF NOT EXISTS {table} (
+                    id INTEGER PRIMARY KEY AUTOINCREMENT,
+                    subject TEXT,
+                    payload BLOB NOT NULL,
+                    created_ms INTEGER NOT NULL,
+                    sent_ms INTEGER
+                );
+                CREATE INDEX IF NOT EXISTS {table}_unsent ON {table} (id) WHERE sent_ms IS NULL;"
+            ))
+            .map_err(sqlite_error)
+    }
+}
+
+impl OutboxStore for SqliteOutboxStore {
+    type Transaction<'t> = rusqlite::Transaction<'t>;
+
+    fn insert_record(
+        &self,
+        transaction: &rusqlite::Transaction<'_>,
+        subject: Option<&str>,
+        payload: &[u8],
+        created_ms: u64,
+    ) -> Result<u64, InternalError> {
+        transaction
+            .execute(
+                &format!(
+                    "INSERT INTO {} (subject, payload, created_ms) VALUES (?1, ?2, ?3)",
+                    self.table
+                ),
+                params![subject, payload, created_ms as i64],
+            )
+            .map_err(sqlite_error)?;
+        Ok(transaction.last_insert_rowid() as u64)
+    }
+
+    fn fetch_unsent(&self, limit: usize) -> Result<Vec<OutboxRecord>, InternalError> {
+        let connection = self.connection.lock().expect("SQLite connection lock");
+        let mut statement = connection
+            .prepare_cached(&format!(
+                "SELECT id, subject, payload, created_ms FROM {} WHERE sent_ms IS NULL ORDER BY id LIMIT ?1",
+                self.table
+            ))
+            .map_err(sqlite_error)?;
+        let records = statement
+            .query_map(params![limit as i64], |row| {
+                Ok(OutboxRecord {
+                    id: row.get::<_, i64>(0)? as u64,
+                    subject: row.get(1)?,
+                    payload: row.get(2)?,
+                    created_ms: row.get::<_, i64>(3)? as u64,
+                })
+            })
+            .map_err(sqlite_error)?;
+        records.collect::<Result<_, _>>().map_err(sqlite_error)
+    }
+
+    fn mark_sent(&self, ids: &[u64]) -> Result<(), InternalError> {
+        let mut connection = self.connection.lock().expect("SQLite connection lock");
+        let transaction = connection.transaction().map_err(sqlite_error)?;
+        {
+            let mut statement = transaction
+                .prepare_cached(&format!(
+                    "UPDATE {} SET sent_ms = ?1 WHERE id = ?2",
+                    self.table
+                ))
+                .map_err(sqlite_error)?;
+            let sent_ms = now_ms() as i64;
+            for id in ids {
+                statement
+                    .execute(params![sent_ms, *id as i64])
+                    .map_err(sqlite_error)?;
+            }
+        }
+        transaction.commit().map_err(sqlite_error)
+    }
+}
+
+fn sqlite_error(err: rusqlite::Error) -> InternalError {
+    InternalError::OutboxError {
+        cause: format! {"SQLite: {}", err},
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rusqlite::Connection;
+
+    use super::SqliteOutboxStore;
+    use crate::relay::OutboxStore;
+
+    #[test]
+    fn should_only_fetch_committed_unsent_events() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("service.db");
+        let store = SqliteOutboxStore::open(&path).unwrap();
+        let mut connection = Connection::open(&path).unwrap();
+
+        let transaction = connection.transaction().unwrap();
+        store
+            .insert_record(&transaction, None, b"rolled back", 1)
+            .unwrap();
+        transaction.rollback().unwrap();
+        let transaction = connection.transaction().unwrap();
+        let first = store
+            .insert_record(&transaction, Some("events.first"), b"first", 2)
+            .unwrap();
+        let second = store
+            .insert_record(&transaction, None, b"second", 3)
+            .unwrap();
+        transaction.commit().unwrap();
+
+        let records = store.fetch_unsent(10).unwrap();
+        assert_eq!(2, records.len());
+        assert_eq!(first, records[0].id);
+        assert_eq!(Some("events.first".to_owned()), records[0].subject);
+        assert_eq!(b"second".to_vec(), records[1].payload);
+
+        store.mark_sent(&[first]).unwrap();
+        let records = store.fetch_unsent(10).unwrap();
+        assert_eq!(1, records.len());
+        assert_eq!(second, records[0].id);
+        assert!(SqliteOutboxStore::open_with_table(&path, "outbox; DROP TABLE x").is_err());
+    }
+}
@@ -0,0 +1,506 @@
+use std::marker::PhantomData;
+use std::rc::Rc;
+use std::time::Duration;
+
+use actix::prelude::*;
+use async_nats::{Connection, Headers};
+use log::*;
+use serde::{Deserialize, Serialize};
+use tokio::time;
+use tracing_futures::Instrument;
+
+use crate::chunking::{Reassembler, ReassemblyConfig};
+use crate::compression::CompressionConfig;
+use crate::connection_event::ConnectionEvent;
+use crate::health::{ConnectionState, GetStatus, NatsStatus, StatusTracker};
+use crate::publisher::Sealer;
+use crate::registry::{NatsConnectionRegistry, SharedConnection};
+use crate::shutdown::{InFlight, Shutdown};
+use crate::signing::{RotateSigningKey, Signer, SigningKey};
+use crate::subject::SubjectTemplate;
+use crate::subscriber::{service_error, unseal, Undelivered};
+use crate::{flush, ContentMode, Envelope, InternalError, NatsClientSettings};
+
+///
+/// Publishes `event` with a unique reply inbox, and resolves with the reply decoded as an event
+/// of the same `Envelope` format. Fails with `InternalError::RequestTimeout` when no reply is
+/// received within `timeout`, and with `InternalError::ResponderError` when the responder failed.
+///
+#[derive(Message, Debug)]
+#[rtype(result = "Result<E, InternalError>")]
+pub struct NatsRequest<E: Envelope> {
+    pub event: E,
+    pub timeout: Duration,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct NatsRequesterConfig {
+    pub client_settings: NatsClientSettings,
+    /// The subject of the requests, possibly a `SubjectTemplate` such as `requests.{type}`.
+    pub subject: String,
+    pub mailbox_size: usize,
+    #[serde(default)]
+    pub content_mode: ContentMode,
+    /// Compresses the large requests, as `NatsPublisherConfig.compression`.
+    #[serde(default)]
+    pub compression: Option<CompressionConfig>,
+    /// Signs the requests, for the responders to verify them. See `RotateSigningKey`.
+    #[serde(default)]
+    pub signing: Option<SigningKey>,
+}
+
+///
+/// Sends `NatsRequest`s to the responders subscribed with `subscribe_responder`.
+/// The requests are compressed and signed like the events of a `NatsPublisher`, the replies
+/// reassembled and decompressed like the messages of a subscriber.
+/// The requests are not retried: the caller decides what to do on a timeout.
+///
+pub struct NatsRequester<E: Envelope> {
+    subject: SubjectTemplate,
+    content_mode: ContentMode,
+    sealer: Sealer,
+    // The client must live as long as the actor, otherwise the connection is dropped when the client is deallocated
+    client: SharedConnection,
+    status: StatusTracker,
+    in_flight: InFlight,
+    shutting_down: bool,
+    envelope: PhantomData<E>,
+}
+
+impl<E: Envelope> NatsRequester<E> {
+    pub async fn start_new(
+        config: NatsRequesterConfig,
+    ) -> Result<Addr<NatsRequester<E>>, InternalError> {
+        NatsRequester::start_with_registry(config, NatsConnectionRegistry::global()).await
+    }
+
+    /// Starts a requester borrowing its connection from `registry`.
+    pub async fn start_with_registry(
+        config: NatsRequesterConfig,
+        registry: &NatsConnectionRegistry,
+    ) -> Result<Addr<NatsRequester<E>>, InternalError> {
+        let subject = SubjectTemplate::parse(&config.subject)?;
+        let signer = match &config.signing {
+            Some(key) => Some(Rc::new(Signer::new(key)?)),
+            None => None,
+        };
+        let listeners = registry.listeners(&config.client_settings)?;
+        let client = registry.acquire(&config.client_settings).await?;
+        Ok(NatsRequester::create(|ctx| {
+            ctx.set_mailbox_capacity(config.mailbox_size);
+            listeners.register(ctx.address().recipient());
+            NatsRequester {
+                subject,
+                content_mode: config.content_mode,
+                sealer: Sealer {
+                    deduplicated: false,
+                    compression: config.compression,
+                    signer,
+                },
+                client,
+                status: StatusTracker::new(ConnectionState::Connected),
+                in_flight: InFlight::default(),
+                shutting_down: false,
+                envelope: PhantomData,
+            }
+        }))
+    }
+}
+
+impl<E: Envelope> Actor for NatsRequester<E> {
+    type Context = Context<Self>;
+}
+
+impl<E: Envelope> Handler<NatsRequest<E>> for NatsRequester<E> {
+    type Result = ResponseFuture<Result<E, InternalError>>;
+
+    fn handle(&mut self, msg: NatsRequest<E>, _: &mut Context<Self>) -> Self::Result {
+        if self.shutting_down {
+            return Box::pin(async { Err(InternalError::ShuttingDown) });
+        }
+        let trace_id = msg.event.trace_id();
+        let span = tracing::error_span!("NatsRequester", trace_id);
+        let prepared = self.subject.render(&msg.event).and_then(|subject| {
+            let (headers, payload) = msg.event.encode_as(self.content_mode)?;
+            let (headers, payload) = self.sealer.seal(&subject, None, headers, payload)?;
+            Ok((subject, headers, payload))
+        });
+        let (subject, headers, payload) = match prepared {
+            Ok(prepared) => prepared,
+            Err(err) => {
+                error!("NatsRequester cannot send request. Err: {}", err);
+                return Box::pin(async { Err(err) });
+            }
+        };
+
+        let client = Connection::clone(&self.client);
+        let status = self.status.clone();
+        let in_flight = self.in_flight.clone();
+        in_flight.start();
+        Box::pin(
+            async move {
+                debug!("NatsRequester sending request to [{}]", subject);
+                let result = request(&client, subject, headers, payload, msg.timeout).await;
+                if let Err(err @ InternalError::NatsOperationError { .. }) = &result {
+                    status.record_error(err);
+                }
+                in_flight.finish();
+                result
+            }
+            .instrument(span),
+        )
+    }
+}
+
+///
+/// Publishes the request with a reply inbox subscribed to beforehand, and waits for one reply,
+/// all its chunks when chunked.
+///
+async fn request<E: Envelope>(
+    client: &Connection,
+    subject: String,
+    headers: Option<Headers>,
+    payload: Vec<u8>,
+    timeout: Duration,
+) -> Result<E, InternalError> {
+    let inbox = client.new_inbox();
+    let subscription =
+        client
+            .subscribe(&inbox)
+            .await
+            .map_err(|err| InternalError::NatsOperationError {
+                cause: format! {"Cannot subscribe to reply inbox. Err: {}", err},
+            })?;
+    let reply = async {
+        client
+            .publish_with_reply_or_headers(&subject, Some(&inbox), headers.as_ref(), &payload)
+            .await
+            .map_err(|err| InternalError::NatsOperationError {
+                cause: format! {"Cannot publish request. Err: {}", err},
+            })?;
+        let mut reassembler = Reassembler::new(ReassemblyConfig::default());
+        while let Some(mut reply) = subscription.next().await {
+            match unseal(&mut reply, &mut reassembler, None) {
+                Ok(()) => return Ok(Some(reply)),
+                Err(Undelivered::Pending) => continue,
+                Err(Undelivered::Rejected(err) | Undelivered::Failed(err)) => return Err(err),
+            }
+        }
+        Ok(None)
+    };
+    let reply = time::timeout(timeout, reply).await;
+    if let Err(err) = subscription.unsubscribe().await {
+        warn!(
+            "NatsRequester cannot unsubscribe from reply inbox. Err: {}",
+            err
+        );
+    }
+    match reply {
+        Err(_) => Err(InternalError::RequestTimeout { subject, timeout }),
+        Ok(Err(err)) => Err(err),
+        Ok(Ok(None)) => Err(InternalError::NatsOperationError {
+            cause: "Reply inbox closed before the reply".to_owned(),
+        }),
+        Ok(Ok(Some(reply))) => match service_error(reply.headers.as_ref()) {
+            Some(err) => Err(err),
+            None => E::decode_message(reply.headers.as_ref(), &reply.data),
+        },
+    }
+}
+
+impl<E: Envelope> Handler<ConnectionEvent> for NatsRequester<E> {
+    type Result = ();
+
+    fn handle(&mut self, msg: ConnectionEvent, _: &mut Context<Self>) {
+        self.status.on_connection_event(&msg);
+    }
+}
+
+impl<E: Envelope> Handler<RotateSigningKey> for NatsRequester<E> {
+    type Result = Result<(), InternalError>;
+
+    fn handle(&mut self, msg: RotateSigningKey, _: &mut Context<Self>) -> Self::Result {
+        let signer = Signer::new(&msg.0)?;
+        info!("NatsRequester now signing with key [{}]", msg.0.id);
+        self.sealer.signer = Some(Rc::new(signer));
+        Ok(())
+    }
+}
+
+impl<E: Envelope> Handler<GetStatus> for NatsRequester<E> {
+    type Result = ResponseFuture<NatsStatus>;
+
+    fn handle(&mut self, _: GetStatus, _: &mut Context<Self>) -> Self::Result {
+        Box::pin(self.status.status(Some(Connection::clone(&self.client))))
+    }
+}
+
+/// Refuses new requests and waits for the replies of the pending ones.
+impl<E: Envelope> Handler<Shutdown> for NatsRequester<E> {
+    type Result = ResponseActFuture<Self, Result<(), InternalError>>;
+
+    fn handle(&mut self, msg: Shutdown, _: &mut Context<Self>) -> Self::Result {
+        info!(
+            "NatsRequester shutting down with {} request(s) pending",
+            self.in_flight.count()
+        );
+        self.shutting_down = true;
+        let in_flight = self.in_flight.clone();
+        let connection = Connection::clone(&self.client);
+        let timeout = msg.timeout;
+        Box::pin(
+            async move {
+                let idle = time::timeout(timeout, in_flight.idle()).await.map_err(|_| {
+                    InternalError::NatsOperationError {
+                        cause: format! {"{} request(s) still pending after {:?}", in_flight.count(), timeout},
+                    }
+                });
+                flush(&connection, timeout).await?;
+                idle
+            }
+            .into_actor(self)
+            .map(|result, act, ctx| {
+                act.status.set_state(ConnectionState::Closed);
+                info!("NatsRequester shut down");
+                // Stopping the actor releases the connection
+                ctx.stop();
+                result
+            }),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{NatsRequest, NatsRequester, NatsRequesterConfig};
+    use crate::test_support::FakeNatsServer;
+    #[cfg(feature = "legacy")]
+    use crate::InternalError;
+
+    fn config(server: &FakeNatsServer, subject: &str) -> NatsRequesterConfig {
+        NatsRequesterConfig {
+            client_settings: server.client_settings(),
+            subject: subject.to_owned(),
+            mailbox_size: 10,
+            content_mode: Default::default(),
+            compression: None,
+            signing: None,
+        }
+    }
+
+    #[cfg(feature = "legacy")]
+    #[actix_rt::test]
+    async fn should_resolve_with_reply_of_responder() {
+        use crate::subscriber::{subscribe_responder, NatsSubscriberConfig};
+        use crate::Event;
+
+        let server = FakeNatsServer::start();
+        let _responder = subscribe_responder(
+            NatsSubscriberConfig {
+                client_settings: server.client_settings(),
+                subject: "ping".to_owned(),
+                mailbox_size: 10,
+                verification: None,
+                reassembly: Default::default(),
+            },
+            |msg| {
+                let ping: Event = msg.event()?;
+                Ok(Event {
+                    trace_id: ping.trace_id,
+                    ..Event::new("pong")
+                })
+            },
+        )
+        .await
+        .unwrap();
+        let requester = NatsRequester::<Event>::start_new(config(&server, "ping"))
+            .await
+            .unwrap();
+        let ping = Event::new("ping");
+
+        let pong = requester
+            .send(NatsRequest {
+                event: ping.clone(),
+                timeout: Duration::from_secs(1),
+            })
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!("pong", pong.event_type);
+        assert_eq!(ping.trace_id, pong.trace_id);
+    }
+
+    #[cfg(feature = "legacy")]
+    #[actix_rt::test]
+    async fn should_seal_requests_for_verifying_responder() {
+        use crate::compression::{Codec, CompressionConfig};
+        use crate::signing::{Algorithm, SigningKey, VerificationConfig};
+        use crate::subscriber::{subscribe_responder, NatsSubscriberConfig};
+        use crate::Event;
+
+        let key = SigningKey {
+            id: "key-1".to_owned(),
+            algorithm: Algorithm::Ed25519,
+            secret: base64::encode([1; 32]),
+        };
+        let server = FakeNatsServer::start();
+        let _responder = subscribe_responder(
+            NatsSubscriberConfig {
+                client_settings: server.client_settings(),
+                subject: "ping".to_owned(),
+                mailbox_size: 10,
+                verification: Some(VerificationConfig {
+                    keys: vec![key.verifying_key().unwrap()],
+                    rejected: None,
+                }),
+                reassembly: Default::default(),
+            },
+            |msg| {
+                let ping: Event = msg.event()?;
+                Ok(Event {
+                    payload: ping.payload,
+                    ..Event::new("pong")
+                })
+            },
+        )
+        .await
+        .unwrap();
+        let mut config = config(&server, "ping");
+        config.compression = Some(CompressionConfig {
+            codec: Codec::Gzip,
+            threshold: 256,
+            level: None,
+        });
+        config.signing = Some(key);
+        let requester = NatsRequester::<Event>::start_new(config).await.unwrap();
+        let mut ping = Event::new("ping");
+        ping.payload
+            .insert("data".to_owned(), "ping ".repeat(100).into());
+
+        let pong = requester
+            .send(NatsRequest {
+                event: ping.clone(),
+                timeout: Duration::from_secs(1),
+            })
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!("pong", pong.event_type);
+        assert_eq!(ping.payload, pong.payload);
+    }
+
+    #[cfg(feature = "legacy")]
+    #[actix_rt::test]
+    async fn should_report_failure_of_responder() {
+        use crate::subscriber::{subscribe_responder, NatsSubscriberConfig};
+        use crate::Event;
+
+        let server = FakeNatsServer::start();
+        let _responder = subscribe_responder(
+            NatsSubscriberConfig {
+                client_settings: server.client_settings(),
+                subject: "ping".to_owned(),
+                mailbox_size: 10,
+                verification: None,
+                reassembly: Default::default(),
+            },
+            |_| -> Result<Event, InternalError> { Err(InternalError::ShuttingDown) },
+        )
+        .await
+        .unwrap();
+        let requester = NatsRequester::<Event>::start_new(config(&server, "ping"))
+            .await
+            .unwrap();
+
+        let result = requester
+            .send(NatsRequest {
+                event: Event::new("ping"),
+                timeout: Duration::from_secs(1),
+            })
+            .await
+            .unwrap();
+
+        match result {
+            Err(InternalError::ResponderError { code, cause }) => {
+                assert_eq!(503, code);
+                assert_eq!(InternalError::ShuttingDown.to_string(), cause);
+            }
+            other => panic!("Unexpected result {:?}", other),
+        }
+    }
+
+    #[cfg(feature = "cloudevents")]
+    #[actix_rt::test]
+    async fn should_answer_from_subscriber_callback() {
+        use cloudevents::{EventBuilder, EventBuilderV10};
+
+        use crate::subscriber::{subscribe, NatsSubscriberConfig};
+        use crate::ContentMode;
+
+        let server = FakeNatsServer::start();
+        let _subscriber = subscribe(
+            NatsSubscriberConfig {
+                client_settings: server.client_settings(),
+                subject: "echo".to_owned(),
+                mailbox_size: 10,
+                verification: None,
+                reassembly: Default::default(),
+            },
+            |msg| {
+                assert!(msg.reply_subject().is_some());
+                assert!(msg.headers().is_some());
+                msg.respond(&msg.cloud_event()?)
+            },
+        )
+        .await
+        .unwrap();
+        let mut config = config(&server, "echo");
+        config.content_mode = ContentMode::Binary;
+        let requester = NatsRequester::<cloudevents::Event>::start_new(config)
+            .await
+            .unwrap();
+        let event = EventBuilderV10::new()
+            .source("http://localhost")
+            .id("trace_id")
+            .ty("com.example.echo")
+            .data("application/json", serde_json::json!({"user": "Ram"}))
+            .build()
+            .unwrap();
+
+        let reply = requester
+            .send(NatsRequest {
+                event: event.clone(),
+                timeout: Duration::from_secs(1),
+            })
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(event, reply);
+    }
+
+    #[cfg(feature = "legacy")]
+    #[actix_rt::test]
+    async fn should_time_out_without_responder() {
+        use crate::Event;
+
+        let server = FakeNatsServer::start();
+        let requester = NatsRequester::<Event>::start_new(config(&server, "nobody"))
+            .await
+            .unwrap();
+
+        let result = requester
+            .send(NatsRequest {
+                event: Event::new("ping"),
+                timeout: Duration::from_millis(100),
+            })
+            .await
+            .unwrap();
+
+        assert!(matches!(result, Err(InternalError::RequestTimeout { .. })));
+    }
+}
@@ -0,0 +1,233 @@
+use std::cell::Cell;
+use std::rc::Rc;
+use std::time::Duration;
+
+use actix::prelude::*;
+use log::*;
+use prometheus::IntGauge;
+use tokio::sync::Notify;
+
+use crate::InternalError;
+
+///
+/// Asks a `NatsPublisher` or a `NatsSubscriber` to stop gracefully within `timeout`:
+/// the publisher refuses new events and waits for the in-flight ones, the subscriber drains
+/// its subscription and processes the buffered messages. Both then flush their connection
+/// and release it.
+///
+#[derive(Message, Debug, Clone)]
+#[rtype(result = "Result<(), InternalError>")]
+pub struct Shutdown {
+    pub timeout: Duration,
+}
+
+///
+/// Counts the operations started by an actor and not completed yet.
+///
+#[derive(Clone, Default)]
+pub(crate) struct InFlight {
+    count: Rc<Cell<usize>>,
+    idle: Rc<Notify>,
+    /// Follows `count`, when exposed as a metric.
+    gauge: Option<IntGauge>,
+}
+
+impl InFlight {
+    pub(crate) fn with_gauge(gauge: IntGauge) -> InFlight {
+        InFlight {
+            gauge: Some(gauge),
+            ..Default::default()
+        }
+    }
+
+    pub(crate) fn start(&self) {
+        self.count.set(self.count.get() + 1);
+        if let Some(gauge) = &self.gauge {
+            gauge.inc();
+        }
+    }
+
+    pub(crate) fn finish(&self) {
+        if let (Some(gauge), true) = (&self.gauge, self.count.get() > 0) {
+            gauge.dec();
+        }
+        self.count.set(self.count.get().saturating_sub(1));
+        if self.count.get() == 0 {
+            self.idle.notify_waiters();
+        }
+    }
+
+    pub(crate) fn count(&self) -> usize {
+        self.count.get()
+    }
+
+    /// Resolves once no operation is in flight.
+    pub(crate) async fn idle(&self) {
+        loop {
+            let notified = self.idle.notified();
+            if self.count.get() == 0 {
+                return;
+            }
+            notified.await;
+        }
+    }
+}
+
+///
+/// Shuts down a set of NATS actors when the application stops.
+/// The actors are shut down one after the other in registration order, so subscribers
+/// registered before publishers can still publish while draining.
+///
+#[derive(Clone)]
+pub struct GracefulShutdown {
+    timeout: Duration,
+    actors: Vec<(String, Recipient<Shutdown>)>,
+}
+
+impl GracefulShutdown {
+    /// `timeout` is the time given to each actor to complete its shutdown.
+    pub fn new(timeout: Duration) -> GracefulShutdown {
+        GracefulShutdown {
+            timeout,
+            actors: vec![],
+        }
+    }
+
+    pub fn with<S: Into<String>>(
+        mut self,
+        name: S,
+        actor: Recipient<Shutdown>,
+    ) -> GracefulShutdown {
+        self.actors.push((name.into(), actor));
+        self
+    }
+
+    /// Shuts down all the actors, even when some of them fail. Returns the last failure.
+    pub async fn shutdown(&self) -> Result<(), InternalError> {
+        let mut result = Ok(());
+        for (name, actor) in self.actors.iter() {
+            info!("Shutting down NATS actor [{}]", name);
+            let shutdown = Shutdown {
+                timeout: self.timeout,
+            };
+            match actor.send(shutdown).await {
+                Ok(Ok(())) => debug!("NATS actor [{}] shut down", name),
+                Ok(Err(err)) => {
+                    error!("NATS actor [{}] shutdown failed. Err: {}", name, err);
+                    result = Err(err);
+                }
+                Err(err) => warn!("NATS actor [{}] already stopped. Err: {}", name, err),
+            }
+        }
+        result
+    }
+
+    ///
+    /// Runs `server` until it stops, then shuts down the actors.
+    /// `HttpServer` stops on SIGINT/SIGTERM after completing the pending requests, so the
+    /// events published while serving them are not lost.
+    ///
+    pub async fn run(&self, server: actix_web::dev::Server) -> std::io::Result<()> {
+        let result = server.await;
+        if let Err(err) = self.shutdown().await {
+            warn!("NATS actors did not shut down gracefully. Err: {}", err);
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    #[cfg(feature = "legacy")]
+    use super::GracefulShutdown;
+    use super::Shutdown;
+    #[cfg(feature = "legacy")]
+    use crate::publisher::{NatsPublisher, NatsPublisherConfig};
+    use crate::subscriber::{subscribe, NatsSubscriberConfig};
+    use crate::test_support::FakeNatsServer;
+    #[cfg(feature = "legacy")]
+    use crate::{Event, EventMessage};
+
+    #[cfg(feature = "legacy")]
+    #[actix_rt::test]
+    async fn should_publish_in_flight_events_before_shutting_down() {
+        let server = FakeNatsServer::start();
+        let connection = async_nats::connect(&server.address()).await.unwrap();
+        let subscription = connection.subscribe("shutdown").await.unwrap();
+        connection.flush().await.unwrap();
+
+        let publisher = NatsPublisher::<Event>::start_new(NatsPublisherConfig::new(
+            server.client_settings(),
+            "shutdown",
+        ))
+        .await
+        .unwrap();
+
+        for _ in 0..10 {
+            publisher.do_send(EventMessage {
+                event: Event::new("event_type"),
+                subject: None,
+            });
+        }
+        GracefulShutdown::new(Duration::from_secs(5))
+            .with("publisher", publisher.clone().recipient())
+            .shutdown()
+            .await
+            .unwrap();
+
+        for _ in 0..10 {
+            assert!(subscription.next().await.is_some());
+        }
+        assert!(publisher
+            .send(EventMessage {
+                event: Event::new("event_type"),
+                subject: None,
+            })
+            .await
+            .unwrap()
+            .is_err());
+    }
+
+    #[actix_rt::test]
+    async fn should_process_buffered_messages_before_shutting_down() {
+        let server = FakeNatsServer::start();
+        let received = Arc::new(Mutex::new(0));
+
+        let counter = received.clone();
+        let subscriber = subscribe(
+            NatsSubscriberConfig {
+                client_settings: server.client_settings(),
+                subject: "shutdown".to_owned(),
+                mailbox_size: 100,
+                verification: None,
+                reassembly: Default::default(),
+            },
+            move |_| {
+                std::thread::sleep(Duration::from_millis(10));
+                *counter.lock().unwrap() += 1;
+                Ok(())
+            },
+        )
+        .await
+        .unwrap();
+
+        let connection = async_nats::connect(&server.address()).await.unwrap();
+        for _ in 0..20 {
+            connection.publish("shutdown", "message").await.unwrap();
+        }
+        connection.flush().await.unwrap();
+
+        subscriber
+            .send(Shutdown {
+                timeout: Duration::from_secs(5),
+            })
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(20, *received.lock().unwrap());
+    }
+}
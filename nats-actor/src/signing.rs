@@ -0,0 +1,361 @@
+use std::collections::HashMap;
+
+use actix::prelude::Message;
+use async_nats::Headers;
+use ring::hmac;
+use ring::signature::{Ed25519KeyPair, KeyPair, UnparsedPublicKey, ED25519};
+use serde::{Deserialize, Serialize};
+
+use crate::dead_letter::DeadLetterSink;
+use crate::InternalError;
+
+/// The header carrying the base64 signature of a message.
+pub const SIGNATURE_HEADER: &str = "Nats-Signature";
+/// The header carrying the id of the key a message is signed with.
+pub const SIGNATURE_KEY_HEADER: &str = "Nats-Signature-Key";
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Algorithm {
+    HmacSha256,
+    Ed25519,
+}
+
+///
+/// The key a `NatsPublisher` signs its messages with. The signature covers the subject, the
+/// headers and the payload as sent, so after the compression.
+///
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+pub struct SigningKey {
+    /// Sent with the signature, for the subscribers to pick the key to verify it with.
+    pub id: String,
+    pub algorithm: Algorithm,
+    /// Base64 encoded: the HMAC secret, or the 32 bytes seed of the Ed25519 key pair.
+    pub secret: String,
+}
+
+impl SigningKey {
+    /// The key verifying the signatures of this key.
+    pub fn verifying_key(&self) -> Result<VerifyingKey, InternalError> {
+        let key = match Signer::new(self)?.key {
+            SignerKey::Hmac(_) => self.secret.clone(),
+            SignerKey::Ed25519(key_pair) => base64::encode(key_pair.public_key()),
+        };
+        Ok(VerifyingKey {
+            id: self.id.clone(),
+            algorithm: self.algorithm,
+            key,
+        })
+    }
+}
+
+/// A key of a `KeyRing`.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+pub struct VerifyingKey {
+    pub id: String,
+    pub algorithm: Algorithm,
+    /// Base64 encoded: the HMAC secret, or the 32 bytes Ed25519 public key.
+    pub key: String,
+}
+
+///
+/// Makes a subscriber verify the signatures of its messages against `keys`. The messages
+/// unsigned, signed with an unknown key or with an invalid signature are not delivered: they
+/// are sent to `rejected` as dead letters, or only logged when not set.
+///
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct VerificationConfig {
+    pub keys: Vec<VerifyingKey>,
+    #[serde(default)]
+    pub rejected: Option<DeadLetterSink>,
+}
+
+///
+/// Replaces the signing key of a `NatsPublisher` or a `NatsRequester`.
+/// To rotate a key, add the new one to the key rings of the subscribers with
+/// `KeyRingUpdate::Add`, then send it to the publishers, and finally remove the old one.
+///
+#[derive(Message, Debug, Clone)]
+#[rtype(result = "Result<(), InternalError>")]
+pub struct RotateSigningKey(pub SigningKey);
+
+/// Changes the key ring of a subscriber verifying its messages.
+#[derive(Message, Debug, Clone)]
+#[rtype(result = "Result<(), InternalError>")]
+pub enum KeyRingUpdate {
+    /// Adds a key, or replaces the key with the same id.
+    Add(VerifyingKey),
+    Remove {
+        id: String,
+    },
+}
+
+enum SignerKey {
+    Hmac(hmac::Key),
+    Ed25519(Ed25519KeyPair),
+}
+
+/// Signs the messages with a `SigningKey`.
+pub(crate) struct Signer {
+    id: String,
+    key: SignerKey,
+}
+
+impl Signer {
+    pub(crate) fn new(key: &SigningKey) -> Result<Signer, InternalError> {
+        validate_id(&key.id)?;
+        let secret = decode_key(&key.id, &key.secret)?;
+        let signer_key = match key.algorithm {
+            Algorithm::HmacSha256 => SignerKey::Hmac(hmac::Key::new(hmac::HMAC_SHA256, &secret)),
+            Algorithm::Ed25519 => Ed25519KeyPair::from_seed_unchecked(&secret)
+                .map(SignerKey::Ed25519)
+                .map_err(|err| invalid_key(&key.id, err))?,
+        };
+        Ok(Signer {
+            id: key.id.clone(),
+            key: signer_key,
+        })
+    }
+
+    /// Adds the signature of the message to its headers.
+    pub(crate) fn sign(
+        &self,
+        subject: &str,
+        headers: Option<Headers>,
+        payload: &[u8],
+    ) -> Option<Headers> {
+        let mut headers = headers.unwrap_or_default();
+        headers.inner.remove(SIGNATURE_HEADER);
+        headers.inner.insert(
+            SIGNATURE_KEY_HEADER.to_owned(),
+            [self.id.clone()].into_iter().collect(),
+        );
+        let content = signed_content(subject, &headers, payload);
+        let signature = match &self.key {
+            SignerKey::Hmac(key) => base64::encode(hmac::sign(key, &content)),
+            SignerKey::Ed25519(key_pair) => base64::encode(key_pair.sign(&content)),
+        };
+        headers.inner.insert(
+            SIGNATURE_HEADER.to_owned(),
+            [signature].into_iter().collect(),
+        );
+        Some(headers)
+    }
+}
+
+enum VerifierKey {
+    Hmac(hmac::Key),
+    Ed25519(Vec<u8>),
+}
+
+/// The keys a subscriber verifies the signatures of its messages with, by id.
+pub(crate) struct KeyRing {
+    keys: HashMap<String, VerifierKey>,
+}
+
+impl KeyRing {
+    pub(crate) fn new(keys: &[VerifyingKey]) -> Result<KeyRing, InternalError> {
+        let mut key_ring = KeyRing {
+            keys: HashMap::new(),
+        };
+        for key in keys {
+            if key_ring.keys.contains_key(&key.id) {
+                return Err(InternalError::ConfigurationError {
+                    cause: format! {"Duplicate verifying key [{}]", key.id},
+                });
+            }
+            key_ring.update(KeyRingUpdate::Add(key.clone()))?;
+        }
+        Ok(key_ring)
+    }
+
+    pub(crate) fn update(&mut self, update: KeyRingUpdate) -> Result<(), InternalError> {
+        match update {
+            KeyRingUpdate::Add(key) => {
+                validate_id(&key.id)?;
+                let decoded = decode_key(&key.id, &key.key)?;
+                let verifier_key = match key.algorithm {
+                    Algorithm::HmacSha256 => {
+                        VerifierKey::Hmac(hmac::Key::new(hmac::HMAC_SHA256, &decoded))
+                    }
+                    Algorithm::Ed25519 if decoded.len() == 32 => VerifierKey::Ed25519(decoded),
+                    Algorithm::Ed25519 => {
+                        return Err(InternalError::ConfigurationError {
+                            cause: format! {"Ed25519 key [{}] must be 32 bytes long", key.id},
+                        })
+                    }
+                };
+                self.keys.insert(key.id, verifier_key);
+            }
+            KeyRingUpdate::Remove { id } => {
+                self.keys.remove(&id);
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks that the message is signed by a key of the ring.
+    pub(crate) fn verify(
+        &self,
+        subject: &str,
+        headers: Option<&Headers>,
+        payload: &[u8],
+    ) -> Result<(), InternalError> {
+        let header = |name: &str| {
+            headers?
+                .iter()
+                .find(|(key, _)| key.eq_ignore_ascii_case(name))
+                .and_then(|(_, values)| values.iter().next())
+        };
+        let (id, signature) = match (header(SIGNATURE_KEY_HEADER), header(SIGNATURE_HEADER)) {
+            (Some(id), Some(signature)) => (id, signature),
+            _ => return Err(unverified("Message not signed".to_owned())),
+        };
+        let key = self
+            .keys
+            .get(id)
+            .ok_or_else(|| unverified(format! {"Unknown signing key [{}]", id}))?;
+        let signature = base64::decode(signature)
+            .map_err(|_| unverified("Signature not base64 encoded".to_owned()))?;
+        let content = signed_content(subject, headers.expect("signed message"), payload);
+        let verified = match key {
+            VerifierKey::Hmac(key) => hmac::verify(key, &content, &signature),
+            VerifierKey::Ed25519(public_key) => {
+                UnparsedPublicKey::new(&ED25519, public_key).verify(&content, &signature)
+            }
+        };
+        verified.map_err(|_| unverified(format! {"Invalid signature with key [{}]", id}))
+    }
+}
+
+///
+/// The signed content: the subject, the headers but the signature sorted by lowercase name,
+/// then the payload.
+///
+fn signed_content(subject: &str, headers: &Headers, payload: &[u8]) -> Vec<u8> {
+    let mut lines: Vec<String> = headers
+        .iter()
+        .filter(|(name, _)| !name.eq_ignore_ascii_case(SIGNATURE_HEADER))
+        .flat_map(|(name, values)| {
+            let name = name.to_ascii_lowercase();
+            values
+                .iter()
+                .map(move |value| format!("{}:{}\n", name, value.trim()))
+        })
+        .collect();
+    lines.sort();
+    let mut content = Vec::with_capacity(subject.len() + payload.len() + 256);
+    content.extend_from_slice(subject.as_bytes());
+    content.push(b'\n');
+    for line in lines {
+        content.extend_from_slice(line.as_bytes());
+    }
+    content.push(b'\n');
+    content.extend_from_slice(payload);
+    content
+}
+
+fn validate_id(id: &str) -> Result<(), InternalError> {
+    // The id is sent as a header value
+    if id.is_empty() || id.trim() != id || id.contains(['\r', '\n']) {
+        return Err(InternalError::ConfigurationError {
+            cause: format! {"Invalid signing key id [{}]", id},
+        });
+    }
+    Ok(())
+}
+
+fn decode_key(id: &str, key: &str) -> Result<Vec<u8>, InternalError> {
+    let decoded = base64::decode(key).map_err(|err| invalid_key(id, err))?;
+    if decoded.is_empty() {
+        return Err(invalid_key(id, "empty key"));
+    }
+    Ok(decoded)
+}
+
+fn invalid_key(id: &str, err: impl std::fmt::Display) -> InternalError {
+    InternalError::ConfigurationError {
+        cause: format! {"Invalid key [{}]. Err: {}", id, err},
+    }
+}
+
+fn unverified(cause: String) -> InternalError {
+    InternalError::Unverified { cause }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Algorithm, KeyRing, KeyRingUpdate, Signer, SigningKey, SIGNATURE_HEADER};
+    use crate::InternalError;
+
+    fn key(id: &str, algorithm: Algorithm) -> SigningKey {
+        SigningKey {
+            id: id.to_owned(),
+            algorithm,
+            secret: base64::encode([id.len() as u8; 32]),
+        }
+    }
+
+    #[test]
+    fn should_verify_signed_messages() {
+        for algorithm in [Algorithm::HmacSha256, Algorithm::Ed25519] {
+            let signing_key = key("key-1", algorithm);
+            let signer = Signer::new(&signing_key).unwrap();
+            let key_ring = KeyRing::new(&[signing_key.verifying_key().unwrap()]).unwrap();
+            let headers = [("ce-type", "ping")].iter().collect();
+
+            let headers = signer.sign("events", Some(headers), b"payload").unwrap();
+
+            assert!(key_ring
+                .verify("events", Some(&headers), b"payload")
+                .is_ok());
+            let tampered = [
+                key_ring.verify("events", Some(&headers), b"forged"),
+                key_ring.verify("other", Some(&headers), b"payload"),
+                key_ring.verify("events", None, b"payload"),
+            ];
+            for result in tampered {
+                assert!(matches!(result, Err(InternalError::Unverified { .. })));
+            }
+            let mut forged_type = headers.clone();
+            forged_type.inner.insert(
+                "ce-type".to_owned(),
+                ["pong".to_owned()].into_iter().collect(),
+            );
+            assert!(key_ring
+                .verify("events", Some(&forged_type), b"payload")
+                .is_err());
+        }
+    }
+
+    #[test]
+    fn should_rotate_keys() {
+        let old_key = key("old", Algorithm::Ed25519);
+        let new_key = key("new-key", Algorithm::Ed25519);
+        let mut key_ring = KeyRing::new(&[old_key.verifying_key().unwrap()]).unwrap();
+        let old_signature = Signer::new(&old_key).unwrap().sign("events", None, b"a");
+        let new_signature = Signer::new(&new_key).unwrap().sign("events", None, b"a");
+
+        assert!(key_ring
+            .verify("events", new_signature.as_ref(), b"a")
+            .is_err());
+        key_ring
+            .update(KeyRingUpdate::Add(new_key.verifying_key().unwrap()))
+            .unwrap();
+        assert!(key_ring
+            .verify("events", old_signature.as_ref(), b"a")
+            .is_ok());
+        assert!(key_ring
+            .verify("events", new_signature.as_ref(), b"a")
+            .is_ok());
+        key_ring
+            .update(KeyRingUpdate::Remove {
+                id: "old".to_owned(),
+            })
+            .unwrap();
+        assert!(key_ring
+            .verify("events", old_signature.as_ref(), b"a")
+            .is_err());
+        assert!(new_signature.unwrap().contains_key(SIGNATURE_HEADER));
+    }
+}
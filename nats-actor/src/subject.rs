@@ -0,0 +1,187 @@
+use std::fmt::{Display, Formatter};
+
+use crate::{Envelope, InternalError};
+
+///
+/// The subject of a `NatsPublisher`, either fixed (`events.ping`) or built from the attributes
+/// of each event (`events.{type}.{source_host}`). The attributes available depend on the
+/// envelope, see `Envelope::attribute`.
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SubjectTemplate {
+    segments: Vec<Segment>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Segment {
+    Literal(String),
+    Attribute(String),
+}
+
+impl SubjectTemplate {
+    pub fn parse(template: &str) -> Result<SubjectTemplate, InternalError> {
+        let invalid = |cause: &str| InternalError::ConfigurationError {
+            cause: format! {"Invalid subject template [{}]: {}", template, cause},
+        };
+        if template.is_empty() {
+            return Err(invalid("empty subject"));
+        }
+
+        let mut segments = vec![];
+        let mut rest = template;
+        while let Some(start) = rest.find(['{', '}']) {
+            if rest[start..].starts_with('}') {
+                return Err(invalid("unexpected '}'"));
+            }
+            let end = rest[start..]
+                .find('}')
+                .map(|end| start + end)
+                .ok_or_else(|| invalid("unclosed '{'"))?;
+            let name = rest[start + 1..end].trim();
+            if name.is_empty() || name.contains('{') {
+                return Err(invalid("invalid attribute name"));
+            }
+            if start > 0 {
+                segments.push(Segment::Literal(rest[..start].to_owned()));
+            }
+            segments.push(Segment::Attribute(name.to_owned()));
+            rest = &rest[end + 1..];
+        }
+        if !rest.is_empty() {
+            segments.push(Segment::Literal(rest.to_owned()));
+        }
+        let template = SubjectTemplate { segments };
+        validate_subject(&template.to_string(), false)?;
+        Ok(template)
+    }
+
+    /// Whether the subject is the same for all the events.
+    pub fn is_fixed(&self) -> bool {
+        self.segments
+            .iter()
+            .all(|segment| matches!(segment, Segment::Literal(_)))
+    }
+
+    /// The subject of `event`. Fails when an attribute is missing or is not a valid subject.
+    pub fn render<E: Envelope>(&self, event: &E) -> Result<String, InternalError> {
+        let mut subject = String::new();
+        for segment in self.segments.iter() {
+            match segment {
+                Segment::Literal(literal) => subject.push_str(literal),
+                Segment::Attribute(name) => {
+                    let value = event.attribute(name).ok_or_else(|| {
+                        InternalError::ConfigurationError {
+                            cause: format! {"Event has no attribute [{}] for subject [{}]", name, self},
+                        }
+                    })?;
+                    subject.push_str(&value);
+                }
+            }
+        }
+        validate_subject(&subject, false)?;
+        Ok(subject)
+    }
+}
+
+impl Display for SubjectTemplate {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        for segment in self.segments.iter() {
+            match segment {
+                Segment::Literal(literal) => write!(f, "{}", literal)?,
+                Segment::Attribute(name) => write!(f, "{{{}}}", name)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+///
+/// Rejects the subjects made of empty tokens or tokens with whitespaces, and the wildcards:
+/// `*` and a final `>` are only accepted as whole tokens, when `wildcards_allowed` is set
+/// (subscriptions), never when publishing.
+///
+pub(crate) fn validate_subject(
+    subject: &str,
+    wildcards_allowed: bool,
+) -> Result<(), InternalError> {
+    let tokens: Vec<&str> = subject.split('.').collect();
+    for (index, token) in tokens.iter().enumerate() {
+        if token.is_empty() || token.contains(char::is_whitespace) {
+            return Err(InternalError::ConfigurationError {
+                cause: format! {"subject [{}] must be made of non-empty tokens without whitespaces", subject},
+            });
+        }
+        let is_wildcard = *token == "*" || (*token == ">" && index == tokens.len() - 1);
+        if (is_wildcard && !wildcards_allowed) || (!is_wildcard && token.contains(['*', '>'])) {
+            return Err(InternalError::ConfigurationError {
+                cause: format! {"subject [{}] contains an invalid wildcard token [{}]", subject, token},
+            });
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{validate_subject, SubjectTemplate};
+
+    #[test]
+    fn should_parse_subject_template() {
+        let template = SubjectTemplate::parse("events.{type}.{source_host}").unwrap();
+
+        assert!(!template.is_fixed());
+        assert_eq!("events.{type}.{source_host}", template.to_string());
+        assert!(SubjectTemplate::parse("events.ping").unwrap().is_fixed());
+        assert!(SubjectTemplate::parse("events.{type").is_err());
+        assert!(SubjectTemplate::parse("events.type}").is_err());
+        assert!(SubjectTemplate::parse("events.{}").is_err());
+        assert!(SubjectTemplate::parse("events.*").is_err());
+        assert!(SubjectTemplate::parse("events..{type}").is_err());
+        assert!(SubjectTemplate::parse("events.{type}.>").is_err());
+    }
+
+    #[test]
+    fn should_accept_wildcards_only_when_allowed() {
+        assert!(validate_subject("events.*.ping", true).is_ok());
+        assert!(validate_subject("events.>", true).is_ok());
+        assert!(validate_subject("events.*.ping", false).is_err());
+        assert!(validate_subject("events.>.ping", true).is_err());
+        assert!(validate_subject("events.pi*ng", true).is_err());
+        assert!(validate_subject("events. ping", true).is_err());
+    }
+
+    #[cfg(feature = "legacy")]
+    #[test]
+    fn should_render_legacy_event_type() {
+        let template = SubjectTemplate::parse("events.{event_type}").unwrap();
+
+        let subject = template.render(&crate::Event::new("com.example.ping"));
+
+        assert_eq!("events.com.example.ping", subject.unwrap());
+        assert!(template.render(&crate::Event::new("")).is_err());
+        assert!(template.render(&crate::Event::new("ping pong")).is_err());
+    }
+
+    #[cfg(feature = "cloudevents")]
+    #[test]
+    fn should_render_cloud_event_attributes() {
+        use cloudevents::{EventBuilder, EventBuilderV10};
+
+        let event = EventBuilderV10::new()
+            .source("https://user@service-a.example.com:8080/ping")
+            .id("trace_id")
+            .ty("com.example.ping")
+            .build()
+            .unwrap();
+        let template = SubjectTemplate::parse("events.{type}.{source_host}").unwrap();
+
+        let subject = template.render(&event);
+
+        assert_eq!(
+            "events.com.example.ping.service-a.example.com",
+            subject.unwrap()
+        );
+        let template = SubjectTemplate::parse("events.{subject}").unwrap();
+        assert!(template.render(&event).is_err());
+    }
+}
@@ -1,15 +1,199 @@
-use crate::{connect_with_retry, InternalError, NatsClientSettings};
+use crate::chunking::{Reassembler, ReassemblyConfig};
+use crate::compression::decompress;
+use crate::connection_event::ConnectionEvent;
+use crate::consumer::AckKind;
+use crate::dead_letter::{DeadLetter, DeadLetterSink};
+use crate::health::{ConnectionState, GetStatus, NatsStatus, StatusTracker};
+use crate::metrics::{NatsMetrics, SubscriberMetrics};
+use crate::registry::{NatsConnectionRegistry, SharedConnection};
+use crate::shutdown::Shutdown;
+use crate::signing::{KeyRing, KeyRingUpdate, VerificationConfig};
+use crate::{flush, ContentMode, Envelope, InternalError, NatsClientSettings};
 
 use actix::prelude::*;
-use async_nats::{Connection, Message};
-use futures_util::stream;
+use actix_web::ResponseError;
+use async_nats::{Connection, Headers, Message};
 use log::*;
 use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::time::Instant;
+use tokio::sync::oneshot;
+use tokio::time;
+
+/// The headers of a reply reporting that the responder failed, as NATS services do.
+const SERVICE_ERROR_HEADER: &str = "Nats-Service-Error";
+const SERVICE_ERROR_CODE_HEADER: &str = "Nats-Service-Error-Code";
 
 #[derive(Message, Debug)]
 #[rtype(result = "Result<(), InternalError>")]
 pub struct NatsMessage {
     pub msg: Message,
+    /// The connection of the subscriber, to send the replies
+    connection: Option<Connection>,
+    /// The reply subjects of the other chunks of a reassembled JetStream message, acknowledged
+    /// along with it
+    chunk_replies: Vec<String>,
+}
+
+impl NatsMessage {
+    /// A message received outside of a subscriber, which cannot be answered.
+    pub fn new(msg: Message) -> NatsMessage {
+        NatsMessage {
+            msg,
+            connection: None,
+            chunk_replies: vec![],
+        }
+    }
+
+    /// A message received on `connection`, to send the replies.
+    pub(crate) fn received(msg: Message, connection: &Connection) -> NatsMessage {
+        NatsMessage {
+            msg,
+            connection: Some(Connection::clone(connection)),
+            chunk_replies: vec![],
+        }
+    }
+
+    /// A JetStream message reassembled from chunks, acknowledged with its other chunks.
+    pub(crate) fn with_chunk_replies(mut self, chunk_replies: Vec<String>) -> NatsMessage {
+        self.chunk_replies = chunk_replies;
+        self
+    }
+
+    /// Decodes the message as an event of the given `Envelope` format, in either content mode.
+    pub fn event<E: Envelope>(&self) -> Result<E, InternalError> {
+        E::decode_message(self.msg.headers.as_ref(), &self.msg.data)
+    }
+
+    #[cfg(feature = "cloudevents")]
+    pub fn cloud_event(&self) -> Result<cloudevents::Event, InternalError> {
+        self.event()
+    }
+
+    /// The subject the requester waits for the reply on, if any.
+    pub fn reply_subject(&self) -> Option<&str> {
+        self.msg.reply.as_deref()
+    }
+
+    pub fn headers(&self) -> Option<&Headers> {
+        self.msg.headers.as_ref()
+    }
+
+    ///
+    /// Answers the request with `event`, in the content mode of the request.
+    /// The reply is published in the background: a failure to publish it is only logged.
+    ///
+    pub fn respond<E: Envelope>(&self, event: &E) -> Result<(), InternalError> {
+        self.replier().respond(event)
+    }
+
+    /// Answers the request with `err`, received as an `InternalError::ResponderError`.
+    pub fn respond_error(&self, err: &InternalError) -> Result<(), InternalError> {
+        self.replier().respond_error(err)
+    }
+
+    ///
+    /// Acknowledges the message delivered by a `JetStreamConsumer`. Used to complete the
+    /// messages the callback answered `AckKind::InProgress` for.
+    ///
+    pub fn ack(&self, kind: AckKind) -> Result<(), InternalError> {
+        self.replier().ack(kind)
+    }
+
+    pub(crate) fn replier(&self) -> Replier {
+        Replier {
+            subject: self.msg.reply.clone(),
+            mode: ContentMode::of(self.headers()),
+            connection: self.connection.clone(),
+            chunk_replies: self.chunk_replies.clone(),
+        }
+    }
+}
+
+/// Where and how the reply to a message is sent.
+pub(crate) struct Replier {
+    subject: Option<String>,
+    mode: ContentMode,
+    connection: Option<Connection>,
+    chunk_replies: Vec<String>,
+}
+
+impl Replier {
+    fn respond<E: Envelope>(&self, event: &E) -> Result<(), InternalError> {
+        let (headers, payload) = event.encode_as(self.mode)?;
+        self.send(headers, payload)
+    }
+
+    /// Sends an empty reply with the status code and the description of `err` as headers.
+    fn respond_error(&self, err: &InternalError) -> Result<(), InternalError> {
+        // A line break would end the header
+        let description = err.to_string().replace(['\r', '\n'], " ");
+        let code = err.status_code().as_u16().to_string();
+        let headers = [
+            (SERVICE_ERROR_HEADER.to_owned(), description),
+            (SERVICE_ERROR_CODE_HEADER.to_owned(), code),
+        ];
+        self.send(Some(headers.into_iter().collect()), vec![])
+    }
+
+    /// Sends the acknowledgement of a JetStream message to its reply subject, and to the ones
+    /// of its other chunks.
+    pub(crate) fn ack(&self, kind: AckKind) -> Result<(), InternalError> {
+        for subject in self.chunk_replies.iter() {
+            self.send_to(Some(subject), None, kind.body())?;
+        }
+        self.send(None, kind.body())
+    }
+
+    fn send(&self, headers: Option<Headers>, payload: Vec<u8>) -> Result<(), InternalError> {
+        self.send_to(self.subject.as_ref(), headers, payload)
+    }
+
+    fn send_to(
+        &self,
+        subject: Option<&String>,
+        headers: Option<Headers>,
+        payload: Vec<u8>,
+    ) -> Result<(), InternalError> {
+        let (subject, connection) = match (subject, &self.connection) {
+            (Some(subject), Some(connection)) => (subject.clone(), connection.clone()),
+            (None, _) => {
+                return Err(InternalError::NatsOperationError {
+                    cause: "The message expects no reply".to_owned(),
+                })
+            }
+            (_, None) => {
+                return Err(InternalError::NatsOperationError {
+                    cause: "No NATS connection to send the reply".to_owned(),
+                })
+            }
+        };
+        actix::spawn(async move {
+            let published = connection
+                .publish_with_reply_or_headers(&subject, None, headers.as_ref(), &payload)
+                .await;
+            if let Err(err) = published {
+                error!("Cannot send reply to [{}]. Err: {}", subject, err);
+            }
+        });
+        Ok(())
+    }
+}
+
+/// The failure reported by a reply sent with `NatsMessage::respond_error`, if any.
+pub(crate) fn service_error(headers: Option<&Headers>) -> Option<InternalError> {
+    let header = |name: &str| {
+        headers?
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .and_then(|(_, values)| values.iter().next().cloned())
+    };
+    let cause = header(SERVICE_ERROR_HEADER)?;
+    let code = header(SERVICE_ERROR_CODE_HEADER)
+        .and_then(|code| code.parse().ok())
+        .unwrap_or(500);
+    Some(InternalError::ResponderError { code, cause })
 }
 
 #[derive(Deserialize, Serialize, Clone)]
@@ -17,6 +201,11 @@ pub struct NatsSubscriberConfig {
     pub client_settings: NatsClientSettings,
     pub subject: String,
     pub mailbox_size: usize,
+    /// Delivers only the messages signed by a known key. See `KeyRingUpdate`.
+    #[serde(default)]
+    pub verification: Option<VerificationConfig>,
+    #[serde(default)]
+    pub reassembly: ReassemblyConfig,
 }
 
 pub async fn subscribe<
@@ -24,8 +213,32 @@ pub async fn subscribe<
 >(
     config: NatsSubscriberConfig,
     callback: F,
-) -> Result<(), InternalError> {
-    let client = connect_with_retry(&config.client_settings).await?;
+) -> Result<Addr<NatsSubscriber<F>>, InternalError> {
+    subscribe_with_registry(config, NatsConnectionRegistry::global(), callback).await
+}
+
+///
+/// Same as `subscribe`, borrowing the connection from `registry`.
+/// A `ConnectionEvent::SlowConsumer` is notified to the connection listeners whenever
+/// the subscriber mailbox is full.
+///
+pub async fn subscribe_with_registry<
+    F: 'static + FnMut(NatsMessage) -> Result<(), InternalError> + Sized + Unpin,
+>(
+    config: NatsSubscriberConfig,
+    registry: &NatsConnectionRegistry,
+    callback: F,
+) -> Result<Addr<NatsSubscriber<F>>, InternalError> {
+    config.reassembly.validate()?;
+    let (key_ring, rejected) = match &config.verification {
+        Some(verification) => (
+            Some(Rc::new(RefCell::new(KeyRing::new(&verification.keys)?))),
+            verification.rejected.clone(),
+        ),
+        None => (None, None),
+    };
+    let listeners = registry.listeners(&config.client_settings)?;
+    let client = registry.acquire(&config.client_settings).await?;
 
     let subscription = client.subscribe(&config.subject).await.map_err(|err| {
         InternalError::NatsOperationError {
@@ -34,30 +247,215 @@ pub async fn subscribe<
     })?;
 
     info!("Subscribed to subject [{}]", config.subject);
+    let subscription = Rc::new(subscription);
+    let connection = Connection::clone(&client);
+    let (pump_done_sender, pump_done) = oneshot::channel();
+    let metrics = NatsMetrics::global().subscriber(&config.subject);
+    let reassembler = Rc::new(RefCell::new(Reassembler::new(config.reassembly)));
 
-    let message_stream = stream::unfold(subscription, |sub| async {
-        sub.next().await.map(|msg| (NatsMessage { msg }, sub))
+    let address = NatsSubscriber::create(|ctx| {
+        ctx.set_mailbox_capacity(config.mailbox_size);
+        listeners.register(ctx.address().recipient());
+        let expired = reassembler.clone();
+        ctx.run_interval(config.reassembly.timeout, move |_, _| {
+            expired.borrow_mut().expire(Instant::now())
+        });
+        NatsSubscriber {
+            callback,
+            client,
+            status: StatusTracker::new(ConnectionState::Connected),
+            subscription: subscription.clone(),
+            pump_done: Some(pump_done),
+            key_ring: key_ring.clone(),
+            metrics: metrics.clone(),
+        }
     });
 
-    NatsSubscriber::create(|ctx| {
-        ctx.set_mailbox_capacity(config.mailbox_size);
-        ctx.add_message_stream(message_stream);
-        NatsSubscriber { callback, client }
+    let subject = config.subject;
+    let pump = address.clone();
+    actix::spawn(async move {
+        while let Some(mut msg) = subscription.next().await {
+            metrics.received.inc();
+            let unsealed = unseal(
+                &mut msg,
+                &mut reassembler.borrow_mut(),
+                key_ring
+                    .as_ref()
+                    .map(|key_ring| key_ring.borrow())
+                    .as_deref(),
+            );
+            match unsealed {
+                Ok(()) => {}
+                Err(Undelivered::Pending) => continue,
+                Err(Undelivered::Rejected(err)) => {
+                    warn!("Rejecting message from [{}]. Err: {}", msg.subject, err);
+                    metrics.rejected.inc();
+                    reject(&msg, err, rejected.as_ref(), &connection);
+                    continue;
+                }
+                Err(Undelivered::Failed(err)) => {
+                    error!("Dropping message from [{}]. Err: {}", msg.subject, err);
+                    metrics.errors.inc();
+                    continue;
+                }
+            }
+            let msg = NatsMessage::received(msg, &connection);
+            metrics.mailbox_depth.inc();
+            let msg = match pump.try_send(msg) {
+                Ok(()) => continue,
+                Err(SendError::Full(msg)) => msg,
+                Err(SendError::Closed(_)) => {
+                    metrics.mailbox_depth.dec();
+                    break;
+                }
+            };
+            warn!("Subscriber of subject [{}] is not keeping up", subject);
+            listeners.notify(ConnectionEvent::SlowConsumer {
+                subject: subject.clone(),
+            });
+            if pump.send(msg).await.is_err() {
+                metrics.mailbox_depth.dec();
+                break;
+            }
+        }
+        debug!("Subscription to subject [{}] ended", subject);
+        let _ = pump_done_sender.send(());
     });
 
-    Ok(())
+    Ok(address)
+}
+
+///
+/// Same as `subscribe`, answering each request with the event returned by `callback`, through
+/// `NatsMessage::respond`. A failure of the callback is sent back with
+/// `NatsMessage::respond_error`. Nothing is sent when the message expects no reply.
+///
+pub async fn subscribe_responder<E, F>(
+    config: NatsSubscriberConfig,
+    callback: F,
+) -> Result<
+    Addr<NatsSubscriber<impl FnMut(NatsMessage) -> Result<(), InternalError> + Unpin>>,
+    InternalError,
+>
+where
+    E: Envelope,
+    F: 'static + FnMut(NatsMessage) -> Result<E, InternalError> + Unpin,
+{
+    subscribe_responder_with_registry(config, NatsConnectionRegistry::global(), callback).await
 }
 
-struct NatsSubscriber<F>
+/// Same as `subscribe_responder`, borrowing the connection from `registry`.
+pub async fn subscribe_responder_with_registry<E, F>(
+    config: NatsSubscriberConfig,
+    registry: &NatsConnectionRegistry,
+    mut callback: F,
+) -> Result<
+    Addr<NatsSubscriber<impl FnMut(NatsMessage) -> Result<(), InternalError> + Unpin>>,
+    InternalError,
+>
+where
+    E: Envelope,
+    F: 'static + FnMut(NatsMessage) -> Result<E, InternalError> + Unpin,
+{
+    subscribe_with_registry(config, registry, move |msg: NatsMessage| {
+        let replier = msg.replier();
+        let reply = callback(msg);
+        if replier.subject.is_none() {
+            debug!("Request without reply subject, dropping the reply");
+            return reply.map(|_| ());
+        }
+        match reply {
+            Ok(reply) => replier.respond(&reply),
+            Err(err) => {
+                replier.respond_error(&err)?;
+                Err(err)
+            }
+        }
+    })
+    .await
+}
+
+/// Delivers the messages received on a subject to the subscription callback.
+pub struct NatsSubscriber<F>
 where
     F: 'static + FnMut(NatsMessage) -> Result<(), InternalError> + Sized + Unpin,
 {
     callback: F,
     // The client must live as long as the actor, otherwise the connection is dropped when the client is deallocated
-    #[allow(dead_code)]
-    client: Connection,
+    client: SharedConnection,
+    status: StatusTracker,
+    subscription: Rc<async_nats::Subscription>,
+    /// Completed once all the messages of the subscription are in the mailbox
+    pump_done: Option<oneshot::Receiver<()>>,
+    /// Shared with the pump, which verifies the messages
+    key_ring: Option<Rc<RefCell<KeyRing>>>,
+    metrics: SubscriberMetrics,
+}
+
+/// Why a received message is not delivered.
+pub(crate) enum Undelivered {
+    /// Waiting for the other chunks of the message.
+    Pending,
+    /// Not verified by the key ring.
+    Rejected(InternalError),
+    /// Malformed chunk or payload.
+    Failed(InternalError),
 }
 
+///
+/// Restores a received message as it was published: reassembles its chunks, verifies its
+/// signature against `key_ring` if set, then decompresses its payload.
+///
+pub(crate) fn unseal(
+    msg: &mut Message,
+    reassembler: &mut Reassembler,
+    key_ring: Option<&KeyRing>,
+) -> Result<(), Undelivered> {
+    let data = std::mem::take(&mut msg.data);
+    match reassembler.push(&msg.subject, &mut msg.headers, data, Instant::now()) {
+        Ok(Some(data)) => msg.data = data,
+        Ok(None) => return Err(Undelivered::Pending),
+        Err(err) => return Err(Undelivered::Failed(err)),
+    }
+    if let Some(key_ring) = key_ring {
+        key_ring
+            .verify(&msg.subject, msg.headers.as_ref(), &msg.data)
+            .map_err(Undelivered::Rejected)?;
+    }
+    // The callback receives the payload as it was before compression
+    msg.data =
+        decompress(&mut msg.headers, std::mem::take(&mut msg.data)).map_err(Undelivered::Failed)?;
+    Ok(())
+}
+
+/// Sends a message not verified to the rejection path, if any.
+pub(crate) fn reject(
+    msg: &Message,
+    err: InternalError,
+    rejected: Option<&DeadLetterSink>,
+    connection: &Connection,
+) {
+    let sink = match rejected {
+        Some(sink) => sink.clone(),
+        None => return,
+    };
+    let letter = DeadLetter::rejected(msg.subject.clone(), &msg.data, &err);
+    let connection = Connection::clone(connection);
+    actix::spawn(async move {
+        if let Err(err) = sink.send(letter.clone(), Some(connection)).await {
+            error!(
+                "Cannot send the rejected message to the rejection path. Letter: {:?}. Err: {}",
+                letter, err
+            );
+        }
+    });
+}
+
+/// Sent by the subscriber to itself: once handled, all the messages queued before were processed.
+#[derive(Message)]
+#[rtype(result = "()")]
+struct Drained;
+
 impl<F> Actor for NatsSubscriber<F>
 where
     F: 'static + FnMut(NatsMessage) -> Result<(), InternalError> + Sized + Unpin,
@@ -73,11 +471,122 @@ where
 
     fn handle(&mut self, msg: NatsMessage, _: &mut Context<Self>) -> Self::Result {
         trace!("Message received");
-        if let Err(err) = (&mut self.callback)(msg) {
+        self.metrics.mailbox_depth.dec();
+        let started = Instant::now();
+        let result = (self.callback)(msg);
+        self.metrics
+            .duration
+            .observe(started.elapsed().as_secs_f64());
+        if let Err(err) = result {
             error!("Received message processing failed: {:?}", err);
+            self.metrics.errors.inc();
             Err(err)
         } else {
             Ok(())
         }
     }
 }
+
+impl<F> Handler<ConnectionEvent> for NatsSubscriber<F>
+where
+    F: 'static + FnMut(NatsMessage) -> Result<(), InternalError> + Sized + Unpin,
+{
+    type Result = ();
+
+    fn handle(&mut self, msg: ConnectionEvent, _: &mut Context<Self>) {
+        self.status.on_connection_event(&msg);
+    }
+}
+
+impl<F> Handler<GetStatus> for NatsSubscriber<F>
+where
+    F: 'static + FnMut(NatsMessage) -> Result<(), InternalError> + Sized + Unpin,
+{
+    type Result = ResponseFuture<NatsStatus>;
+
+    fn handle(&mut self, _: GetStatus, _: &mut Context<Self>) -> Self::Result {
+        Box::pin(self.status.status(Some(Connection::clone(&self.client))))
+    }
+}
+
+impl<F> Handler<KeyRingUpdate> for NatsSubscriber<F>
+where
+    F: 'static + FnMut(NatsMessage) -> Result<(), InternalError> + Sized + Unpin,
+{
+    type Result = Result<(), InternalError>;
+
+    fn handle(&mut self, msg: KeyRingUpdate, _: &mut Context<Self>) -> Self::Result {
+        match &self.key_ring {
+            Some(key_ring) => {
+                info!("NatsSubscriber updating its key ring: {:?}", msg);
+                key_ring.borrow_mut().update(msg)
+            }
+            None => Err(InternalError::ConfigurationError {
+                cause: "The subscriber does not verify its messages".to_owned(),
+            }),
+        }
+    }
+}
+
+impl<F> Handler<Drained> for NatsSubscriber<F>
+where
+    F: 'static + FnMut(NatsMessage) -> Result<(), InternalError> + Sized + Unpin,
+{
+    type Result = ();
+
+    fn handle(&mut self, _: Drained, _: &mut Context<Self>) {}
+}
+
+impl<F> Handler<Shutdown> for NatsSubscriber<F>
+where
+    F: 'static + FnMut(NatsMessage) -> Result<(), InternalError> + Sized + Unpin,
+{
+    type Result = ResponseActFuture<Self, Result<(), InternalError>>;
+
+    fn handle(&mut self, msg: Shutdown, ctx: &mut Context<Self>) -> Self::Result {
+        info!("NatsSubscriber shutting down, draining subscription");
+        let subscription = self.subscription.clone();
+        let pump_done = self.pump_done.take();
+        let address = ctx.address();
+        let timeout = msg.timeout;
+        let drain = async move {
+            // The messages already received are still delivered by the pump
+            subscription
+                .drain()
+                .await
+                .map_err(|err| InternalError::NatsOperationError {
+                    cause: format! {"Cannot drain subscription. Err: {}", err},
+                })?;
+            if let Some(pump_done) = pump_done {
+                let _ = pump_done.await;
+            }
+            let _ = address.send(Drained).await;
+            Ok(())
+        };
+        Box::pin(
+            async move {
+                time::timeout(timeout, drain).await.unwrap_or_else(|_| {
+                    Err(InternalError::NatsOperationError {
+                        cause: format! {"Subscription not drained after {:?}", timeout},
+                    })
+                })
+            }
+            .into_actor(self)
+            .then(move |result, act, _| {
+                let connection = Connection::clone(&act.client);
+                act.status.set_state(ConnectionState::Closed);
+                async move {
+                    flush(&connection, timeout).await?;
+                    info!("NatsSubscriber shut down");
+                    result
+                }
+                .into_actor(act)
+            })
+            .map(|result, _, ctx| {
+                // Stopping the actor releases the connection
+                ctx.stop();
+                result
+            }),
+        )
+    }
+}
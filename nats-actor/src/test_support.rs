@@ -0,0 +1,438 @@
+//!
+//! An in-process fake of the NATS server, speaking enough of the text protocol
+//! (INFO, CONNECT, PUB, HPUB, SUB, UNSUB, MSG, HMSG, PING, PONG) to run the publishers
+//! and subscribers of this crate in `cargo test` without external services.
+//!
+
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+use log::*;
+use serde_json::json;
+
+use crate::NatsClientSettings;
+
+///
+/// A NATS server listening on an ephemeral port of 127.0.0.1, stopped when dropped.
+/// Subjects support the `*` and `>` wildcards, and each message published to a queue
+/// group is delivered to a single member of the group (round robin).
+///
+pub struct FakeNatsServer {
+    address: SocketAddr,
+    state: Arc<Mutex<ServerState>>,
+    stopped: Arc<AtomicBool>,
+    acceptor: Option<JoinHandle<()>>,
+}
+
+#[derive(Default)]
+struct ServerState {
+    next_client_id: u64,
+    clients: HashMap<u64, Client>,
+    /// Picks the member of a queue group receiving the next message
+    queue_deliveries: usize,
+}
+
+struct Client {
+    stream: TcpStream,
+    subscriptions: HashMap<String, Subscription>,
+}
+
+struct Subscription {
+    subject: String,
+    queue_group: Option<String>,
+    remaining_messages: Option<u64>,
+}
+
+impl FakeNatsServer {
+    pub fn start() -> FakeNatsServer {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("cannot bind the fake NATS server");
+        let address = listener.local_addr().unwrap();
+        let state = Arc::new(Mutex::new(ServerState::default()));
+        let stopped = Arc::new(AtomicBool::new(false));
+
+        let acceptor = {
+            let state = state.clone();
+            let stopped = stopped.clone();
+            thread::spawn(move || {
+                for stream in listener.incoming() {
+                    if stopped.load(Ordering::SeqCst) {
+                        break;
+                    }
+                    match stream {
+                        Ok(stream) => {
+                            let state = state.clone();
+                            thread::spawn(move || serve(stream, address, state));
+                        }
+                        Err(err) => warn!("FakeNatsServer cannot accept connection. Err: {}", err),
+                    }
+                }
+            })
+        };
+
+        debug!("FakeNatsServer listening on {}", address);
+        FakeNatsServer {
+            address,
+            state,
+            stopped,
+            acceptor: Some(acceptor),
+        }
+    }
+
+    /// The `host:port` address of the server.
+    pub fn address(&self) -> String {
+        self.address.to_string()
+    }
+
+    /// Client settings pointing to this server.
+    pub fn client_settings(&self) -> NatsClientSettings {
+        NatsClientSettings {
+            addresses: vec![self.address()],
+            ..Default::default()
+        }
+    }
+
+    /// The number of open client connections.
+    pub fn connections(&self) -> usize {
+        self.state.lock().unwrap().clients.len()
+    }
+
+    /// The number of active subscriptions over all the connections.
+    pub fn subscriptions(&self) -> usize {
+        self.state
+            .lock()
+            .unwrap()
+            .clients
+            .values()
+            .map(|client| client.subscriptions.len())
+            .sum()
+    }
+
+    /// Abruptly closes all the client connections. The clients are free to reconnect.
+    pub fn kill_connections(&self) {
+        let mut state = self.state.lock().unwrap();
+        for (client_id, client) in state.clients.drain() {
+            debug!("FakeNatsServer killing connection of client {}", client_id);
+            let _ = client.stream.shutdown(Shutdown::Both);
+        }
+    }
+
+    /// Stops accepting connections and kills the open ones.
+    pub fn stop(&mut self) {
+        if let Some(acceptor) = self.acceptor.take() {
+            self.stopped.store(true, Ordering::SeqCst);
+            // Wakes up the acceptor blocked on `incoming()`
+            let _ = TcpStream::connect(self.address);
+            let _ = acceptor.join();
+            self.kill_connections();
+            debug!("FakeNatsServer on {} stopped", self.address);
+        }
+    }
+}
+
+impl Drop for FakeNatsServer {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+fn serve(stream: TcpStream, address: SocketAddr, state: Arc<Mutex<ServerState>>) {
+    let client_id = {
+        let mut state = state.lock().unwrap();
+        state.next_client_id += 1;
+        let client_id = state.next_client_id;
+        match stream.try_clone() {
+            Ok(writer) => {
+                state.clients.insert(
+                    client_id,
+                    Client {
+                        stream: writer,
+                        subscriptions: HashMap::new(),
+                    },
+                );
+            }
+            Err(err) => {
+                warn!("FakeNatsServer cannot clone client stream. Err: {}", err);
+                return;
+            }
+        }
+        client_id
+    };
+
+    let info = json!({
+        "server_id": "fake_nats_server",
+        "server_name": "fake_nats_server",
+        "version": "2.2.0",
+        "go": "go1.16",
+        "host": address.ip().to_string(),
+        "port": address.port(),
+        "headers": true,
+        "max_payload": 1048576,
+        "proto": 1,
+        "client_id": client_id,
+    });
+    send(&state, client_id, format!("INFO {}\r\n", info).as_bytes());
+
+    if let Err(err) = read_operations(BufReader::new(stream), client_id, &state) {
+        debug!(
+            "FakeNatsServer client {} disconnected. Err: {}",
+            client_id, err
+        );
+    }
+    state.lock().unwrap().clients.remove(&client_id);
+}
+
+fn read_operations(
+    mut reader: BufReader<TcpStream>,
+    client_id: u64,
+    state: &Mutex<ServerState>,
+) -> std::io::Result<()> {
+    let mut line = String::new();
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            return Ok(());
+        }
+        let mut tokens = line.split_whitespace();
+        let operation = tokens.next().unwrap_or_default().to_ascii_uppercase();
+        let args: Vec<&str> = tokens.collect();
+
+        match (operation.as_str(), args.as_slice()) {
+            ("CONNECT", _) | ("PONG", _) => {}
+            ("PING", _) => send(state, client_id, b"PONG\r\n"),
+            ("PUB", [subject, reply_to @ .., size]) => {
+                let payload = read_payload(&mut reader, size)?;
+                publish(state, subject, reply_to.first().copied(), None, &payload);
+            }
+            ("HPUB", [subject, reply_to @ .., header_size, size]) => {
+                let payload = read_payload(&mut reader, size)?;
+                let header_size = parse_size(header_size)?;
+                publish(
+                    state,
+                    subject,
+                    reply_to.first().copied(),
+                    Some(header_size),
+                    &payload,
+                );
+            }
+            ("SUB", [subject, queue_group @ .., sid]) => {
+                if let Some(client) = state.lock().unwrap().clients.get_mut(&client_id) {
+                    client.subscriptions.insert(
+                        sid.to_string(),
+                        Subscription {
+                            subject: subject.to_string(),
+                            queue_group: queue_group.first().map(|group| group.to_string()),
+                            remaining_messages: None,
+                        },
+                    );
+                }
+            }
+            ("UNSUB", [sid, max_messages @ ..]) => {
+                let max_messages = max_messages.first().map(parse_size).transpose()?;
+                if let Some(client) = state.lock().unwrap().clients.get_mut(&client_id) {
+                    match max_messages {
+                        Some(max_messages) if max_messages > 0 => {
+                            if let Some(subscription) = client.subscriptions.get_mut(*sid) {
+                                subscription.remaining_messages = Some(max_messages as u64);
+                            }
+                        }
+                        _ => {
+                            client.subscriptions.remove(*sid);
+                        }
+                    }
+                }
+            }
+            _ => {
+                warn!(
+                    "FakeNatsServer received unknown operation [{}]",
+                    line.trim()
+                );
+                send(state, client_id, b"-ERR 'Unknown Protocol Operation'\r\n");
+            }
+        }
+    }
+}
+
+fn parse_size(size: &&str) -> std::io::Result<usize> {
+    size.parse().map_err(|err| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format! {"invalid size [{}]. Err: {}", size, err},
+        )
+    })
+}
+
+fn read_payload(reader: &mut BufReader<TcpStream>, size: &&str) -> std::io::Result<Vec<u8>> {
+    let size = parse_size(size)?;
+    // The payload is followed by \r\n
+    let mut payload = vec![0; size + 2];
+    reader.read_exact(&mut payload)?;
+    payload.truncate(size);
+    Ok(payload)
+}
+
+fn publish(
+    state: &Mutex<ServerState>,
+    subject: &str,
+    reply_to: Option<&str>,
+    header_size: Option<usize>,
+    payload: &[u8],
+) {
+    let mut state = state.lock().unwrap();
+
+    let mut targets = vec![];
+    let mut queue_groups: HashMap<String, Vec<(u64, String)>> = HashMap::new();
+    for (client_id, client) in state.clients.iter() {
+        for (sid, subscription) in client.subscriptions.iter() {
+            if !subject_matches(&subscription.subject, subject) {
+                continue;
+            }
+            match &subscription.queue_group {
+                Some(group) => queue_groups
+                    .entry(group.clone())
+                    .or_default()
+                    .push((*client_id, sid.clone())),
+                None => targets.push((*client_id, sid.clone())),
+            }
+        }
+    }
+    for (_, mut members) in queue_groups {
+        members.sort();
+        targets.push(members.swap_remove(state.queue_deliveries % members.len()));
+        state.queue_deliveries += 1;
+    }
+
+    let reply_to = reply_to
+        .map(|reply_to| format!("{} ", reply_to))
+        .unwrap_or_default();
+    for (client_id, sid) in targets {
+        let mut message = match header_size {
+            Some(header_size) => format!(
+                "HMSG {} {} {}{} {}\r\n",
+                subject,
+                sid,
+                reply_to,
+                header_size,
+                payload.len()
+            ),
+            None => format!("MSG {} {} {}{}\r\n", subject, sid, reply_to, payload.len()),
+        }
+        .into_bytes();
+        message.extend_from_slice(payload);
+        message.extend_from_slice(b"\r\n");
+
+        if let Some(client) = state.clients.get_mut(&client_id) {
+            if let Err(err) = client.stream.write_all(&message) {
+                debug!(
+                    "FakeNatsServer cannot deliver to client {}. Err: {}",
+                    client_id, err
+                );
+            }
+            let expired = match client.subscriptions.get_mut(&sid) {
+                Some(Subscription {
+                    remaining_messages: Some(remaining),
+                    ..
+                }) => {
+                    *remaining -= 1;
+                    *remaining == 0
+                }
+                _ => false,
+            };
+            if expired {
+                client.subscriptions.remove(&sid);
+            }
+        }
+    }
+}
+
+fn send(state: &Mutex<ServerState>, client_id: u64, data: &[u8]) {
+    if let Some(client) = state.lock().unwrap().clients.get_mut(&client_id) {
+        if let Err(err) = client.stream.write_all(data) {
+            debug!(
+                "FakeNatsServer cannot write to client {}. Err: {}",
+                client_id, err
+            );
+        }
+    }
+}
+
+/// Whether `subject` matches `pattern`, where `*` matches one token and a trailing `>`
+/// matches one or more tokens.
+fn subject_matches(pattern: &str, subject: &str) -> bool {
+    let mut pattern = pattern.split('.');
+    let mut subject = subject.split('.');
+    loop {
+        match (pattern.next(), subject.next()) {
+            (Some(">"), Some(_)) => return true,
+            (Some(expected), Some(token)) if expected == "*" || expected == token => {}
+            (None, None) => return true,
+            _ => return false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use tokio::time;
+
+    use super::{subject_matches, FakeNatsServer};
+
+    #[test]
+    fn should_match_wildcards() {
+        assert!(subject_matches("events.ping", "events.ping"));
+        assert!(subject_matches("events.*", "events.ping"));
+        assert!(subject_matches("*.ping", "events.ping"));
+        assert!(subject_matches("events.>", "events.ping.sent"));
+        assert!(!subject_matches("events.>", "events"));
+        assert!(!subject_matches("events.*", "events.ping.sent"));
+        assert!(!subject_matches("events.ping", "events.pong"));
+    }
+
+    #[actix_rt::test]
+    async fn should_deliver_to_wildcard_subscribers_and_one_queue_member() {
+        let server = FakeNatsServer::start();
+        let connection = async_nats::connect(&server.address()).await.unwrap();
+
+        let wildcard = connection.subscribe("events.*").await.unwrap();
+        let first_member = connection
+            .queue_subscribe("events.ping", "workers")
+            .await
+            .unwrap();
+        let second_member = connection
+            .queue_subscribe("events.ping", "workers")
+            .await
+            .unwrap();
+        let unsubscribed = connection.subscribe("events.>").await.unwrap();
+        unsubscribed.unsubscribe().await.unwrap();
+        connection.flush().await.unwrap();
+        assert_eq!(3, server.subscriptions());
+
+        connection.publish("events.ping", "first").await.unwrap();
+        connection.publish("events.ping", "second").await.unwrap();
+        connection.flush().await.unwrap();
+
+        assert_eq!(b"first", &wildcard.next().await.unwrap().data[..]);
+        assert_eq!(b"second", &wildcard.next().await.unwrap().data[..]);
+        // Round robin: each member of the queue group receives one of the two messages
+        let timeout = Duration::from_secs(5);
+        assert!(time::timeout(timeout, first_member.next()).await.is_ok());
+        assert!(time::timeout(timeout, second_member.next()).await.is_ok());
+    }
+
+    #[actix_rt::test]
+    async fn should_refuse_connections_once_stopped() {
+        let mut server = FakeNatsServer::start();
+        let connection = async_nats::connect(&server.address()).await.unwrap();
+        assert_eq!(1, server.connections());
+
+        server.stop();
+
+        assert_eq!(0, server.connections());
+        assert!(async_nats::connect(&server.address()).await.is_err());
+        drop(connection);
+    }
+}